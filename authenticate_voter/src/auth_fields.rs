@@ -0,0 +1,256 @@
+// SPDX-FileCopyrightText: 2022 Eduardo Robles <edu@sequentech.io>
+//
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Mapping from contact attributes to the authentication extra fields sent to
+//! the Sequent backend.
+
+use std::collections::HashMap;
+use serde::Deserialize;
+use tracing::{event, Level};
+use ivr_common::IvrError;
+use ivr_common::config::{env_var, json_env_var};
+
+use crate::caller_id::AuthMode;
+
+/// Normalization applied to the value of a contact attribute before sending
+/// it to the backend.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum Normalization {
+    /// The value is sent as is.
+    #[default]
+    None,
+    /// Leading and trailing whitespace is removed.
+    Trim,
+    /// Every character that is not a digit is removed.
+    Digits,
+    /// The value is trimmed and converted to uppercase.
+    Uppercase,
+    /// The value is trimmed and converted to lowercase.
+    Lowercase,
+    /// A date typed as `DDMMYYYY` is converted to `YYYY-MM-DD`.
+    DateDdmmyyyy,
+}
+
+impl Normalization {
+    /// Applies the normalization, returning `None` if the value is not valid
+    /// for it.
+    pub fn apply(&self, value: &str) -> Option<String> {
+        match self {
+            Normalization::None => Some(value.into()),
+            Normalization::Trim => Some(value.trim().into()),
+            Normalization::Digits => Some(
+                value
+                    .chars()
+                    .filter(|character| character.is_ascii_digit())
+                    .collect()
+            ),
+            Normalization::Uppercase => Some(value.trim().to_uppercase()),
+            Normalization::Lowercase => Some(value.trim().to_lowercase()),
+            Normalization::DateDdmmyyyy => {
+                let value = value.trim();
+                let is_valid = value.len() == 8
+                    && value.chars().all(|character| character.is_ascii_digit());
+                if !is_valid {
+                    return None;
+                }
+                let (day, month, year) = (&value[0..2], &value[2..4], &value[4..8]);
+                let day_number: u32 = day.parse().ok()?;
+                let month_number: u32 = month.parse().ok()?;
+                if !(1..=31).contains(&day_number) || !(1..=12).contains(&month_number) {
+                    return None;
+                }
+                Some(format!("{}-{}-{}", year, month, day))
+            },
+        }
+    }
+}
+
+fn default_required() -> bool {
+    true
+}
+
+/// Maps a contact attribute to an authentication extra field.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct AuthField {
+    /// Name of the contact attribute, for example `VoterUserId`.
+    pub attribute: String,
+    /// Name of the authentication extra field sent to the backend.
+    pub field: String,
+    /// Whether authentication fails when the attribute is missing and there's
+    /// no default value. Defaults to `true`.
+    #[serde(default = "default_required")]
+    pub required: bool,
+    /// Value sent when the attribute is missing.
+    #[serde(default)]
+    pub default: Option<String>,
+    #[serde(default)]
+    pub normalize: Normalization,
+}
+
+/// Reads the list of authentication fields from the `AUTH_FIELDS` env var, a
+/// JSON list of [`AuthField`]. For example:
+///
+/// ```json
+/// [
+///     {"attribute": "VoterUserId", "field": "Membership Number", "normalize": "digits"},
+///     {"attribute": "VoterPIN", "field": "code"},
+///     {"attribute": "VoterBirthDate", "field": "Date of Birth", "normalize": "date-ddmmyyyy"},
+///     {"attribute": "VoterPostcode", "field": "Postcode", "required": false, "normalize": "uppercase"}
+/// ]
+/// ```
+///
+/// If `AUTH_FIELDS` is not set, `VoterUserId` is mapped to the `USER_ID_KEY`
+/// env var (unless the auth mode doesn't use the user id) and `VoterPIN` is
+/// mapped to the `VOTER_PIN_KEY` env var.
+pub fn get_auth_fields(auth_mode: AuthMode) -> Result<Vec<AuthField>, IvrError> {
+    if let Some(auth_fields) = json_env_var::<Vec<AuthField>>("AUTH_FIELDS")? {
+        event!(Level::INFO, "auth_fields={:?}", auth_fields);
+        return Ok(auth_fields);
+    }
+
+    let mut auth_fields: Vec<AuthField> = vec![];
+    if auth_mode.uses_user_id() {
+        // This is the authentication extra field name for the user id
+        let user_id_key = env_var("USER_ID_KEY")?;
+        event!(Level::INFO, user_id_key);
+        auth_fields.push(AuthField {
+            attribute: "VoterUserId".into(),
+            field: user_id_key,
+            required: true,
+            default: None,
+            normalize: Normalization::None,
+        });
+    }
+
+    // This is the authentication extra field name for the voter pin
+    let voter_pin_key = env_var("VOTER_PIN_KEY")?;
+    event!(Level::INFO, voter_pin_key);
+    auth_fields.push(AuthField {
+        attribute: "VoterPIN".into(),
+        field: voter_pin_key,
+        required: true,
+        default: None,
+        normalize: Normalization::None,
+    });
+    Ok(auth_fields)
+}
+
+/// Builds the authentication extra fields from the contact attributes.
+pub fn get_auth_data(
+    auth_fields: &[AuthField],
+    attributes: &HashMap<String, String>
+) -> Result<HashMap<String, String>, IvrError>
+{
+    let mut data: HashMap<String, String> = HashMap::new();
+    for auth_field in auth_fields.iter() {
+        let value = match (attributes.get(&auth_field.attribute), &auth_field.default) {
+            (Some(value), _) => auth_field
+                .normalize
+                .apply(value)
+                .ok_or_else(|| {
+                    IvrError::InvalidAttribute(auth_field.attribute.clone())
+                })?,
+            (None, Some(default)) => default.clone(),
+            (None, None) if auth_field.required => {
+                return Err(
+                    IvrError::MissingAttribute(auth_field.attribute.clone())
+                );
+            },
+            (None, None) => continue,
+        };
+        data.insert(auth_field.field.clone(), value);
+    }
+    Ok(data)
+}
+
+/// Returns the value of a contact attribute as it's sent to the backend, i.e.
+/// with the normalization of its authentication field applied. Returns `None`
+/// if the attribute is missing, it's not sent to the backend or its value is
+/// not valid.
+pub fn normalized_attribute(
+    auth_fields: &[AuthField],
+    attributes: &HashMap<String, String>,
+    attribute: &str
+) -> Option<String>
+{
+    let auth_field = auth_fields
+        .iter()
+        .find(|auth_field| auth_field.attribute == attribute)?;
+    auth_field.normalize.apply(attributes.get(attribute)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use ivr_common::IvrError;
+    use crate::auth_fields::{
+        get_auth_data, normalized_attribute, AuthField, Normalization
+    };
+
+    #[test]
+    fn normalizations() {
+        assert_eq!(Normalization::Digits.apply("12-34 5"), Some("12345".into()));
+        assert_eq!(Normalization::Uppercase.apply(" sw1a "), Some("SW1A".into()));
+        assert_eq!(
+            Normalization::DateDdmmyyyy.apply("31011980"),
+            Some("1980-01-31".into())
+        );
+        assert_eq!(Normalization::DateDdmmyyyy.apply("31131980"), None);
+        assert_eq!(Normalization::DateDdmmyyyy.apply("3101198"), None);
+    }
+
+    #[test]
+    fn auth_data_from_attributes() {
+        let auth_fields: Vec<AuthField> = serde_json::from_str(r#"[
+            {"attribute": "VoterUserId", "field": "user-id", "normalize": "digits"},
+            {"attribute": "VoterPIN", "field": "code"},
+            {"attribute": "VoterPostcode", "field": "postcode", "required": false},
+            {"attribute": "VoterRegion", "field": "region", "default": "north"}
+        ]"#).unwrap();
+        let attributes: HashMap<String, String> = HashMap::from([
+            ("VoterUserId".into(), "100-2".into()),
+            ("VoterPIN".into(), "1234".into()),
+        ]);
+        let data = get_auth_data(&auth_fields, &attributes).unwrap();
+        assert_eq!(
+            data,
+            HashMap::from([
+                ("user-id".into(), "1002".into()),
+                ("code".into(), "1234".into()),
+                ("region".into(), "north".into()),
+            ])
+        );
+
+        let attributes: HashMap<String, String> = HashMap::from([
+            ("VoterUserId".into(), "100".into()),
+        ]);
+        assert_eq!(
+            get_auth_data(&auth_fields, &attributes),
+            Err(IvrError::MissingAttribute("VoterPIN".into()))
+        );
+    }
+
+    #[test]
+    fn normalized_attributes() {
+        let auth_fields: Vec<AuthField> = serde_json::from_str(r#"[
+            {"attribute": "VoterUserId", "field": "user-id", "normalize": "digits"}
+        ]"#).unwrap();
+        for user_id in ["100", " 100", "1-00"] {
+            let attributes: HashMap<String, String> = HashMap::from([
+                ("VoterUserId".into(), user_id.into()),
+                ("VoterPIN".into(), "1234".into()),
+            ]);
+            assert_eq!(
+                normalized_attribute(&auth_fields, &attributes, "VoterUserId"),
+                Some("100".into())
+            );
+            // the PIN is not sent to the backend with these fields
+            assert_eq!(
+                normalized_attribute(&auth_fields, &attributes, "VoterPIN"),
+                None
+            );
+        }
+    }
+}
@@ -0,0 +1,108 @@
+// SPDX-FileCopyrightText: 2022 Eduardo Robles <edu@sequentech.io>
+//
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Caller-ID (ANI) bound authentication.
+
+use std::str::FromStr;
+use ivr_common::IvrError;
+
+/// Which credentials are sent to the Sequent authentication endpoint,
+/// configured with the `AUTH_MODE` env var.
+// the variants are named after the `AUTH_MODE` values
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AuthMode {
+    /// `user-id`: the user id and PIN typed by the voter. This is the default.
+    UserId,
+    /// `user-id-and-caller-id`: the user id and PIN typed by the voter, plus
+    /// the caller number in the `CALLER_ID_KEY` field.
+    UserIdAndCallerId,
+    /// `caller-id`: the caller number in the `CALLER_ID_KEY` field replaces
+    /// the user id, so the voter only types the PIN.
+    CallerId,
+}
+
+impl AuthMode {
+    /// Whether the typed user id is sent to the backend.
+    pub fn uses_user_id(&self) -> bool {
+        *self != AuthMode::CallerId
+    }
+
+    /// Whether the caller number is sent to the backend.
+    pub fn uses_caller_id(&self) -> bool {
+        *self != AuthMode::UserId
+    }
+}
+
+impl FromStr for AuthMode {
+    type Err = IvrError;
+
+    fn from_str(auth_mode: &str) -> Result<Self, Self::Err> {
+        match auth_mode {
+            "user-id" => Ok(AuthMode::UserId),
+            "user-id-and-caller-id" => Ok(AuthMode::UserIdAndCallerId),
+            "caller-id" => Ok(AuthMode::CallerId),
+            _ => Err(IvrError::Configuration("AUTH_MODE".into())),
+        }
+    }
+}
+
+/// Normalizes a caller number to the E.164 format (`+` followed by up to 15
+/// digits). Spaces, dashes, dots and parentheses are removed, and a leading
+/// `00` international prefix is converted to `+`. Numbers without an
+/// international prefix are prefixed with `default_country_code` (without the
+/// `+`) after removing the leading trunk `0`, if any.
+///
+/// Returns `None` if the number is anonymous or can't be normalized.
+pub fn normalize_e164(
+    number: &str,
+    default_country_code: Option<&str>
+) -> Option<String>
+{
+    let number: String = number
+        .chars()
+        .filter(|character| !" -.()".contains(*character))
+        .collect();
+    let digits = if let Some(digits) = number.strip_prefix('+') {
+        digits.to_string()
+    } else if let Some(digits) = number.strip_prefix("00") {
+        digits.to_string()
+    } else {
+        let country_code = default_country_code?.trim_start_matches('+');
+        format!("{}{}", country_code, number.trim_start_matches('0'))
+    };
+
+    let is_valid = (8..=15).contains(&digits.len())
+        && digits.chars().all(|character| character.is_ascii_digit())
+        && !digits.starts_with('0');
+    if is_valid {
+        Some(format!("+{}", digits))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::caller_id::normalize_e164;
+
+    #[test]
+    fn normalize_caller_numbers() {
+        assert_eq!(
+            normalize_e164("+1 (416) 555-0123", None),
+            Some("+14165550123".into())
+        );
+        assert_eq!(
+            normalize_e164("0034 612 345 678", None),
+            Some("+34612345678".into())
+        );
+        assert_eq!(
+            normalize_e164("0612345678", Some("34")),
+            Some("+34612345678".into())
+        );
+        assert_eq!(normalize_e164("612345678", None), None);
+        assert_eq!(normalize_e164("anonymous", Some("1")), None);
+        assert_eq!(normalize_e164("+1234567890123456", None), None);
+    }
+}
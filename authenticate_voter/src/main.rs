@@ -7,30 +7,236 @@ use lambda_runtime::{run, service_fn, Error, LambdaEvent};
 use std::env;
 use std::str::FromStr;
 use std::collections::HashMap;
+use std::time::SystemTime;
 use serde_json::{json, Value};
 use tracing::{event, Level};
-use oxhttp::Client;
-use oxhttp::model::{Request, Method, Status};
+use ivr_common::IvrError;
+use ivr_common::config::{env_var, json_env_var, parse_env_var};
+use ivr_common::khmac::{unix_timestamp, KhmacToken};
+use ivr_common::connect::{context_deadline, get_attribute, to_response};
+use ivr_common::http::{BackendRequest, HttpClient};
+use ivr_common::ssml;
 
-/// This is the main body for the function.
-/// Write your code inside it.
-/// There are some code example in the following URLs:
-/// - https://github.com/awslabs/aws-lambda-rust-runtime/tree/main/examples
-/// - https://github.com/aws-samples/serverless-rust-demo/
-async fn function_handler(event: LambdaEvent<ConnectEvent>)
-    -> Result<Value, Error>
+mod auth_fields;
+mod caller_id;
+mod throttle;
+mod validation;
+use auth_fields::{get_auth_data, get_auth_fields, normalized_attribute};
+use caller_id::{normalize_e164, AuthMode};
+use throttle::{caller_id_key, user_id_key, Throttle};
+use validation::{validate_credentials, ValidationRules};
+
+/// Name of the lambda parameter used to select the action to perform.
+const ACTION_PARAMETER: &str = "Action";
+
+/// Action that selects one of the child elections returned by a previous
+/// authentication, instead of authenticating the voter.
+const SELECT_CHILD_ELECTION_ACTION: &str = "select-child-election";
+
+/// A child election the voter is allowed to vote in, as returned in the
+/// `vote-children-info` of a parent election authentication.
+#[derive(Debug, PartialEq)]
+struct ChildElection {
+    /// 1-based position of the child election, to be used as the DTMF option
+    index: usize,
+    auth_event_id: String,
+    title: String,
+    auth_token: String,
+    /// Remaining validity of the auth token in seconds, if known
+    auth_token_validity: Option<i64>,
+}
+
+/// Returns the list of child elections with a vote permission token, in the
+/// same order as received from the backend. The title of each child election
+/// is taken from `titles` (keyed by auth event id) and defaults to
+/// "Election <auth-event-id>".
+fn get_child_elections(
+    vote_children_info: &Value,
+    titles: &HashMap<String, String>
+) -> Vec<ChildElection>
 {
-    let (connect_event, connect_context) = event.into_parts();
-    event!(
-        Level::DEBUG,
-        connect_event = serde_json::to_string(&connect_event)?,
-        connect_context = serde_json::to_string(&connect_context)?
-    );
+    vote_children_info
+        .as_array()
+        .map(|children_info| children_info.as_slice())
+        .unwrap_or(&[])
+        .iter()
+        // filter null or empty tokens
+        .filter(|child_info| {
+            child_info["vote-permission-token"].is_string()
+                && !child_info["vote-permission-token"]
+                    .as_str()
+                    .unwrap()
+                    .is_empty()
+        })
+        .enumerate()
+        .map(|(position, child_info)| {
+            let auth_event_id = match &child_info["auth-event-id"] {
+                Value::String(auth_event_id) => auth_event_id.clone(),
+                auth_event_id => auth_event_id.to_string(),
+            };
+            let title = titles
+                .get(&auth_event_id)
+                .cloned()
+                .unwrap_or_else(|| format!("Election {}", auth_event_id));
+            ChildElection {
+                index: position + 1,
+                title,
+                auth_token: child_info["vote-permission-token"]
+                    .as_str()
+                    .unwrap()
+                    .into(),
+                auth_event_id,
+                auth_token_validity: None,
+            }
+        })
+        .collect()
+}
+
+/// Converts the list of child elections into flat contact attributes, as
+/// Amazon Connect only accepts a flat map of strings as the lambda result:
+/// - `ChildElectionsCount`: number of child elections.
+/// - `ChildElection_<index>_Index`, `ChildElection_<index>_AuthEventId`,
+///   `ChildElection_<index>_Title` and `ChildElection_<index>_AuthToken` for
+///   each child election, and `ChildElection_<index>_AuthTokenValiditySeconds`
+///   if known.
+/// - `ChildElectionsMenuSSML`: a spoken menu listing the child elections, with
+///   the titles escaped.
+///
+/// If there's only one child election, `AuthToken`, `ElectionId` and
+/// `AuthTokenValiditySeconds` (if known) are also set so that the voter doesn't
+/// need to choose.
+fn child_elections_value(child_elections: &[ChildElection]) -> Value {
+    let mut ret_value = json!({
+        "ChildElectionsCount": child_elections.len().to_string()
+    });
+    let ret_map = ret_value.as_object_mut().unwrap();
+    let mut menu_ssml = String::from("");
+    for child_election in child_elections.iter() {
+        let prefix = format!("ChildElection_{}", child_election.index);
+        ret_map.insert(
+            format!("{}_Index", prefix),
+            child_election.index.to_string().into()
+        );
+        ret_map.insert(
+            format!("{}_AuthEventId", prefix),
+            child_election.auth_event_id.clone().into()
+        );
+        ret_map.insert(
+            format!("{}_Title", prefix),
+            child_election.title.clone().into()
+        );
+        ret_map.insert(
+            format!("{}_AuthToken", prefix),
+            child_election.auth_token.clone().into()
+        );
+        if let Some(auth_token_validity) = child_election.auth_token_validity {
+            ret_map.insert(
+                format!("{}_AuthTokenValiditySeconds", prefix),
+                auth_token_validity.to_string().into()
+            );
+        }
+        menu_ssml = format!(
+            "{}<s>Press {} for {}.</s>",
+            menu_ssml,
+            child_election.index,
+            ssml::escape(&child_election.title)
+        );
+    }
+    ret_map.insert("ChildElectionsMenuSSML".into(), menu_ssml.into());
+
+    if child_elections.len() == 1 {
+        ret_map.insert(
+            "AuthToken".into(),
+            child_elections[0].auth_token.clone().into()
+        );
+        ret_map.insert(
+            "ElectionId".into(),
+            child_elections[0].auth_event_id.clone().into()
+        );
+        if let Some(auth_token_validity) = child_elections[0].auth_token_validity {
+            ret_map.insert(
+                "AuthTokenValiditySeconds".into(),
+                auth_token_validity.to_string().into()
+            );
+        }
+    }
+    ret_value
+}
+
+/// Parses and checks the vote permission token returned by the backend for
+/// `election_id`, returning its remaining validity in seconds if known.
+fn check_backend_auth_token(
+    auth_token: &str,
+    election_id: &str,
+    validity_seconds: Option<u64>
+) -> Result<Option<i64>, IvrError>
+{
+    let token: KhmacToken = auth_token
+        .parse()
+        .map_err(|_| IvrError::InvalidBackendResponse(
+            format!("invalid vote-permission-token: {}", auth_token)
+        ))?;
+    token.check(election_id, validity_seconds, unix_timestamp())
+}
+
+/// Selects one of the child elections returned by a previous authentication.
+/// The contact flow is expected to have stored the `ChildElection_*` result
+/// attributes of the authentication as contact attributes, and the chosen
+/// index in the `SelectedChildElection` contact attribute. Returns the
+/// `AuthToken`, `ElectionId` and `AuthTokenValiditySeconds` (if known) of the
+/// chosen child election.
+fn select_child_election(attributes: &HashMap<String, String>)
+    -> Result<Value, IvrError>
+{
+    let selected_index: &String =
+        get_attribute(attributes, "SelectedChildElection")?;
+    event!(Level::INFO, selected_index);
+
+    let prefix = format!("ChildElection_{}", selected_index.trim());
+    let auth_token = attributes.get(&format!("{}_AuthToken", prefix));
+    let election_id = attributes.get(&format!("{}_AuthEventId", prefix));
+    match (auth_token, election_id) {
+        (Some(auth_token), Some(election_id)) => {
+            let token: KhmacToken = auth_token.parse()?;
+            let auth_token_validity = token.check(
+                election_id,
+                parse_env_var("AUTH_TOKEN_VALIDITY_SECONDS")?,
+                unix_timestamp()
+            )?;
+            let mut ret_value = json!({
+                "AuthToken": auth_token,
+                "ElectionId": election_id
+            });
+            if let Some(auth_token_validity) = auth_token_validity {
+                ret_value["AuthTokenValiditySeconds"] =
+                    auth_token_validity.to_string().into();
+            }
+            event!(Level::DEBUG, ret_value = ret_value.to_string());
+            Ok(ret_value)
+        },
+        _ => Err(IvrError::InvalidChildElectionSelection)
+    }
+}
+
+/// Authenticates the voter with the credentials provided in the contact
+/// attributes, returning the vote permission token. The work is finished
+/// before the lambda deadline, if known, or the `timeout` error is returned.
+async fn authenticate(
+    http_client: &HttpClient,
+    connect_event: &ConnectEvent,
+    lambda_deadline: Option<SystemTime>
+) -> Result<Value, IvrError>
+{
+    let started_at = SystemTime::now();
+    let deadline = http_client
+        .config()
+        .timeouts
+        .deadline(lambda_deadline, started_at);
 
     // Example base_url: 
     // https://clientname.example.com/iam/api/auth-event/{{election_id}}/authenticate/
     // Note that {{election_id}} will be substituted with the election id
-    let login_url_template = env::var("LOGIN_URL")?;
+    let login_url_template = env_var("LOGIN_URL")?;
     event!(Level::INFO, login_url_template);
 
     // If election id is not provided by the caller, we will use this one 
@@ -38,70 +244,113 @@ async fn function_handler(event: LambdaEvent<ConnectEvent>)
     let default_election_id_result = env::var("DEFAULT_ELECTION_ID");
     event!(Level::INFO, "{:?}", default_election_id_result);
 
-    // This is the authentication extra field name for the user id
-    let user_id_key = env::var("USER_ID_KEY")?;
-    event!(Level::INFO, user_id_key);
+    // Which credentials are sent to the backend, see `AuthMode`
+    let auth_mode: AuthMode = env::var("AUTH_MODE")
+        .unwrap_or(String::from("user-id"))
+        .parse()?;
+    event!(Level::INFO, "auth_mode={:?}", auth_mode);
 
-    // This is the authentication extra field name for the voter pin
-    let voter_pin_key = env::var("VOTER_PIN_KEY")?;
-    event!(Level::INFO, voter_pin_key);
+    // Mapping from contact attributes to authentication extra fields
+    let auth_fields = get_auth_fields(auth_mode)?;
 
-    let election_id_result = connect_event
-        .details
-        .contact_data
-        .attributes
-        .get("ElectionId");
-    let election_id: String = match (election_id_result, default_election_id_result)
-    {
+    // Optional validity of the vote permission tokens in seconds, as
+    // configured in the backend. If set, expired tokens are rejected and the
+    // remaining validity is returned in the `AuthTokenValiditySeconds`
+    // attribute
+    let auth_token_validity_seconds: Option<u64> =
+        parse_env_var("AUTH_TOKEN_VALIDITY_SECONDS")?;
+    event!(Level::INFO, "{:?}", auth_token_validity_seconds);
+
+    // Optional JSON map from child election auth event id to the title that
+    // is spoken to the voter, for example: {"34": "the Northern Region"}
+    let child_election_titles: HashMap<String, String> =
+        json_env_var("CHILD_ELECTION_TITLES")?.unwrap_or_default();
+    event!(Level::INFO, "child_election_titles={:?}", child_election_titles);
+
+    // Optional per election validation rules of the credentials typed by the
+    // voter, see `ValidationRules`
+    let validation_rules: ValidationRules =
+        json_env_var("CREDENTIAL_VALIDATION")?.unwrap_or_default();
+    event!(Level::INFO, "validation_rules={:?}", validation_rules);
+
+    let attributes = &connect_event.details.contact_data.attributes;
+    let election_id: String = match (
+        attributes.get("ElectionId"),
+        default_election_id_result
+    ) {
         (Some(election_id), _) => election_id.clone(),
         (_, Ok(default_election_id)) => default_election_id,
-        _ => return Err(
-            "ElectionId contact data attribute missing and default election id
-            env var missing too".into()
-        ),
+        _ => return Err(IvrError::MissingAttribute("ElectionId".into())),
     };
     event!(Level::INFO, election_id);
 
-    let user_id_value: &String = connect_event
-        .details
-        .contact_data
-        .attributes
-        .get("VoterUserId")
-        .ok_or(String::from("VoterUserId contact data attribute missing"))?;
-    event!(Level::INFO, user_id_value);
-    let voter_pin_value: &String = connect_event
-        .details
-        .contact_data
-        .attributes
-        .get("VoterPIN")
-        .ok_or(String::from("VoterPIN contact data attribute missing"))?;
-    event!(Level::DEBUG, voter_pin_value);
+    // Reject malformed credentials without calling the backend
+    validate_credentials(
+        &validation_rules,
+        &election_id,
+        &auth_fields,
+        attributes
+    )?;
 
-    let mut data = HashMap::new();
-    data.insert(user_id_key, user_id_value);
-    data.insert(voter_pin_key, voter_pin_value);
-    let body: String = serde_json::to_string(&data)?;
+    let mut data: HashMap<String, String> =
+        get_auth_data(&auth_fields, attributes)?;
+    event!(Level::DEBUG, "auth_data={:?}", data);
+
+    if auth_mode.uses_caller_id() {
+        // This is the authentication extra field name for the caller number
+        let caller_id_key = env_var("CALLER_ID_KEY")?;
+        event!(Level::INFO, caller_id_key);
+
+        // Country code (for example "1") used for caller numbers received
+        // without international prefix
+        let default_country_code = env::var("CALLER_ID_DEFAULT_COUNTRY_CODE")
+            .ok();
+        let caller_id_value = connect_event
+            .details
+            .contact_data
+            .customer_endpoint
+            .address
+            .as_ref()
+            .and_then(|address| {
+                normalize_e164(address, default_country_code.as_deref())
+            })
+            .ok_or(IvrError::MissingCallerId)?;
+        event!(Level::INFO, caller_id_value);
+        data.insert(caller_id_key, caller_id_value);
+    }
+
+    let body: String = serde_json::to_string(&data)
+        .map_err(|error| IvrError::Internal(error.to_string()))?;
 
-    let client = Client::new();
     let login_url = login_url_template.replace("{{election_id}}", &election_id);
     event!(Level::DEBUG, request_url = login_url, request_body = body);
-    let response = client.request(
-        Request::builder(
-            Method::POST,
-            login_url.parse()?
-        )
+    let contact_data = &connect_event.details.contact_data;
+    let headers = http_client.config().headers.render(&HashMap::from([
+        ("contact_id", contact_data.contact_id.clone().unwrap_or_default()),
+        (
+            "initial_contact_id",
+            contact_data.initial_contact_id.clone().unwrap_or_default()
+        ),
+        ("election_id", election_id.clone()),
+    ]));
+    // Authenticating again only issues a new vote permission token, so the
+    // call can be retried as if it was idempotent
+    let request = BackendRequest::post(&login_url, "LOGIN_URL")?
+        .with_header("Content-Type", "application/json", || {
+            IvrError::Internal("invalid header".into())
+        })?
+        .with_extra_headers(&headers)?
         .with_body(body)
-    )?;
-
-    let status = response.status();
-    event!(Level::INFO, request_response_status = status.to_string());
-
-    let body = response.into_body().to_string()?;
+        .idempotent();
+    let (status, body) = http_client.send(deadline, &request).await?;
     event!(Level::DEBUG, request_response_body = body);
-    let body_value: Value = serde_json::from_str(&body)?;
 
     match status {
-        Status::OK => {
+        200 => {
+            let body_value: Value = serde_json::from_str(&body)
+                .map_err(|error| {
+                    IvrError::InvalidBackendResponse(error.to_string())
+                })?;
             let vote_permission_token: &Value = &body_value["vote-permission-token"];
             event!(Level::DEBUG, "vote_permission_token={:?}", vote_permission_token);
             let vote_children_info: &Value = &body_value["vote-children-info"];
@@ -110,10 +359,19 @@ async fn function_handler(event: LambdaEvent<ConnectEvent>)
             if vote_permission_token.is_string()
                 && vote_permission_token.to_string().len() > 0
             {
-                let ret_value = json!({
+                let auth_token_validity = check_backend_auth_token(
+                    vote_permission_token.as_str().unwrap(),
+                    &election_id,
+                    auth_token_validity_seconds
+                )?;
+                let mut ret_value = json!({
                     "AuthToken": vote_permission_token,
                     "ElectionId": election_id
                 });
+                if let Some(auth_token_validity) = auth_token_validity {
+                    ret_value["AuthTokenValiditySeconds"] =
+                        auth_token_validity.to_string().into();
+                }
                 event!(Level::DEBUG, ret_value = ret_value.to_string());
 
                 // Return the vote_permission_token
@@ -123,40 +381,176 @@ async fn function_handler(event: LambdaEvent<ConnectEvent>)
                 && vote_children_info.as_array().unwrap().len() > 0
                 && vote_children_info.as_array().unwrap()[0].is_object()
             {
-                let child_election = vote_children_info
-                    .as_array()
-                    .unwrap()
-                    .iter()
-                    // filter null tokens
-                    .filter(|child_info| {
-                        child_info
-                            .as_object()
-                            .unwrap()["vote-permission-token"]
-                            .is_string()
-                    })
-                    .collect::<Vec<&Value>>()[0]
-                    .as_object()
-                    .unwrap();
-                // we perform login only to the first child election
-                let ret_value = json!({
-                    "AuthToken":
-                        child_election["vote-permission-token"]
-                            .as_str()
-                            .unwrap(),
-                    "ElectionId": child_election["auth-event-id"].to_string()
-                });
+                let mut child_elections = get_child_elections(
+                    vote_children_info,
+                    &child_election_titles
+                );
+                if child_elections.is_empty() {
+                    return Err(IvrError::EmptyVotePermissionToken);
+                }
+                for child_election in child_elections.iter_mut() {
+                    child_election.auth_token_validity = check_backend_auth_token(
+                        &child_election.auth_token,
+                        &child_election.auth_event_id,
+                        auth_token_validity_seconds
+                    )?;
+                }
+                let ret_value = child_elections_value(&child_elections);
                 event!(Level::DEBUG, ret_value = ret_value.to_string());
 
-                // Return the vote_permission_token
+                // Return the list of child elections the voter can vote in
                 Ok(ret_value)
             } else {
-                Err("empty-vote-permission-token".into())
+                Err(IvrError::EmptyVotePermissionToken)
             }
         },
-        _ => Err("invalid-status".into())
+        // the backend answers with one of these client errors when the
+        // credentials are invalid. Any other status, such as a 404 from a
+        // misconfigured `LOGIN_URL`, is not the voter's fault
+        400 | 401 | 403 => Err(IvrError::InvalidCredentials),
+        status => Err(IvrError::InvalidStatus(status))
     }    
 }
 
+/// Returns the keys used to throttle the authentication attempts: the caller
+/// number and the user id, when available. The user id is normalized as it's
+/// sent to the backend, so that variants of the same credential share the
+/// key.
+fn get_throttle_keys(connect_event: &ConnectEvent)
+    -> Result<Vec<String>, IvrError>
+{
+    let auth_mode: AuthMode = env::var("AUTH_MODE")
+        .unwrap_or(String::from("user-id"))
+        .parse()?;
+    let auth_fields = get_auth_fields(auth_mode)?;
+    let mut keys: Vec<String> = vec![];
+    let default_country_code = env::var("CALLER_ID_DEFAULT_COUNTRY_CODE").ok();
+    let caller_id = connect_event
+        .details
+        .contact_data
+        .customer_endpoint
+        .address
+        .as_ref()
+        .and_then(|address| {
+            normalize_e164(address, default_country_code.as_deref())
+        });
+    if let Some(caller_id) = caller_id {
+        keys.push(caller_id_key(&caller_id));
+    }
+
+    let attributes = &connect_event.details.contact_data.attributes;
+    let election_id = attributes
+        .get("ElectionId")
+        .cloned()
+        .or_else(|| env::var("DEFAULT_ELECTION_ID").ok());
+    let user_id =
+        normalized_attribute(&auth_fields, attributes, "VoterUserId");
+    if let (Some(election_id), Some(user_id)) = (election_id, user_id) {
+        keys.push(user_id_key(&election_id, &user_id));
+    }
+    Ok(keys)
+}
+
+/// Authenticates the voter, throttling the failed attempts if configured.
+/// Returns the result and, if throttling is enabled and authentication failed,
+/// the remaining attempts.
+async fn throttled_authenticate(
+    http_client: &Result<HttpClient, IvrError>,
+    connect_event: &ConnectEvent,
+    lambda_deadline: Option<SystemTime>
+) -> (Result<Value, IvrError>, Option<u32>)
+{
+    let http_client = match http_client {
+        Ok(http_client) => http_client,
+        Err(error) => return (Err(error.clone()), None),
+    };
+    let throttle = match Throttle::from_env() {
+        Ok(Some(throttle)) => throttle,
+        Ok(None) => return (
+            authenticate(http_client, connect_event, lambda_deadline).await,
+            None
+        ),
+        Err(error) => return (Err(error), None),
+    };
+    let keys = match get_throttle_keys(connect_event) {
+        Ok(keys) => keys,
+        Err(error) => return (Err(error), None),
+    };
+    event!(Level::INFO, "throttle_keys={:?}", keys);
+
+    if let Err(error) = throttle.check(&keys, unix_timestamp()) {
+        return (Err(error), Some(0));
+    }
+    match authenticate(http_client, connect_event, lambda_deadline).await {
+        Ok(ret_value) => match throttle.record_success(&keys) {
+            Ok(()) => (Ok(ret_value), None),
+            Err(error) => (Err(error), None),
+        },
+        Err(IvrError::InvalidCredentials) => {
+            match throttle.record_failure(&keys, unix_timestamp()) {
+                Ok(0) => (Err(IvrError::LockedOut), Some(0)),
+                Ok(remaining_attempts) => (
+                    Err(IvrError::InvalidCredentials),
+                    Some(remaining_attempts)
+                ),
+                Err(error) => (Err(error), None),
+            }
+        },
+        Err(error) => (Err(error), None),
+    }
+}
+
+/// This is the main body for the function.
+/// Write your code inside it.
+/// There are some code example in the following URLs:
+/// - https://github.com/awslabs/aws-lambda-rust-runtime/tree/main/examples
+/// - https://github.com/aws-samples/serverless-rust-demo/
+///
+/// Errors are not returned as a failed invocation but as a successful
+/// response with the `Status`, `ErrorCode` and `Retryable` attributes, so that
+/// the contact flow can branch on them.
+///
+/// `http_client` is created at cold start, or is the configuration error found
+/// when creating it.
+async fn function_handler(
+    http_client: &Result<HttpClient, IvrError>,
+    event: LambdaEvent<ConnectEvent>
+) -> Result<Value, Error>
+{
+    let (connect_event, connect_context) = event.into_parts();
+    event!(
+        Level::DEBUG,
+        connect_event = serde_json::to_string(&connect_event)?,
+        connect_context = serde_json::to_string(&connect_context)?
+    );
+
+    let action = connect_event
+        .details
+        .parameters
+        .get(ACTION_PARAMETER)
+        .map(|action| action.as_str());
+    let (result, remaining_attempts) =
+        if action == Some(SELECT_CHILD_ELECTION_ACTION) {
+            (
+                select_child_election(
+                    &connect_event.details.contact_data.attributes
+                ),
+                None
+            )
+        } else {
+            throttled_authenticate(
+                http_client,
+                &connect_event,
+                context_deadline(connect_context.deadline)
+            ).await
+        };
+    let mut ret_value = to_response(result);
+    if let Some(remaining_attempts) = remaining_attempts {
+        ret_value["RemainingAttempts"] = remaining_attempts.to_string().into();
+    }
+    Ok(ret_value)
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Error> {
     let tracing_level_str = env::var("TRACING_LEVEL")
@@ -175,13 +569,24 @@ async fn main() -> Result<(), Error> {
     event!(Level::INFO, tracing_level_str);
     event!(Level::INFO, "starting up `authenticate_voter` lambda");
 
-    run(service_fn(function_handler)).await
+    // The HTTP client is created once and reused by the warm invocations, so
+    // that the connections to the backend are kept alive. A configuration
+    // error is returned by every invocation
+    let http_client = HttpClient::from_env();
+    if let Err(error) = &http_client {
+        event!(Level::ERROR, "invalid HTTP configuration: {}", error);
+    }
+    let http_client = &http_client;
+    run(service_fn(move |event| async move {
+        function_handler(http_client, event).await
+    })).await
 }
 
 #[cfg(test)]
 mod tests {
     use std::collections::HashMap;
     use std::env;
+    use std::time::Duration;
     use std::include_str;
     use serde_json::Value;
     use serde_json::json;
@@ -191,6 +596,7 @@ mod tests {
     use aws_lambda_events::event::connect::ConnectEvent;
     use httpmock::prelude::*;
     use httpmock::Mock;
+    use ivr_common::http::HttpClient;
     //use num_bigint::BigUint;
     //use num_traits::Num;
 
@@ -227,6 +633,7 @@ mod tests {
         let auth_mock = server.mock(|when, then| {
             when.method(POST)
                 .path("/authentication-success")
+                .header("content-type", "application/json")
                 .json_body(json!({ "user-id": "100", "code": "22345678" }));
             then.status(200)
                 .header("content-type", "application/json")
@@ -237,7 +644,21 @@ mod tests {
             ("TRACING_LEVEL", "debug"),
             ("USER_ID_KEY", "user-id"),
             ("VOTER_PIN_KEY", "code"),
-            ("LOGIN_URL", login_url.as_str())
+            ("LOGIN_URL", login_url.as_str()),
+            ("CHILD_ELECTION_TITLES", ""),
+            ("AUTH_MODE", ""),
+            ("CALLER_ID_KEY", ""),
+            ("CALLER_ID_DEFAULT_COUNTRY_CODE", ""),
+            ("AUTH_TOKEN_VALIDITY_SECONDS", ""),
+            ("THROTTLE_MAX_ATTEMPTS", ""),
+            ("CREDENTIAL_VALIDATION", ""),
+            ("THROTTLE_STORE", ""),
+            ("HTTP_RETRY_MAX_ATTEMPTS", ""),
+            ("HTTP_RETRY_BASE_DELAY_MS", ""),
+            ("HTTP_RETRY_MAX_DELAY_MS", ""),
+            ("HTTP_TIMEOUT_MS", ""),
+            ("HTTP_HEADERS", ""),
+            ("HTTP_SECRET_HEADERS", "")
         ]);
         let override_env_vars_val = override_env_vars
             .unwrap_or(Default::default());
@@ -250,7 +671,8 @@ mod tests {
         return auth_mock;
     }
 
-    // calls the crate's lambda
+    // calls the crate's lambda, with the HTTP client created from the env vars
+    // as in a cold start
     async fn call_lambda(connect_event_str: &str) -> Result<Value, Error> {
         let input: ConnectEvent = serde_json::from_str(connect_event_str)?;
         let context = lambda_runtime::Context::default();
@@ -260,7 +682,8 @@ mod tests {
             "connect_event = {}", 
             serde_json::to_string(&connect_event).unwrap_or(Default::default())
         );
-        let event_result = function_handler(event)
+        let http_client = HttpClient::from_env();
+        let event_result = function_handler(&http_client, event)
             .await;
         return event_result;
     }
@@ -301,9 +724,132 @@ mod tests {
             &event_result
         );
         auth_mock.assert();
+        assert_eq!(event_result["Status"], "success");
         assert_eq!(event_result["AuthToken"], "khmac:///sha-256;c4ba96310ea7474b4ee2e84b00eaf412786816ea7d3713af866dab67c3201668/4cf53604330bab6a6179de2e:AuthEvent:17:vote:1665653516");
     }
 
+    // Simulates an authentication to a parent election with multiple child
+    // elections, one of which the voter cannot vote in
+    #[tokio::test]
+    #[serial]
+    async fn authentication_children_success() {
+        let server = MockServer::start();
+        let auth_mock = init(
+            &server,
+            Some(HashMap::from([
+                (
+                    "CHILD_ELECTION_TITLES",
+                    r#"{"34": "the Northern & Western Region"}"#
+                )
+            ])),
+            None,
+            include_str!(
+                "../test/mock_backend/authentication_children_success.json"
+            )
+        );
+
+        let event_result = call_lambda(include_str!("../test/test_data_1.json"))
+            .await
+            .expect("failed to handle event");
+
+        auth_mock.assert();
+        assert_eq!(event_result["ChildElectionsCount"], "2");
+        assert_eq!(event_result["ChildElection_1_Index"], "1");
+        assert_eq!(event_result["ChildElection_1_AuthEventId"], "34");
+        assert_eq!(
+            event_result["ChildElection_1_Title"],
+            "the Northern & Western Region"
+        );
+        assert_eq!(event_result["ChildElection_2_AuthEventId"], "36");
+        assert_eq!(event_result["ChildElection_2_Title"], "Election 36");
+        assert_eq!(
+            event_result["ChildElectionsMenuSSML"],
+            "<s>Press 1 for the Northern &amp; Western Region.</s>\
+            <s>Press 2 for Election 36.</s>"
+        );
+        // with multiple child elections, the voter needs to choose one
+        assert!(event_result.get("AuthToken").is_none());
+    }
+
+    // Simulates the selection of a child election after authentication
+    #[tokio::test]
+    #[serial]
+    async fn select_child_election() {
+        // the selection doesn't call the backend, but the env vars of the
+        // previous tests still need to be reset
+        let server = MockServer::start();
+        init(
+            &server,
+            Default::default(),
+            None,
+            include_str!("../test/mock_backend/authentication_success.json")
+        );
+
+        let event_result = call_lambda(include_str!("../test/test_data_3.json"))
+            .await
+            .expect("failed to handle event");
+
+        assert_eq!(event_result["ElectionId"], "36");
+        assert_eq!(event_result["AuthToken"], "khmac:///sha-256;5e6f7a8b9c0d1e2f3a4b5c6d7e8f9a0b1c2d3e4f5a6b7c8d9e0f1a2b3c4d5e6f/4cf53604330bab6a6179de2e:AuthEvent:36:vote:1665653516");
+    }
+
+    // Simulates an authentication where the caller number replaces the user id
+    #[tokio::test]
+    #[serial]
+    async fn authentication_caller_id_success() {
+        let server = MockServer::start();
+        let auth_voter_path = "/authentication-caller-id";
+        init(
+            &server,
+            Some(HashMap::from([
+                ("AUTH_MODE", "caller-id"),
+                ("CALLER_ID_KEY", "tlf")
+            ])),
+            Some(auth_voter_path),
+            include_str!("../test/mock_backend/authentication_success.json")
+        );
+        let auth_caller_id_mock = server.mock(|when, then| {
+            when.method(POST)
+                .path(auth_voter_path)
+                .json_body(json!({ "tlf": "+1234567890", "code": "22345678" }));
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(include_str!(
+                    "../test/mock_backend/authentication_success.json"
+                ));
+        });
+
+        let event_result = call_lambda(include_str!("../test/test_data_1.json"))
+            .await
+            .expect("failed to handle event");
+
+        auth_caller_id_mock.assert();
+        assert_eq!(event_result["Status"], "success");
+    }
+
+    // Simulates an authentication where the backend returns an expired token
+    #[tokio::test]
+    #[serial]
+    async fn authentication_expired_token() {
+        let server = MockServer::start();
+        let auth_mock = init(
+            &server,
+            Some(HashMap::from([
+                ("AUTH_TOKEN_VALIDITY_SECONDS", "3600")
+            ])),
+            None,
+            include_str!("../test/mock_backend/authentication_success.json")
+        );
+
+        let event_result = call_lambda(include_str!("../test/test_data_1.json"))
+            .await
+            .expect("failed to handle event");
+
+        auth_mock.assert();
+        assert_eq!(event_result["Status"], "error");
+        assert_eq!(event_result["ErrorCode"], "expired-auth-token");
+    }
+
     // simulates an authentication failure because input data is invalid
     #[tokio::test]
     #[serial]
@@ -315,12 +861,54 @@ mod tests {
             None,
             include_str!("../test/mock_backend/authentication_success.json")
         );
+        // any other credentials are rejected
+        let auth_error_mock = server.mock(|when, then| {
+            when.method(POST)
+                .path("/authentication-success");
+            then.status(401)
+                .header("content-type", "application/json")
+                .body(include_str!(
+                    "../test/mock_backend/authentication_failure.json"
+                ));
+        });
+
+        let event_result = call_lambda(include_str!("../test/test_data_2.json"))
+            .await
+            .expect("failed to handle event");
+
+        auth_mock.assert_hits(0);
+        auth_error_mock.assert();
+        assert_eq!(event_result["Status"], "error");
+        assert_eq!(event_result["ErrorCode"], "invalid-credentials");
+        assert_eq!(event_result["Retryable"], "true");
+    }
+
+    // a malformed pin is rejected without calling the backend
+    #[tokio::test]
+    #[serial]
+    async fn authentication_invalid_credential_format() {
+        let server = MockServer::start();
+        let auth_mock = init(
+            &server,
+            Some(HashMap::from([(
+                "CREDENTIAL_VALIDATION",
+                r#"{"17": {"VoterPIN": {"min_length": 4, "max_length": 6, "charset": "digits"}}}"#
+            )])),
+            None,
+            include_str!("../test/mock_backend/authentication_success.json")
+        );
 
-        call_lambda(include_str!("../test/test_data_2.json"))
+        let event_result = call_lambda(include_str!("../test/test_data_1.json"))
             .await
-            .expect_err("authentication succeeded when it should have failed");
+            .expect("failed to handle event");
 
         auth_mock.assert_hits(0);
+        assert_eq!(event_result["Status"], "error");
+        assert_eq!(event_result["ErrorCode"], "invalid-credential-length");
+        assert_eq!(event_result["Retryable"], "true");
+        assert_eq!(event_result["ErrorAttribute"], "VoterPIN");
+        assert_eq!(event_result["ExpectedMinLength"], "4");
+        assert_eq!(event_result["ExpectedMaxLength"], "6");
     }
 
     // simulates an authentication failure (independent of incoming data)
@@ -347,15 +935,207 @@ mod tests {
                 ));
         });
         let event_result = call_lambda(include_str!("../test/test_data_1.json"))
-            .await;
+            .await
+            .expect("failed to handle event");
         auth_error_mock.assert();
-        event_result
-            .expect_err("authentication succeeded when it should have failed");
+        assert_eq!(event_result["Status"], "error");
+        assert_eq!(event_result["ErrorCode"], "invalid-credentials");
+    }
+
+    // a misconfigured login URL is not reported as invalid credentials
+    #[tokio::test]
+    #[serial]
+    async fn authentication_not_found() {
+        let server = MockServer::start();
+        let auth_mock = init(
+            &server,
+            Default::default(),
+            Some("/not-found"),
+            include_str!("../test/mock_backend/authentication_success.json")
+        );
+        let event_result = call_lambda(include_str!("../test/test_data_1.json"))
+            .await
+            .expect("failed to handle event");
+        auth_mock.assert_hits(0);
+        assert_eq!(event_result["Status"], "error");
+        assert_eq!(event_result["ErrorCode"], "invalid-status");
+        assert_eq!(event_result["Retryable"], "false");
+    }
+
+    // the configured extra headers are sent to the backend
+    #[tokio::test]
+    #[serial]
+    async fn authentication_extra_headers() {
+        let server = MockServer::start();
+        let auth_voter_path = "/authenticate-with-headers";
+        init(
+            &server,
+            Some(HashMap::from([
+                ("HTTP_HEADERS", r#"{"X-Request-Id": "{{contact_id}}"}"#),
+                ("HTTP_SECRET_HEADERS", r#"{"X-Api-Key": "TEST_API_KEY"}"#),
+                ("TEST_API_KEY", "secret-api-key")
+            ])),
+            Some(auth_voter_path),
+            include_str!(
+                "../test/mock_backend/authentication_success.json"
+            )
+        );
+        let auth_headers_mock = server.mock(|when, then| {
+            when.method(POST)
+                .path(auth_voter_path)
+                .header("x-request-id", "4a573372-1f28-4e26-b97b-XXXXXXXXXXX")
+                .header("x-api-key", "secret-api-key");
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(include_str!(
+                    "../test/mock_backend/authentication_success.json"
+                ));
+        });
+        let event_result = call_lambda(include_str!("../test/test_data_1.json"))
+            .await
+            .expect("failed to handle event");
+        auth_headers_mock.assert();
+        assert_eq!(event_result["Status"], "success");
     }
 
-    // should panic with LOGIN_URL env var not set
+    // the backend is temporarily unavailable, so the call is retried
+    #[tokio::test]
+    #[serial]
+    async fn authentication_retries() {
+        let server = MockServer::start();
+        let auth_voter_path = "/authenticate-unavailable";
+        init(
+            &server,
+            Some(HashMap::from([
+                ("HTTP_RETRY_MAX_ATTEMPTS", "3"),
+                ("HTTP_RETRY_BASE_DELAY_MS", "1")
+            ])),
+            Some(auth_voter_path),
+            include_str!(
+                "../test/mock_backend/authentication_success.json"
+            )
+        );
+        let auth_error_mock = server.mock(|when, then| {
+            when.method(POST)
+                .path(auth_voter_path);
+            then.status(503);
+        });
+        let event_result = call_lambda(include_str!("../test/test_data_1.json"))
+            .await
+            .expect("failed to handle event");
+        auth_error_mock.assert_hits(3);
+        assert_eq!(event_result["Status"], "error");
+        assert_eq!(event_result["ErrorCode"], "invalid-status");
+        assert_eq!(event_result["Retryable"], "true");
+    }
+
+    // the backend is too slow, so the call times out
+    #[tokio::test]
+    #[serial]
+    async fn authentication_timeout() {
+        let server = MockServer::start();
+        let auth_voter_path = "/authenticate-slow";
+        init(
+            &server,
+            Some(HashMap::from([
+                ("HTTP_RETRY_MAX_ATTEMPTS", "1"),
+                ("HTTP_TIMEOUT_MS", "100")
+            ])),
+            Some(auth_voter_path),
+            include_str!(
+                "../test/mock_backend/authentication_success.json"
+            )
+        );
+        let auth_slow_mock = server.mock(|when, then| {
+            when.method(POST)
+                .path(auth_voter_path);
+            then.status(200)
+                .delay(Duration::from_millis(500))
+                .header("content-type", "application/json")
+                .body(include_str!(
+                    "../test/mock_backend/authentication_success.json"
+                ));
+        });
+        let event_result = call_lambda(include_str!("../test/test_data_1.json"))
+            .await
+            .expect("failed to handle event");
+        auth_slow_mock.assert();
+        assert_eq!(event_result["Status"], "error");
+        assert_eq!(event_result["ErrorCode"], "timeout");
+        assert_eq!(event_result["Retryable"], "true");
+    }
+
+    // simulates repeated authentication failures until the caller is locked
+    // out
+    #[tokio::test]
+    #[serial]
+    async fn authentication_lockout() {
+        let server = MockServer::start();
+        let throttle_path = env::temp_dir()
+            .join(format!("throttle-lockout-{}.json", std::process::id()));
+        let throttle_store = format!("file:{}", throttle_path.display());
+        let auth_voter_path = "/authenticate-failure";
+        init(
+            &server,
+            Some(HashMap::from([
+                ("THROTTLE_MAX_ATTEMPTS", "2"),
+                ("THROTTLE_STORE", throttle_store.as_str())
+            ])),
+            Some(auth_voter_path),
+            include_str!(
+                "../test/mock_backend/authentication_success.json"
+            )
+        );
+        let auth_error_mock = server.mock(|when, then| {
+            when.method(POST)
+                .path(auth_voter_path);
+            then.status(400)
+                .header("content-type", "application/json")
+                .body(include_str!(
+                    "../test/mock_backend/authentication_failure.json"
+                ));
+        });
+
+        let event_result = call_lambda(include_str!("../test/test_data_1.json"))
+            .await
+            .expect("failed to handle event");
+        assert_eq!(event_result["ErrorCode"], "invalid-credentials");
+        assert_eq!(event_result["RemainingAttempts"], "1");
+
+        let event_result = call_lambda(include_str!("../test/test_data_1.json"))
+            .await
+            .expect("failed to handle event");
+        assert_eq!(event_result["ErrorCode"], "locked-out");
+        assert_eq!(event_result["RemainingAttempts"], "0");
+
+        // once locked out, the backend is not called anymore
+        let event_result = call_lambda(include_str!("../test/test_data_1.json"))
+            .await
+            .expect("failed to handle event");
+        assert_eq!(event_result["ErrorCode"], "locked-out");
+        auth_error_mock.assert_hits(2);
+        let _ = std::fs::remove_file(throttle_path);
+
+        // throttling requires an explicit store
+        init(
+            &server,
+            Some(HashMap::from([
+                ("THROTTLE_MAX_ATTEMPTS", "2")
+            ])),
+            Some(auth_voter_path),
+            include_str!(
+                "../test/mock_backend/authentication_success.json"
+            )
+        );
+        let event_result = call_lambda(include_str!("../test/test_data_1.json"))
+            .await
+            .expect("failed to handle event");
+        assert_eq!(event_result["ErrorCode"], "configuration-error");
+        auth_error_mock.assert_hits(2);
+    }
+
+    // should return a configuration error with LOGIN_URL env var not set
     #[tokio::test]
-    #[should_panic]
     #[serial]
     async fn unset_login_url_env_var() {
         let server = MockServer::start();
@@ -367,14 +1147,15 @@ mod tests {
             None,
             include_str!("../test/mock_backend/authentication_success.json")
         );
-        call_lambda(include_str!("../test/test_data_1.json"))
+        let event_result = call_lambda(include_str!("../test/test_data_1.json"))
             .await
             .expect("failed to handle event");
+        assert_eq!(event_result["ErrorCode"], "configuration-error");
+        assert_eq!(event_result["Retryable"], "false");
     }
 
-    // should panic with VOTER_PIN_KEY env var not set
+    // should return a configuration error with VOTER_PIN_KEY env var not set
     #[tokio::test]
-    #[should_panic]
     #[serial]
     async fn unset_voter_pin_key_env_var() {
         let server = MockServer::start();
@@ -386,14 +1167,15 @@ mod tests {
             None,
             include_str!("../test/mock_backend/authentication_success.json")
         );
-        call_lambda(include_str!("../test/test_data_1.json"))
+        let event_result = call_lambda(include_str!("../test/test_data_1.json"))
             .await
             .expect("failed to handle event");
+        assert_eq!(event_result["ErrorCode"], "configuration-error");
+        assert_eq!(event_result["Retryable"], "false");
     }
 
-    // should panic with USER_ID_KEY env var not set
+    // should return a configuration error with USER_ID_KEY env var not set
     #[tokio::test]
-    #[should_panic]
     #[serial]
     async fn unset_user_id_key_env_var() {
         let server = MockServer::start();
@@ -405,8 +1187,10 @@ mod tests {
             None,
             include_str!("../test/mock_backend/authentication_success.json")
         );
-        call_lambda(include_str!("../test/test_data_1.json"))
+        let event_result = call_lambda(include_str!("../test/test_data_1.json"))
             .await
             .expect("failed to handle event");
+        assert_eq!(event_result["ErrorCode"], "configuration-error");
+        assert_eq!(event_result["Retryable"], "false");
     }
 }
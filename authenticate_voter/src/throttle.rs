@@ -0,0 +1,463 @@
+// SPDX-FileCopyrightText: 2022 Eduardo Robles <edu@sequentech.io>
+//
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Throttling of authentication attempts, to prevent brute-forcing PINs by
+//! hanging up and redialing.
+//!
+//! Failed attempts are counted per key (the caller number and the user id)
+//! within a time window. When a key reaches the maximum number of failed
+//! attempts, it's locked out for a configurable duration.
+//!
+//! A caller who redials can be served by another lambda instance, so the
+//! attempts must be stored where all the instances share them. The store has
+//! to be configured explicitly: the memory store is only meant for testing.
+
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::path::PathBuf;
+use std::sync::{Mutex, OnceLock};
+use fs2::FileExt;
+use serde::{Deserialize, Serialize};
+use tracing::{event, Level};
+use ivr_common::IvrError;
+use ivr_common::config::parse_env_var;
+
+/// Prefix of the keys of the user ids.
+const USER_ID_KEY_PREFIX: &str = "user-id:";
+
+/// Key of the failed attempts of a caller number.
+pub fn caller_id_key(caller_id: &str) -> String {
+    format!("caller-id:{}", caller_id)
+}
+
+/// Key of the failed attempts of a user id in an election.
+pub fn user_id_key(election_id: &str, user_id: &str) -> String {
+    format!("{}{}:{}", USER_ID_KEY_PREFIX, election_id, user_id)
+}
+
+/// Failed authentication attempts of a key.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AttemptRecord {
+    /// Number of failed attempts in the current window.
+    pub failures: u32,
+    /// Unix timestamp of the first failed attempt of the current window.
+    pub window_start: u64,
+    /// Unix timestamp until which the key is locked out, if any.
+    pub locked_until: Option<u64>,
+}
+
+/// Updates a record, given the current one. Returning `None` removes it.
+pub type RecordUpdate<'a> =
+    dyn FnMut(Option<AttemptRecord>) -> Option<AttemptRecord> + 'a;
+
+/// Storage of the attempt records.
+pub trait AttemptStore {
+    fn load(&self, key: &str) -> Result<Option<AttemptRecord>, IvrError>;
+
+    /// Atomically replaces the record of a key with the one returned by
+    /// `update`, so that concurrent updates are not lost.
+    fn update(&self, key: &str, update: &mut RecordUpdate)
+        -> Result<(), IvrError>;
+}
+
+impl<T: AttemptStore + ?Sized> AttemptStore for &T {
+    fn load(&self, key: &str) -> Result<Option<AttemptRecord>, IvrError> {
+        (**self).load(key)
+    }
+
+    fn update(&self, key: &str, update: &mut RecordUpdate)
+        -> Result<(), IvrError>
+    {
+        (**self).update(key, update)
+    }
+}
+
+/// Applies `update` to the record of `key` in `records`.
+fn update_records(
+    records: &mut HashMap<String, AttemptRecord>,
+    key: &str,
+    update: &mut RecordUpdate
+)
+{
+    match update(records.remove(key)) {
+        Some(record) => records.insert(key.into(), record),
+        None => None,
+    };
+}
+
+/// Stores the records in memory, only for testing. The shared instance is kept
+/// across warm invocations of the same lambda instance, but it's not shared
+/// with the other instances, so a caller can bypass the lockout by redialing.
+#[derive(Default)]
+pub struct MemoryStore {
+    records: Mutex<HashMap<String, AttemptRecord>>,
+}
+
+impl MemoryStore {
+    /// Returns the instance shared by all the invocations.
+    pub fn shared() -> &'static MemoryStore {
+        static SHARED: OnceLock<MemoryStore> = OnceLock::new();
+        SHARED.get_or_init(MemoryStore::default)
+    }
+}
+
+impl AttemptStore for MemoryStore {
+    fn load(&self, key: &str) -> Result<Option<AttemptRecord>, IvrError> {
+        let records = self.records
+            .lock()
+            .map_err(|error| IvrError::Internal(error.to_string()))?;
+        Ok(records.get(key).cloned())
+    }
+
+    fn update(&self, key: &str, update: &mut RecordUpdate)
+        -> Result<(), IvrError>
+    {
+        let mut records = self.records
+            .lock()
+            .map_err(|error| IvrError::Internal(error.to_string()))?;
+        update_records(&mut records, key, update);
+        Ok(())
+    }
+}
+
+/// Stores the records in a JSON file. To be shared by all the lambda
+/// instances, the file must be in a file system mounted by all of them, for
+/// example Amazon EFS.
+///
+/// Updates hold an exclusive lock on the `<path>.lock` file while they read
+/// the records and write them to `<path>.tmp`, which is then renamed to the
+/// file, so that loads without the lock never read a partially written file.
+pub struct FileStore {
+    path: PathBuf,
+}
+
+impl FileStore {
+    pub fn new(path: PathBuf) -> FileStore {
+        FileStore { path }
+    }
+
+    /// Returns the path of the file with the given extension appended.
+    fn sibling(&self, extension: &str) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(extension);
+        PathBuf::from(path)
+    }
+
+    fn read_records(&self) -> Result<HashMap<String, AttemptRecord>, IvrError> {
+        match fs::read_to_string(&self.path) {
+            Ok(contents) => serde_json::from_str(&contents)
+                .map_err(|error| IvrError::Internal(error.to_string())),
+            Err(_) => Ok(HashMap::new()),
+        }
+    }
+}
+
+impl AttemptStore for FileStore {
+    fn load(&self, key: &str) -> Result<Option<AttemptRecord>, IvrError> {
+        Ok(self.read_records()?.remove(key))
+    }
+
+    fn update(&self, key: &str, update: &mut RecordUpdate)
+        -> Result<(), IvrError>
+    {
+        let internal = |error: std::io::Error| {
+            IvrError::Internal(error.to_string())
+        };
+        let lock: File = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(self.sibling(".lock"))
+            .map_err(internal)?;
+        // released when the file is closed
+        lock.lock_exclusive().map_err(internal)?;
+
+        let mut records = self.read_records()?;
+        update_records(&mut records, key, update);
+        let contents = serde_json::to_string(&records)
+            .map_err(|error| IvrError::Internal(error.to_string()))?;
+        let tmp_path = self.sibling(".tmp");
+        fs::write(&tmp_path, contents).map_err(internal)?;
+        fs::rename(&tmp_path, &self.path).map_err(internal)
+    }
+}
+
+/// Throttling configuration.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ThrottleConfig {
+    /// Failed attempts allowed per key within the window.
+    pub max_attempts: u32,
+    /// Duration of the window in seconds.
+    pub window_seconds: u64,
+    /// Duration of the lockout in seconds.
+    pub lockout_seconds: u64,
+}
+
+/// Throttles the authentication attempts of a set of keys.
+pub struct Throttle {
+    config: ThrottleConfig,
+    store: Box<dyn AttemptStore>,
+}
+
+impl Throttle {
+    pub fn new(config: ThrottleConfig, store: Box<dyn AttemptStore>) -> Throttle {
+        Throttle { config, store }
+    }
+
+    /// Reads the throttling configuration from the env vars, returning `None`
+    /// if throttling is disabled (`THROTTLE_MAX_ATTEMPTS` is not set):
+    /// - `THROTTLE_MAX_ATTEMPTS`: failed attempts allowed within the window.
+    /// - `THROTTLE_WINDOW_SECONDS`: duration of the window, 1 hour by default.
+    /// - `THROTTLE_LOCKOUT_SECONDS`: duration of the lockout, 1 hour by
+    ///   default.
+    /// - `THROTTLE_STORE`: `file:<path>`, or `memory` for testing. Required
+    ///   when throttling is enabled.
+    pub fn from_env() -> Result<Option<Throttle>, IvrError> {
+        let max_attempts: u32 = match parse_env_var("THROTTLE_MAX_ATTEMPTS")? {
+            Some(max_attempts) => max_attempts,
+            None => return Ok(None),
+        };
+        let config = ThrottleConfig {
+            max_attempts,
+            window_seconds: parse_env_var("THROTTLE_WINDOW_SECONDS")?
+                .unwrap_or(3600),
+            lockout_seconds: parse_env_var("THROTTLE_LOCKOUT_SECONDS")?
+                .unwrap_or(3600),
+        };
+        event!(Level::INFO, "throttle_config={:?}", config);
+
+        // there's no default store, as the memory store would let callers
+        // bypass the lockout by redialing
+        let store_str: String = parse_env_var("THROTTLE_STORE")?
+            .ok_or_else(|| IvrError::Configuration("THROTTLE_STORE".into()))?;
+        let store: Box<dyn AttemptStore> = match store_str.as_str() {
+            "memory" => {
+                event!(
+                    Level::WARN,
+                    "the memory throttle store is not shared by the lambda \
+                    instances, use it only for testing"
+                );
+                Box::new(MemoryStore::shared())
+            },
+            store_str => match store_str.strip_prefix("file:") {
+                Some(path) => Box::new(FileStore::new(PathBuf::from(path))),
+                None => return Err(
+                    IvrError::Configuration("THROTTLE_STORE".into())
+                ),
+            },
+        };
+        Ok(Some(Throttle::new(config, store)))
+    }
+
+    /// Whether the window or the lockout of a record hasn't finished yet.
+    fn is_current(&self, record: &AttemptRecord, now: u64) -> bool {
+        match record.locked_until {
+            Some(locked_until) => locked_until > now,
+            None => record.window_start + self.config.window_seconds > now,
+        }
+    }
+
+    /// Returns the record of a key, discarding it if its window or lockout
+    /// has already finished.
+    fn current_record(&self, key: &str, now: u64)
+        -> Result<Option<AttemptRecord>, IvrError>
+    {
+        Ok(self.store
+            .load(key)?
+            .filter(|record| self.is_current(record, now)))
+    }
+
+    /// Checks that none of the keys is locked out, returning the remaining
+    /// attempts.
+    pub fn check(&self, keys: &[String], now: u64) -> Result<u32, IvrError> {
+        let mut remaining_attempts = self.config.max_attempts;
+        for key in keys.iter() {
+            if let Some(record) = self.current_record(key, now)? {
+                if record.locked_until.is_some() {
+                    event!(Level::WARN, key, "locked out");
+                    return Err(IvrError::LockedOut);
+                }
+                remaining_attempts = remaining_attempts.min(
+                    self.config.max_attempts.saturating_sub(record.failures)
+                );
+            }
+        }
+        Ok(remaining_attempts)
+    }
+
+    /// Records a failed attempt for all the keys, returning the remaining
+    /// attempts. Keys that reach the maximum number of failed attempts are
+    /// locked out.
+    pub fn record_failure(&self, keys: &[String], now: u64)
+        -> Result<u32, IvrError>
+    {
+        let mut remaining_attempts = self.config.max_attempts;
+        for key in keys.iter() {
+            self.store.update(key, &mut |record| {
+                let mut record = record
+                    .filter(|record| self.is_current(record, now))
+                    .unwrap_or(AttemptRecord {
+                        failures: 0,
+                        window_start: now,
+                        locked_until: None,
+                    });
+                record.failures += 1;
+                if record.failures >= self.config.max_attempts {
+                    event!(Level::WARN, key, "locking out");
+                    record.locked_until =
+                        Some(now + self.config.lockout_seconds);
+                }
+                remaining_attempts = remaining_attempts.min(
+                    self.config.max_attempts.saturating_sub(record.failures)
+                );
+                Some(record)
+            })?;
+        }
+        Ok(remaining_attempts)
+    }
+
+    /// Clears the failed attempts of the user id keys after a successful
+    /// authentication. The failed attempts of the caller number are kept, as
+    /// otherwise a caller could reset them by authenticating with their own
+    /// credentials between attempts against other user ids.
+    pub fn record_success(&self, keys: &[String]) -> Result<(), IvrError> {
+        let user_id_keys = keys
+            .iter()
+            .filter(|key| key.starts_with(USER_ID_KEY_PREFIX));
+        for key in user_id_keys {
+            self.store.update(key, &mut |_| None)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{SystemTime, UNIX_EPOCH};
+    use ivr_common::IvrError;
+    use crate::throttle::{
+        caller_id_key, user_id_key, AttemptRecord, AttemptStore, FileStore,
+        MemoryStore, Throttle, ThrottleConfig
+    };
+
+    fn throttle() -> Throttle {
+        Throttle::new(
+            ThrottleConfig {
+                max_attempts: 3,
+                window_seconds: 600,
+                lockout_seconds: 3600,
+            },
+            Box::new(MemoryStore::default())
+        )
+    }
+
+    #[test]
+    fn lockout_after_max_attempts() {
+        let throttle = throttle();
+        let keys = vec![caller_id_key("+14165550123"), user_id_key("17", "100")];
+        assert_eq!(throttle.check(&keys, 1000), Ok(3));
+        assert_eq!(throttle.record_failure(&keys, 1000), Ok(2));
+        assert_eq!(throttle.record_failure(&keys, 1010), Ok(1));
+        assert_eq!(throttle.check(&keys, 1020), Ok(1));
+        assert_eq!(throttle.record_failure(&keys, 1020), Ok(0));
+        assert_eq!(throttle.check(&keys, 1030), Err(IvrError::LockedOut));
+
+        // the same user from another phone is locked out too
+        let other_keys =
+            vec![caller_id_key("+14165550124"), user_id_key("17", "100")];
+        assert_eq!(throttle.check(&other_keys, 1030), Err(IvrError::LockedOut));
+
+        // after the lockout, attempts are allowed again
+        assert_eq!(throttle.check(&keys, 1020 + 3600), Ok(3));
+    }
+
+    #[test]
+    fn window_and_success_reset_failures() {
+        let throttle = throttle();
+        let keys = vec![String::from("user-id:17:200")];
+        assert_eq!(throttle.record_failure(&keys, 1000), Ok(2));
+        assert_eq!(throttle.record_failure(&keys, 1100), Ok(1));
+        // the window has finished
+        assert_eq!(throttle.check(&keys, 1600), Ok(3));
+        assert_eq!(throttle.record_failure(&keys, 1600), Ok(2));
+
+        assert_eq!(throttle.record_success(&keys), Ok(()));
+        assert_eq!(throttle.check(&keys, 1610), Ok(3));
+    }
+
+    #[test]
+    fn success_keeps_caller_failures() {
+        let throttle = throttle();
+        let caller_keys = vec![caller_id_key("+14165550123")];
+        let attacked_keys =
+            vec![caller_keys[0].clone(), user_id_key("17", "300")];
+        assert_eq!(throttle.record_failure(&attacked_keys, 1000), Ok(2));
+        assert_eq!(throttle.record_failure(&attacked_keys, 1010), Ok(1));
+
+        // the caller authenticates with their own credentials
+        let own_keys = vec![caller_keys[0].clone(), user_id_key("17", "400")];
+        assert_eq!(throttle.record_success(&own_keys), Ok(()));
+        assert_eq!(throttle.check(&caller_keys, 1020), Ok(1));
+        assert_eq!(throttle.record_failure(&attacked_keys, 1020), Ok(0));
+        assert_eq!(throttle.check(&own_keys, 1030), Err(IvrError::LockedOut));
+    }
+
+    const RECORD: AttemptRecord = AttemptRecord {
+        failures: 0,
+        window_start: 1000,
+        locked_until: None,
+    };
+
+    #[test]
+    fn file_store_updates() {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let path = std::env::temp_dir()
+            .join(format!("throttle-{}-{}.json", std::process::id(), nanos));
+        let store = Arc::new(FileStore::new(path.clone()));
+        assert_eq!(store.load("caller-id:1"), Ok(None));
+
+        // concurrent updates of the same file are not lost
+        let threads: Vec<_> = (0..8)
+            .map(|index| {
+                let store = store.clone();
+                thread::spawn(move || {
+                    let keys =
+                        ["caller-id:1".to_string(), format!("k{}", index)];
+                    for _ in 0..5 {
+                        for key in keys.iter() {
+                            store.update(key, &mut |record| {
+                                let mut record = record.unwrap_or(RECORD);
+                                record.failures += 1;
+                                Some(record)
+                            }).unwrap();
+                        }
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        let failures = |key: &str| {
+            store.load(key).unwrap().map(|record| record.failures)
+        };
+        assert_eq!(failures("caller-id:1"), Some(40));
+        assert_eq!(failures("k7"), Some(5));
+
+        assert_eq!(store.update("caller-id:1", &mut |_| None), Ok(()));
+        assert_eq!(failures("caller-id:1"), None);
+        assert_eq!(failures("k0"), Some(5));
+
+        let mut lock_path = path.clone().into_os_string();
+        lock_path.push(".lock");
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(lock_path).unwrap();
+    }
+}
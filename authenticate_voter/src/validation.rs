@@ -0,0 +1,333 @@
+// SPDX-FileCopyrightText: 2022 Eduardo Robles <edu@sequentech.io>
+//
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Pre-flight validation of the credentials typed by the voter, so that
+//! malformed input is rejected without calling the backend.
+
+use std::collections::{BTreeMap, HashMap};
+use regex::Regex;
+use serde::Deserialize;
+use ivr_common::IvrError;
+use ivr_common::error::FormatViolation;
+
+use crate::auth_fields::AuthField;
+
+/// Characters allowed in a credential.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum Charset {
+    /// Any character.
+    #[default]
+    Any,
+    /// Only digits.
+    Digits,
+    /// Only ASCII letters and digits.
+    Alphanumeric,
+}
+
+/// Check digit algorithm. The check digit is always the last digit.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum CheckDigit {
+    /// The Luhn algorithm, used for example in credit card numbers.
+    Luhn,
+    /// Modulo 11 with weights 2, 3, 4... starting from the rightmost digit
+    /// before the check digit. Numbers whose check digit would be 10 are not
+    /// valid.
+    Mod11,
+}
+
+impl CheckDigit {
+    /// Whether the last digit of `digits` is a valid check digit. `digits`
+    /// must only contain ASCII digits.
+    pub fn is_valid(&self, digits: &str) -> bool {
+        let values: Vec<u32> = digits
+            .chars()
+            .filter_map(|character| character.to_digit(10))
+            .collect();
+        if values.len() < 2 || values.len() != digits.len() {
+            return false;
+        }
+        match self {
+            CheckDigit::Luhn => {
+                let sum: u32 = values
+                    .iter()
+                    .rev()
+                    .enumerate()
+                    .map(|(position, value)| {
+                        if position % 2 == 1 {
+                            let doubled = value * 2;
+                            if doubled > 9 { doubled - 9 } else { doubled }
+                        } else {
+                            *value
+                        }
+                    })
+                    .sum();
+                // not `is_multiple_of`, which the toolchain of the flake lacks
+                let remainder = sum % 10;
+                remainder == 0
+            },
+            CheckDigit::Mod11 => {
+                let (payload, check_digit) = values.split_at(values.len() - 1);
+                let sum: u32 = payload
+                    .iter()
+                    .rev()
+                    .enumerate()
+                    .map(|(position, value)| value * (position as u32 + 2))
+                    .sum();
+                (11 - sum % 11) % 11 == check_digit[0]
+            },
+        }
+    }
+}
+
+/// Validation rule of a credential.
+#[derive(Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct ValidationRule {
+    #[serde(default)]
+    pub min_length: Option<usize>,
+    #[serde(default)]
+    pub max_length: Option<usize>,
+    #[serde(default)]
+    pub charset: Charset,
+    /// Regular expression the whole credential must match.
+    #[serde(default)]
+    pub regex: Option<String>,
+    #[serde(default)]
+    pub check_digit: Option<CheckDigit>,
+}
+
+impl ValidationRule {
+    /// Validates the value of the credential in the `attribute` contact
+    /// attribute.
+    pub fn validate(&self, attribute: &str, value: &str) -> Result<(), IvrError> {
+        let violation = |violation: FormatViolation| {
+            IvrError::InvalidCredentialFormat {
+                attribute: attribute.into(),
+                violation,
+            }
+        };
+        let value = value.trim();
+        let length = value.chars().count();
+        let is_too_short = self
+            .min_length
+            .map(|min_length| length < min_length)
+            .unwrap_or(false);
+        let is_too_long = self
+            .max_length
+            .map(|max_length| length > max_length)
+            .unwrap_or(false);
+        if is_too_short || is_too_long {
+            return Err(violation(FormatViolation::Length {
+                min: self.min_length,
+                max: self.max_length,
+            }));
+        }
+
+        let has_valid_characters = match self.charset {
+            Charset::Any => true,
+            Charset::Digits => value
+                .chars()
+                .all(|character| character.is_ascii_digit()),
+            Charset::Alphanumeric => value
+                .chars()
+                .all(|character| character.is_ascii_alphanumeric()),
+        };
+        if !has_valid_characters {
+            return Err(violation(FormatViolation::Characters));
+        }
+
+        if let Some(regex) = &self.regex {
+            // anchor the regex so that it matches the whole value
+            let regex = Regex::new(&format!("^(?:{})$", regex)).map_err(|_| {
+                IvrError::Configuration("CREDENTIAL_VALIDATION".into())
+            })?;
+            if !regex.is_match(value) {
+                return Err(violation(FormatViolation::Format));
+            }
+        }
+
+        if let Some(check_digit) = &self.check_digit {
+            if !check_digit.is_valid(value) {
+                return Err(violation(FormatViolation::CheckDigit));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Validation rules per election, read from the `CREDENTIAL_VALIDATION` env
+/// var. It's a JSON map from election id to a map from contact attribute to
+/// [`ValidationRule`]. Rules for the `default` election id apply to all
+/// elections unless overridden by the rules for the specific election. For
+/// example:
+///
+/// ```json
+/// {
+///     "default": {
+///         "VoterPIN": {"min_length": 4, "max_length": 8, "charset": "digits"}
+///     },
+///     "17": {
+///         "VoterUserId": {"min_length": 8, "max_length": 8, "charset": "digits", "check_digit": "luhn"}
+///     }
+/// }
+/// ```
+pub type ValidationRules = HashMap<String, HashMap<String, ValidationRule>>;
+
+/// Validates the contact attributes with the rules that apply to the election,
+/// in alphabetical order of attribute. Missing attributes are not validated.
+/// The attributes are validated as they are sent to the backend, with the
+/// normalization of their field in `auth_fields`, if any.
+pub fn validate_credentials(
+    rules: &ValidationRules,
+    election_id: &str,
+    auth_fields: &[AuthField],
+    attributes: &HashMap<String, String>
+) -> Result<(), IvrError>
+{
+    let mut election_rules: BTreeMap<&String, &ValidationRule> = BTreeMap::new();
+    for rules_key in ["default", election_id] {
+        if let Some(rules) = rules.get(rules_key) {
+            election_rules.extend(rules.iter());
+        }
+    }
+    for (attribute, rule) in election_rules.into_iter() {
+        let value = match attributes.get(attribute) {
+            Some(value) => value,
+            None => continue,
+        };
+        let auth_field = auth_fields
+            .iter()
+            .find(|auth_field| &auth_field.attribute == attribute);
+        let value = match auth_field {
+            Some(auth_field) => auth_field
+                .normalize
+                .apply(value)
+                .ok_or_else(|| IvrError::InvalidAttribute(attribute.clone()))?,
+            None => value.clone(),
+        };
+        rule.validate(attribute, &value)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use ivr_common::IvrError;
+    use ivr_common::error::FormatViolation;
+    use crate::auth_fields::AuthField;
+    use crate::validation::{
+        validate_credentials, CheckDigit, ValidationRule, ValidationRules
+    };
+
+    #[test]
+    fn check_digits() {
+        assert!(CheckDigit::Luhn.is_valid("79927398713"));
+        assert!(!CheckDigit::Luhn.is_valid("79927398710"));
+        assert!(CheckDigit::Mod11.is_valid("0306406152"));
+        assert!(!CheckDigit::Mod11.is_valid("0306406153"));
+        assert!(!CheckDigit::Luhn.is_valid("7"));
+        assert!(!CheckDigit::Luhn.is_valid("7992a398713"));
+    }
+
+    #[test]
+    fn validation_rule() {
+        let rule: ValidationRule = serde_json::from_str(
+            r#"{"min_length": 8, "max_length": 8, "charset": "digits"}"#
+        ).unwrap();
+        assert_eq!(rule.validate("VoterUserId", "12345678"), Ok(()));
+        assert_eq!(
+            rule.validate("VoterUserId", "1234567"),
+            Err(IvrError::InvalidCredentialFormat {
+                attribute: "VoterUserId".into(),
+                violation: FormatViolation::Length {
+                    min: Some(8),
+                    max: Some(8),
+                },
+            })
+        );
+        assert_eq!(
+            rule.validate("VoterUserId", "1234567A"),
+            Err(IvrError::InvalidCredentialFormat {
+                attribute: "VoterUserId".into(),
+                violation: FormatViolation::Characters,
+            })
+        );
+
+        let rule: ValidationRule = serde_json::from_str(
+            r#"{"regex": "[0-9]{2}-[0-9]+"}"#
+        ).unwrap();
+        assert_eq!(rule.validate("VoterUserId", "12-345"), Ok(()));
+        assert_eq!(
+            rule.validate("VoterUserId", "x12-345"),
+            Err(IvrError::InvalidCredentialFormat {
+                attribute: "VoterUserId".into(),
+                violation: FormatViolation::Format,
+            })
+        );
+    }
+
+    #[test]
+    fn election_rules() {
+        let rules: ValidationRules = serde_json::from_str(r#"{
+            "default": {
+                "VoterUserId": {"charset": "digits"},
+                "VoterPIN": {"min_length": 4, "max_length": 8}
+            },
+            "17": {
+                "VoterUserId": {"check_digit": "luhn"}
+            }
+        }"#).unwrap();
+        let attributes: HashMap<String, String> = HashMap::from([
+            ("VoterUserId".into(), "79927398710".into()),
+            ("VoterPIN".into(), "1234".into()),
+        ]);
+        assert_eq!(
+            validate_credentials(&rules, "18", &[], &attributes),
+            Ok(())
+        );
+        assert_eq!(
+            validate_credentials(&rules, "17", &[], &attributes),
+            Err(IvrError::InvalidCredentialFormat {
+                attribute: "VoterUserId".into(),
+                violation: FormatViolation::CheckDigit,
+            })
+        );
+    }
+
+    #[test]
+    fn normalized_credentials() {
+        let rules: ValidationRules = serde_json::from_str(r#"{
+            "default": {
+                "VoterUserId": {"min_length": 4, "charset": "digits"},
+                "VoterBirthDate": {"regex": "[0-9]{4}-[0-9]{2}-[0-9]{2}"}
+            }
+        }"#).unwrap();
+        let auth_fields: Vec<AuthField> = serde_json::from_str(r#"[
+            {"attribute": "VoterUserId", "field": "user-id", "normalize": "digits"},
+            {"attribute": "VoterBirthDate", "field": "birth-date", "normalize": "date-ddmmyyyy"}
+        ]"#).unwrap();
+        let validate = |user_id: &str, birth_date: &str| {
+            let attributes: HashMap<String, String> = HashMap::from([
+                ("VoterUserId".into(), user_id.into()),
+                ("VoterBirthDate".into(), birth_date.into()),
+            ]);
+            validate_credentials(&rules, "17", &auth_fields, &attributes)
+        };
+        // the values are validated as they are sent to the backend
+        assert_eq!(validate("100-2", "31121990"), Ok(()));
+        assert_eq!(
+            validate("1-2", "31121990"),
+            Err(IvrError::InvalidCredentialFormat {
+                attribute: "VoterUserId".into(),
+                violation: FormatViolation::Length { min: Some(4), max: None },
+            })
+        );
+        assert_eq!(
+            validate("1002", "32121990"),
+            Err(IvrError::InvalidAttribute("VoterBirthDate".into()))
+        );
+    }
+}
@@ -0,0 +1,79 @@
+// SPDX-FileCopyrightText: 2022 Eduardo Robles <edu@sequentech.io>
+//
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Latency of a backend call with a client created for every invocation, as
+//! the lambdas used to do, and with a client reused across warm invocations.
+//!
+//! Run it with `cargo bench -p ivr_common`. The mock backend is plain HTTP, so
+//! the gain only includes the TCP handshake: against the real backend the TLS
+//! handshake is saved too.
+
+use std::time::{Duration, SystemTime};
+use criterion::{criterion_group, criterion_main, Criterion};
+use httpmock::prelude::*;
+use tokio::runtime::Runtime;
+
+use ivr_common::headers::HeaderConfig;
+use ivr_common::http::{BackendRequest, HttpClient, HttpConfig};
+use ivr_common::retry::RetryPolicy;
+use ivr_common::timeout::TimeoutConfig;
+use ivr_common::tls::TlsConfig;
+
+fn config() -> HttpConfig {
+    HttpConfig {
+        retry_policy: RetryPolicy {
+            max_attempts: 1,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(1000),
+        },
+        timeouts: TimeoutConfig {
+            invocation_timeout: Duration::from_secs(8),
+            margin: Duration::from_millis(500),
+            request_timeout: Duration::from_secs(3),
+        },
+        headers: HeaderConfig::default(),
+        tls: TlsConfig::default(),
+        pool_idle_timeout: Duration::from_secs(50),
+    }
+}
+
+async fn authenticate(client: &HttpClient, login_url: &str) {
+    let request = BackendRequest::post(login_url, "LOGIN_URL")
+        .unwrap()
+        .with_body(r#"{"user-id": "100", "code": "22345678"}"#.into())
+        .idempotent();
+    let deadline = SystemTime::now() + Duration::from_secs(8);
+    let (status, _) = client.send(deadline, &request).await.unwrap();
+    assert_eq!(status, 200);
+}
+
+fn backend_call(criterion: &mut Criterion) {
+    let server = MockServer::start();
+    server.mock(|when, then| {
+        when.method(POST).path("/authenticate");
+        then.status(200)
+            .header("content-type", "application/json")
+            .body(r#"{"vote-permission-token": "khmac:///sha-256;0/0"}"#);
+    });
+    let login_url = server.url("/authenticate");
+    let runtime = Runtime::new().unwrap();
+
+    let mut group = criterion.benchmark_group("backend_call");
+    group.bench_function("client_per_invocation", |bencher| {
+        bencher.to_async(&runtime).iter(|| async {
+            let client = HttpClient::new(config()).unwrap();
+            authenticate(&client, &login_url).await;
+        })
+    });
+    let client = HttpClient::new(config()).unwrap();
+    group.bench_function("reused_client", |bencher| {
+        bencher
+            .to_async(&runtime)
+            .iter(|| authenticate(&client, &login_url))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, backend_call);
+criterion_main!(benches);
@@ -0,0 +1,45 @@
+// SPDX-FileCopyrightText: 2022 Eduardo Robles <edu@sequentech.io>
+//
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Helpers to read the lambda configuration from env vars.
+
+use std::env;
+use std::str::FromStr;
+use serde::de::DeserializeOwned;
+use tracing::{event, Level};
+
+use crate::IvrError;
+
+/// Reads a required env var.
+pub fn env_var(name: &str) -> Result<String, IvrError> {
+    env::var(name).map_err(|_| IvrError::Configuration(name.into()))
+}
+
+/// Reads an optional env var that contains JSON, returning `None` if it's not
+/// set.
+pub fn json_env_var<T: DeserializeOwned>(name: &str)
+    -> Result<Option<T>, IvrError>
+{
+    match env::var(name) {
+        Ok(value) => serde_json::from_str(&value)
+            .map(Some)
+            .map_err(|error| {
+                event!(Level::ERROR, env_var = name, "{}", error);
+                IvrError::Configuration(name.into())
+            }),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Reads and parses an optional env var, returning `None` if it's not set.
+pub fn parse_env_var<T: FromStr>(name: &str) -> Result<Option<T>, IvrError> {
+    match env::var(name) {
+        Ok(value) => value
+            .trim()
+            .parse()
+            .map(Some)
+            .map_err(|_| IvrError::Configuration(name.into())),
+        Err(_) => Ok(None),
+    }
+}
@@ -0,0 +1,58 @@
+// SPDX-FileCopyrightText: 2022 Eduardo Robles <edu@sequentech.io>
+//
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Helpers to deal with Amazon Connect contact data and lambda responses.
+
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use serde_json::Value;
+use tracing::{event, Level};
+
+use crate::IvrError;
+
+/// Returns a required contact attribute.
+pub fn get_attribute<'a>(
+    attributes: &'a HashMap<String, String>,
+    name: &str
+) -> Result<&'a String, IvrError>
+{
+    attributes
+        .get(name)
+        .ok_or_else(|| IvrError::MissingAttribute(name.into()))
+}
+
+/// Converts the deadline of the lambda context, in milliseconds since the unix
+/// epoch, into a `SystemTime`. Returns `None` if the deadline is unknown, i.e.
+/// zero.
+pub fn context_deadline(deadline_ms: u64) -> Option<SystemTime> {
+    if deadline_ms == 0 {
+        None
+    } else {
+        Some(UNIX_EPOCH + Duration::from_millis(deadline_ms))
+    }
+}
+
+/// Converts the result of a lambda into the attributes returned to the
+/// contact flow. On success the `Status` attribute is set to `success`, and on
+/// error the attributes are those of [`IvrError::to_response`].
+pub fn to_response(result: Result<Value, IvrError>) -> Value {
+    match result {
+        Ok(mut ret_value) => {
+            if let Some(ret_map) = ret_value.as_object_mut() {
+                ret_map.insert("Status".into(), "success".into());
+            }
+            ret_value
+        },
+        Err(error) => {
+            event!(
+                Level::ERROR,
+                error_code = error.code(),
+                retryable = error.is_retryable(),
+                "{}",
+                error
+            );
+            error.to_response()
+        }
+    }
+}
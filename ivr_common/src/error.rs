@@ -0,0 +1,328 @@
+// SPDX-FileCopyrightText: 2022 Eduardo Robles <edu@sequentech.io>
+//
+// SPDX-License-Identifier: AGPL-3.0-only
+
+use std::fmt;
+use serde_json::{json, Value};
+
+/// Why a credential typed by the voter doesn't have a valid format.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FormatViolation {
+    /// The credential is too short or too long. Contains the allowed length
+    /// range.
+    Length { min: Option<usize>, max: Option<usize> },
+    /// The credential contains characters that are not allowed.
+    Characters,
+    /// The credential doesn't match the configured regular expression.
+    Format,
+    /// The check digit of the credential is not valid.
+    CheckDigit,
+}
+
+/// Errors returned by the lambdas to the Amazon Connect contact flow.
+///
+/// Instead of failing the lambda invocation, which Amazon Connect only sees
+/// as a generic error, these errors are returned as a successful response
+/// with the `Status`, `ErrorCode` and `Retryable` attributes (see
+/// [`IvrError::to_response`]) so that the contact flow can branch on them.
+#[derive(Debug, Clone, PartialEq)]
+pub enum IvrError {
+    /// A lambda env var is missing or has an invalid value. Contains the name
+    /// of the env var.
+    Configuration(String),
+    /// A required contact attribute is missing. Contains the name of the
+    /// attribute.
+    MissingAttribute(String),
+    /// A contact attribute has an invalid value. Contains the name of the
+    /// attribute.
+    InvalidAttribute(String),
+    /// The caller number is not available or is not a valid phone number, and
+    /// it's required for authentication.
+    MissingCallerId,
+    /// The backend rejected the credentials provided by the voter.
+    InvalidCredentials,
+    /// A credential typed by the voter doesn't have a valid format, so it was
+    /// rejected without calling the backend.
+    InvalidCredentialFormat {
+        /// Name of the contact attribute with the credential.
+        attribute: String,
+        violation: FormatViolation,
+    },
+    /// Too many failed authentication attempts, so the caller or the user id
+    /// is temporarily locked out.
+    LockedOut,
+    /// The voter was authenticated but is not allowed to vote, for example
+    /// because they already voted.
+    EmptyVotePermissionToken,
+    /// The voter chose a child election that doesn't exist.
+    InvalidChildElectionSelection,
+    /// The digest of the vote permission token doesn't match, so it was
+    /// tampered with or corrupted.
+    InvalidAuthTokenSignature,
+    /// The vote permission token has expired, so the voter needs to
+    /// authenticate again.
+    ExpiredAuthToken,
+    /// The vote permission token doesn't grant permission to vote in the
+    /// election.
+    AuthTokenElectionMismatch,
+    /// The election is not accepting votes, because it hasn't started yet or
+    /// it has already finished.
+    ElectionNotOpen,
+    /// The voter choice couldn't be converted into a valid ballot.
+    InvalidVote(String),
+    /// The voter chose fewer or more answers than allowed in a question.
+    InvalidSelectionCount {
+        /// Number of the question, starting at 1.
+        question: usize,
+        /// Number of answers chosen by the voter.
+        selected: usize,
+        min: u32,
+        max: u32,
+    },
+    /// The backend couldn't be reached.
+    BackendUnavailable(String),
+    /// There's no time left to finish before the lambda or Amazon Connect
+    /// gives up, for example because the backend is slow.
+    Timeout,
+    /// The backend answered with an unexpected HTTP status code.
+    InvalidStatus(u16),
+    /// Casting the vote failed after the ballot box might have recorded it,
+    /// for example with a `500` status or a timeout, so it must not be cast
+    /// again.
+    UnconfirmedVote(String),
+    /// The backend answered with a body that couldn't be understood.
+    InvalidBackendResponse(String),
+    /// The election public key uses group parameters that the lambda doesn't
+    /// support, so the votes can't be encrypted.
+    UnsupportedGroup(String),
+    /// The election public key is not valid or doesn't match the pinned
+    /// fingerprint, so it might have been substituted.
+    InvalidPublicKey(String),
+    /// The encrypted ballot failed its own verification, so it was not cast.
+    InvalidBallot(String),
+    /// Any other unexpected error.
+    Internal(String),
+}
+
+impl IvrError {
+    /// Error code returned to the contact flow in the `ErrorCode` attribute.
+    pub fn code(&self) -> &'static str {
+        match self {
+            IvrError::Configuration(_) => "configuration-error",
+            IvrError::MissingAttribute(_) => "missing-attribute",
+            IvrError::InvalidAttribute(_) => "invalid-attribute",
+            IvrError::MissingCallerId => "missing-caller-id",
+            IvrError::InvalidCredentials => "invalid-credentials",
+            IvrError::InvalidCredentialFormat { violation, .. } =>
+                match violation {
+                    FormatViolation::Length { .. } =>
+                        "invalid-credential-length",
+                    FormatViolation::Characters =>
+                        "invalid-credential-characters",
+                    FormatViolation::Format => "invalid-credential-format",
+                    FormatViolation::CheckDigit =>
+                        "invalid-credential-check-digit",
+                },
+            IvrError::LockedOut => "locked-out",
+            IvrError::EmptyVotePermissionToken =>
+                "empty-vote-permission-token",
+            IvrError::InvalidChildElectionSelection =>
+                "invalid-child-election-selection",
+            IvrError::InvalidAuthTokenSignature => "invalid-auth-token-signature",
+            IvrError::ExpiredAuthToken => "expired-auth-token",
+            IvrError::AuthTokenElectionMismatch =>
+                "auth-token-election-mismatch",
+            IvrError::ElectionNotOpen => "election-not-open",
+            IvrError::InvalidVote(_) => "invalid-vote",
+            IvrError::InvalidSelectionCount { selected, min, .. } =>
+                if *selected < *min as usize {
+                    "too-few-answers"
+                } else {
+                    "too-many-answers"
+                },
+            IvrError::BackendUnavailable(_) => "backend-unavailable",
+            IvrError::Timeout => "timeout",
+            IvrError::InvalidStatus(_) => "invalid-status",
+            IvrError::UnconfirmedVote(_) => "unconfirmed-vote",
+            IvrError::InvalidBackendResponse(_) => "invalid-backend-response",
+            IvrError::UnsupportedGroup(_) => "unsupported-group",
+            IvrError::InvalidPublicKey(_) => "invalid-public-key",
+            IvrError::InvalidBallot(_) => "invalid-ballot",
+            IvrError::Internal(_) => "internal-error",
+        }
+    }
+
+    /// Whether the contact flow can retry the step that failed, either
+    /// because the voter can provide a different input or because the
+    /// failure is transient.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            IvrError::MissingAttribute(_)
+            | IvrError::InvalidAttribute(_)
+            | IvrError::InvalidCredentials
+            | IvrError::InvalidCredentialFormat { .. }
+            | IvrError::InvalidChildElectionSelection
+            | IvrError::InvalidVote(_)
+            | IvrError::InvalidSelectionCount { .. }
+            | IvrError::BackendUnavailable(_)
+            | IvrError::Timeout => true,
+            IvrError::InvalidStatus(status) => *status >= 500,
+            IvrError::Configuration(_)
+            | IvrError::MissingCallerId
+            | IvrError::LockedOut
+            | IvrError::EmptyVotePermissionToken
+            | IvrError::InvalidAuthTokenSignature
+            | IvrError::ExpiredAuthToken
+            | IvrError::AuthTokenElectionMismatch
+            | IvrError::ElectionNotOpen
+            | IvrError::UnconfirmedVote(_)
+            | IvrError::InvalidBackendResponse(_)
+            | IvrError::UnsupportedGroup(_)
+            | IvrError::InvalidPublicKey(_)
+            | IvrError::InvalidBallot(_)
+            | IvrError::Internal(_) => false,
+        }
+    }
+
+    /// Converts the error into the attributes returned to the contact flow.
+    /// Credential format errors also include the `ErrorAttribute` and, for
+    /// length errors, the `ExpectedMinLength` and `ExpectedMaxLength`
+    /// attributes, so that the contact flow can tell the voter what's wrong.
+    /// Likewise, selection count errors include the `ErrorQuestion`,
+    /// `ExpectedMinAnswers` and `ExpectedMaxAnswers` attributes.
+    pub fn to_response(&self) -> Value {
+        let mut response = json!({
+            "Status": "error",
+            "ErrorCode": self.code(),
+            "Retryable": self.is_retryable().to_string()
+        });
+        if let IvrError::InvalidCredentialFormat { attribute, violation } = self {
+            response["ErrorAttribute"] = json!(attribute);
+            if let FormatViolation::Length { min, max } = violation {
+                if let Some(min) = min {
+                    response["ExpectedMinLength"] = json!(min.to_string());
+                }
+                if let Some(max) = max {
+                    response["ExpectedMaxLength"] = json!(max.to_string());
+                }
+            }
+        }
+        if let IvrError::InvalidSelectionCount { question, min, max, .. } = self {
+            response["ErrorQuestion"] = json!(question.to_string());
+            response["ExpectedMinAnswers"] = json!(min.to_string());
+            response["ExpectedMaxAnswers"] = json!(max.to_string());
+        }
+        response
+    }
+}
+
+impl fmt::Display for IvrError {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IvrError::Configuration(name) =>
+                write!(formatter, "{}: env var {}", self.code(), name),
+            IvrError::MissingAttribute(name)
+            | IvrError::InvalidAttribute(name) =>
+                write!(formatter, "{}: contact attribute {}", self.code(), name),
+            IvrError::InvalidCredentialFormat { attribute, .. } =>
+                write!(formatter, "{}: contact attribute {}", self.code(), attribute),
+            IvrError::InvalidStatus(status) =>
+                write!(formatter, "{}: {}", self.code(), status),
+            IvrError::InvalidSelectionCount { question, selected, min, max } =>
+                write!(
+                    formatter,
+                    "{}: {} answers chosen in question {}, expected {} to {}",
+                    self.code(),
+                    selected,
+                    question,
+                    min,
+                    max
+                ),
+            IvrError::InvalidVote(details)
+            | IvrError::BackendUnavailable(details)
+            | IvrError::UnconfirmedVote(details)
+            | IvrError::InvalidBackendResponse(details)
+            | IvrError::UnsupportedGroup(details)
+            | IvrError::InvalidPublicKey(details)
+            | IvrError::InvalidBallot(details)
+            | IvrError::Internal(details) =>
+                write!(formatter, "{}: {}", self.code(), details),
+            IvrError::MissingCallerId
+            | IvrError::InvalidCredentials
+            | IvrError::LockedOut
+            | IvrError::EmptyVotePermissionToken
+            | IvrError::InvalidChildElectionSelection
+            | IvrError::InvalidAuthTokenSignature
+            | IvrError::ExpiredAuthToken
+            | IvrError::AuthTokenElectionMismatch
+            | IvrError::ElectionNotOpen
+            | IvrError::Timeout =>
+                write!(formatter, "{}", self.code()),
+        }
+    }
+}
+
+impl std::error::Error for IvrError {}
+
+#[cfg(test)]
+mod tests {
+    use crate::IvrError;
+    use crate::error::FormatViolation;
+
+    #[test]
+    fn error_response() {
+        let response = IvrError::InvalidCredentials.to_response();
+        assert_eq!(response["Status"], "error");
+        assert_eq!(response["ErrorCode"], "invalid-credentials");
+        assert_eq!(response["Retryable"], "true");
+
+        let response = IvrError::Configuration("LOGIN_URL".into())
+            .to_response();
+        assert_eq!(response["ErrorCode"], "configuration-error");
+        assert_eq!(response["Retryable"], "false");
+    }
+
+    #[test]
+    fn credential_format_response() {
+        let response = IvrError::InvalidCredentialFormat {
+            attribute: "VoterUserId".into(),
+            violation: FormatViolation::Length { min: Some(8), max: Some(8) },
+        }.to_response();
+        assert_eq!(response["ErrorCode"], "invalid-credential-length");
+        assert_eq!(response["Retryable"], "true");
+        assert_eq!(response["ErrorAttribute"], "VoterUserId");
+        assert_eq!(response["ExpectedMinLength"], "8");
+        assert_eq!(response["ExpectedMaxLength"], "8");
+    }
+
+    #[test]
+    fn selection_count_response() {
+        let too_few = IvrError::InvalidSelectionCount {
+            question: 2,
+            selected: 0,
+            min: 1,
+            max: 3,
+        };
+        let response = too_few.to_response();
+        assert_eq!(response["ErrorCode"], "too-few-answers");
+        assert_eq!(response["Retryable"], "true");
+        assert_eq!(response["ErrorQuestion"], "2");
+        assert_eq!(response["ExpectedMinAnswers"], "1");
+        assert_eq!(response["ExpectedMaxAnswers"], "3");
+
+        let too_many = IvrError::InvalidSelectionCount {
+            question: 1,
+            selected: 4,
+            min: 1,
+            max: 3,
+        };
+        assert_eq!(too_many.code(), "too-many-answers");
+    }
+
+    #[test]
+    fn invalid_status_retryable() {
+        assert!(IvrError::InvalidStatus(503).is_retryable());
+        assert!(!IvrError::InvalidStatus(400).is_retryable());
+        assert!(!IvrError::UnconfirmedVote("status 500".into()).is_retryable());
+    }
+}
@@ -0,0 +1,129 @@
+// SPDX-FileCopyrightText: 2022 Eduardo Robles <edu@sequentech.io>
+//
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Extra headers sent in every request to the Sequent backend, for example an
+//! API key and a client identifier required by a WAF in front of the backend.
+
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::fmt;
+use tracing::{event, Level};
+
+use crate::IvrError;
+use crate::config::json_env_var;
+
+/// Extra headers of the backend requests.
+#[derive(Clone, PartialEq, Default)]
+pub struct HeaderConfig {
+    /// Value templates of the headers, by header name.
+    headers: BTreeMap<String, String>,
+    /// Values of the secret headers, by header name.
+    secret_headers: BTreeMap<String, String>,
+}
+
+impl HeaderConfig {
+    pub fn new(
+        headers: BTreeMap<String, String>,
+        secret_headers: BTreeMap<String, String>
+    ) -> HeaderConfig
+    {
+        HeaderConfig { headers, secret_headers }
+    }
+
+    /// Reads the extra headers from the env vars:
+    /// - `HTTP_HEADERS`: optional JSON map from header name to value template,
+    ///   for example `{"X-Client-Id": "ivr", "X-Request-Id": "{{contact_id}}"}`.
+    ///   See [`HeaderConfig::render`] for the template variables.
+    /// - `HTTP_SECRET_HEADERS`: optional JSON map from header name to the name
+    ///   of the env var with its value, for example
+    ///   `{"X-Api-Key": "SEQUENT_API_KEY"}`. Secret values are never logged.
+    pub fn from_env() -> Result<HeaderConfig, IvrError> {
+        let headers: BTreeMap<String, String> =
+            json_env_var("HTTP_HEADERS")?.unwrap_or_default();
+        let secret_header_env_vars: BTreeMap<String, String> =
+            json_env_var("HTTP_SECRET_HEADERS")?.unwrap_or_default();
+
+        let mut secret_headers: BTreeMap<String, String> = BTreeMap::new();
+        for (name, env_var_name) in secret_header_env_vars.into_iter() {
+            let value = env::var(&env_var_name)
+                .map_err(|_| IvrError::Configuration(env_var_name))?;
+            secret_headers.insert(name, value);
+        }
+        let config = HeaderConfig { headers, secret_headers };
+        event!(Level::INFO, "header_config={:?}", config);
+        Ok(config)
+    }
+
+    /// Returns the headers, replacing `{{name}}` in the value templates with
+    /// the value of the `name` variable. The lambdas provide the
+    /// `contact_id`, `initial_contact_id` and `election_id` variables.
+    pub fn render(&self, variables: &HashMap<&str, String>)
+        -> Vec<(String, String)>
+    {
+        let mut headers: Vec<(String, String)> = self.headers
+            .iter()
+            .map(|(name, template)| {
+                let value = variables.iter().fold(
+                    template.clone(),
+                    |value, (variable, variable_value)| value.replace(
+                        &format!("{{{{{}}}}}", variable),
+                        variable_value
+                    )
+                );
+                (name.clone(), value)
+            })
+            .collect();
+        headers.extend(self.secret_headers.clone());
+        headers
+    }
+}
+
+impl fmt::Debug for HeaderConfig {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        formatter
+            .debug_struct("HeaderConfig")
+            .field("headers", &self.headers)
+            .field("secret_headers", &self.secret_headers.keys())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeMap, HashMap};
+    use crate::headers::HeaderConfig;
+
+    fn config() -> HeaderConfig {
+        HeaderConfig::new(
+            BTreeMap::from([
+                ("X-Client-Id".into(), "ivr".into()),
+                ("X-Request-Id".into(), "{{contact_id}}".into()),
+            ]),
+            BTreeMap::from([("X-Api-Key".into(), "secret-api-key".into())])
+        )
+    }
+
+    #[test]
+    fn render_headers() {
+        let variables: HashMap<&str, String> = HashMap::from([
+            ("contact_id", "c0ffee".into()),
+            ("election_id", "17".into()),
+        ]);
+        assert_eq!(
+            config().render(&variables),
+            vec![
+                ("X-Client-Id".into(), "ivr".into()),
+                ("X-Request-Id".into(), "c0ffee".into()),
+                ("X-Api-Key".into(), "secret-api-key".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn secret_values_not_logged() {
+        let debug = format!("{:?}", config());
+        assert!(debug.contains("X-Api-Key"));
+        assert!(!debug.contains("secret-api-key"));
+    }
+}
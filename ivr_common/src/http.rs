@@ -0,0 +1,341 @@
+// SPDX-FileCopyrightText: 2022 Eduardo Robles <edu@sequentech.io>
+//
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! HTTP calls to the Sequent backend.
+//!
+//! The [`HttpClient`] is meant to be created once per execution environment,
+//! at cold start, and reused by the warm invocations: it keeps the connections
+//! to the backend alive, so that only the first call pays the TCP and TLS
+//! handshakes, and it doesn't block the runtime while waiting for the backend.
+
+use std::time::{Duration, SystemTime};
+use reqwest::{Client, Method};
+use reqwest::header::RETRY_AFTER;
+use tracing::{event, Level};
+use url::Url;
+
+use crate::IvrError;
+use crate::config::parse_env_var;
+use crate::headers::HeaderConfig;
+use crate::retry::{is_retryable_status, parse_retry_after, retry, Failure, RetryPolicy};
+use crate::timeout::TimeoutConfig;
+use crate::tls::TlsConfig;
+
+/// Configuration of the HTTP calls to the Sequent backend.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HttpConfig {
+    pub retry_policy: RetryPolicy,
+    pub timeouts: TimeoutConfig,
+    pub headers: HeaderConfig,
+    pub tls: TlsConfig,
+    /// Time an idle connection to the backend is kept open for later calls.
+    pub pool_idle_timeout: Duration,
+}
+
+impl HttpConfig {
+    /// Reads the configuration from the env vars, see
+    /// [`RetryPolicy::from_env`], [`TimeoutConfig::from_env`],
+    /// [`HeaderConfig::from_env`] and [`TlsConfig::from_env`]. The idle
+    /// connections are kept open for `HTTP_POOL_IDLE_TIMEOUT_MS`, 50000 by
+    /// default, which should be lower than the idle timeout of the backend and
+    /// of any load balancer in front of it.
+    pub fn from_env() -> Result<HttpConfig, IvrError> {
+        let pool_idle_timeout = Duration::from_millis(
+            parse_env_var("HTTP_POOL_IDLE_TIMEOUT_MS")?.unwrap_or(50000)
+        );
+        event!(Level::INFO, "pool_idle_timeout={:?}", pool_idle_timeout);
+        Ok(HttpConfig {
+            retry_policy: RetryPolicy::from_env()?,
+            timeouts: TimeoutConfig::from_env()?,
+            headers: HeaderConfig::from_env()?,
+            tls: TlsConfig::from_env()?,
+            pool_idle_timeout,
+        })
+    }
+}
+
+/// Whether a header name only contains the characters allowed by RFC 9110.
+fn is_valid_header_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|character| {
+        character.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(character)
+    })
+}
+
+/// Whether a header value only contains visible ASCII characters, spaces and
+/// tabs.
+fn is_valid_header_value(value: &str) -> bool {
+    value.chars().all(|character| {
+        character.is_ascii_graphic() || character == ' ' || character == '\t'
+    })
+}
+
+/// A request to the Sequent backend.
+pub struct BackendRequest {
+    method: Method,
+    url: Url,
+    headers: Vec<(String, String)>,
+    body: Option<String>,
+    idempotent: bool,
+}
+
+impl BackendRequest {
+    /// Creates a request without headers nor body. `url_env_var` is the name
+    /// of the env var the url comes from, returned in the error if the url is
+    /// not valid.
+    fn new(method: Method, url: &str, url_env_var: &str)
+        -> Result<BackendRequest, IvrError>
+    {
+        let url = Url::parse(url)
+            .map_err(|_| IvrError::Configuration(url_env_var.into()))?;
+        Ok(BackendRequest {
+            method,
+            url,
+            headers: vec![],
+            body: None,
+            idempotent: false,
+        })
+    }
+
+    /// Creates a `GET` request, which is idempotent.
+    pub fn get(url: &str, url_env_var: &str) -> Result<BackendRequest, IvrError> {
+        Ok(BackendRequest::new(Method::GET, url, url_env_var)?.idempotent())
+    }
+
+    /// Creates a `POST` request.
+    pub fn post(url: &str, url_env_var: &str) -> Result<BackendRequest, IvrError> {
+        BackendRequest::new(Method::POST, url, url_env_var)
+    }
+
+    /// Adds a header, returning `error` if the name or the value are not
+    /// valid.
+    pub fn with_header<E>(mut self, name: &str, value: &str, error: E)
+        -> Result<BackendRequest, IvrError>
+    where
+        E: Fn() -> IvrError
+    {
+        if !is_valid_header_name(name) || !is_valid_header_value(value) {
+            return Err(error());
+        }
+        self.headers.push((name.into(), value.into()));
+        Ok(self)
+    }
+
+    /// Adds the extra headers of the configuration, see [`HeaderConfig`].
+    pub fn with_extra_headers(mut self, headers: &[(String, String)])
+        -> Result<BackendRequest, IvrError>
+    {
+        for (name, value) in headers.iter() {
+            self = self.with_header(name, value, || {
+                IvrError::Configuration("HTTP_HEADERS".into())
+            })?;
+        }
+        Ok(self)
+    }
+
+    pub fn with_body(mut self, body: String) -> BackendRequest {
+        self.body = Some(body);
+        self
+    }
+
+    /// Marks the request as idempotent, i.e. sending it more than once has
+    /// the same effect as sending it once. See [`crate::retry`] for which
+    /// failures are retried depending on it.
+    pub fn idempotent(mut self) -> BackendRequest {
+        self.idempotent = true;
+        self
+    }
+}
+
+/// Classifies an error of the HTTP client.
+fn request_failure(error: reqwest::Error) -> Failure {
+    if error.is_builder() {
+        Failure::Permanent(IvrError::Internal(error.to_string()))
+    } else if error.is_connect() {
+        // also covers connection timeouts, when the request was not sent yet
+        Failure::Connect(error.to_string())
+    } else if error.is_timeout() {
+        Failure::Timeout(error.to_string())
+    } else {
+        Failure::Transport(error.to_string())
+    }
+}
+
+/// HTTP client of the Sequent backend, with a pool of keep-alive connections.
+/// Clones share the same pool.
+#[derive(Clone)]
+pub struct HttpClient {
+    config: HttpConfig,
+    client: Client,
+}
+
+impl HttpClient {
+    pub fn new(config: HttpConfig) -> Result<HttpClient, IvrError> {
+        let client = Client::builder()
+            .use_preconfigured_tls(config.tls.client_config()?)
+            .connect_timeout(config.timeouts.request_timeout)
+            .pool_idle_timeout(config.pool_idle_timeout)
+            .build()
+            .map_err(|error| IvrError::Internal(error.to_string()))?;
+        Ok(HttpClient { config, client })
+    }
+
+    /// Creates the client with the configuration read from the env vars, see
+    /// [`HttpConfig::from_env`].
+    pub fn from_env() -> Result<HttpClient, IvrError> {
+        HttpClient::new(HttpConfig::from_env()?)
+    }
+
+    pub fn config(&self) -> &HttpConfig {
+        &self.config
+    }
+
+    /// Sends the request, retrying it according to the retry policy, and
+    /// returns the status and body of the response.
+    ///
+    /// Every attempt times out at the configured request timeout or at the
+    /// `deadline`, whichever comes first.
+    pub async fn send(&self, deadline: SystemTime, request: &BackendRequest)
+        -> Result<(u16, String), IvrError>
+    {
+        retry(
+            &self.config.retry_policy,
+            request.idempotent,
+            deadline,
+            || self.attempt(deadline, request)
+        ).await
+    }
+
+    /// Sends the request once.
+    async fn attempt(&self, deadline: SystemTime, request: &BackendRequest)
+        -> Result<(u16, String), Failure>
+    {
+        let timeout = self.config
+            .timeouts
+            .request_timeout(deadline, SystemTime::now())
+            .map_err(Failure::Permanent)?;
+        let mut call = self.client
+            .request(request.method.clone(), request.url.clone())
+            .timeout(timeout);
+        for (name, value) in request.headers.iter() {
+            call = call.header(name, value);
+        }
+        if let Some(body) = &request.body {
+            call = call.body(body.clone());
+        }
+        let response = call.send().await.map_err(request_failure)?;
+
+        let status = response.status().as_u16();
+        event!(Level::INFO, request_response_status = status);
+        let retry_after = response
+            .headers()
+            .get(RETRY_AFTER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| parse_retry_after(value, SystemTime::now()));
+
+        let body = response.text().await.map_err(request_failure)?;
+
+        if is_retryable_status(status) {
+            return Err(Failure::Status { status, retry_after });
+        }
+        Ok((status, body))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+    use httpmock::prelude::*;
+    use crate::IvrError;
+    use crate::headers::HeaderConfig;
+    use crate::http::{BackendRequest, HttpClient, HttpConfig};
+    use crate::retry::RetryPolicy;
+    use crate::timeout::TimeoutConfig;
+    use crate::tls::TlsConfig;
+
+    fn config() -> HttpConfig {
+        HttpConfig {
+            retry_policy: RetryPolicy {
+                max_attempts: 3,
+                base_delay: Duration::from_millis(1),
+                max_delay: Duration::from_millis(10),
+            },
+            timeouts: TimeoutConfig {
+                invocation_timeout: Duration::from_secs(8),
+                margin: Duration::from_millis(500),
+                request_timeout: Duration::from_secs(3),
+            },
+            headers: HeaderConfig::default(),
+            tls: TlsConfig::default(),
+            pool_idle_timeout: Duration::from_secs(50),
+        }
+    }
+
+    #[test]
+    fn request_headers() {
+        let request = BackendRequest::post("https://example.com/vote", "URL")
+            .unwrap()
+            .with_extra_headers(&[
+                ("X-Request-Id".into(), "c0ffee".into()),
+            ])
+            .unwrap();
+        assert!(!request.idempotent);
+        assert_eq!(
+            request.headers,
+            vec![("X-Request-Id".into(), "c0ffee".into())]
+        );
+
+        let invalid_header = || IvrError::InvalidAttribute("AuthToken".into());
+        assert_eq!(
+            BackendRequest::post("https://example.com/vote", "URL")
+                .unwrap()
+                .with_header("Authorization", "token\r\nX-Injected: 1", invalid_header)
+                .err(),
+            Some(IvrError::InvalidAttribute("AuthToken".into()))
+        );
+        assert_eq!(
+            BackendRequest::get("not a url", "GET_ELECTION_URL").err(),
+            Some(IvrError::Configuration("GET_ELECTION_URL".into()))
+        );
+    }
+
+    // the same client is reused for several calls, as in warm invocations
+    #[tokio::test]
+    async fn send_requests() {
+        let server = MockServer::start();
+        let election_mock = server.mock(|when, then| {
+            when.method(GET)
+                .path("/election/17")
+                .header("x-request-id", "c0ffee");
+            then.status(200).body("{}");
+        });
+        let unavailable_mock = server.mock(|when, then| {
+            when.method(POST).path("/election/17/voter/1");
+            then.status(503);
+        });
+
+        let client = HttpClient::new(config()).unwrap();
+        let deadline = SystemTime::now() + Duration::from_secs(60);
+        let request = BackendRequest::get(&server.url("/election/17"), "URL")
+            .unwrap()
+            .with_extra_headers(&[("X-Request-Id".into(), "c0ffee".into())])
+            .unwrap();
+        for _ in 0..2 {
+            assert_eq!(
+                client.send(deadline, &request).await,
+                Ok((200, "{}".into()))
+            );
+        }
+        election_mock.assert_hits(2);
+
+        let request =
+            BackendRequest::post(&server.url("/election/17/voter/1"), "URL")
+                .unwrap()
+                .with_body("{}".into());
+        assert_eq!(
+            client.send(deadline, &request).await,
+            Err(IvrError::InvalidStatus(503))
+        );
+        unavailable_mock.assert_hits(3);
+    }
+}
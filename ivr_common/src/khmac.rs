@@ -0,0 +1,241 @@
+// SPDX-FileCopyrightText: 2022 Eduardo Robles <edu@sequentech.io>
+//
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Parsing and validation of the khmac vote permission tokens issued by the
+//! Sequent backend, which have the following format:
+//!
+//! ```text
+//! khmac:///<algorithm>;<digest>/<voter-id>:<object-type>:<election-id>:<action>:<timestamp>
+//! ```
+//!
+//! For example:
+//!
+//! ```text
+//! khmac:///sha-256;c4ba96310ea7474b4ee2e84b00eaf412786816ea7d3713af866dab67c3201668/4cf53604330bab6a6179de2e:AuthEvent:17:vote:1665653516
+//! ```
+
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::IvrError;
+
+const KHMAC_PREFIX: &str = "khmac:///";
+
+/// A parsed khmac vote permission token.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KhmacToken {
+    /// Hash algorithm of the HMAC, for example `sha-256`.
+    pub algorithm: String,
+    /// Hex encoded HMAC digest of the message.
+    pub digest: String,
+    pub voter_id: String,
+    /// Type of the object the token grants permission on, usually
+    /// `AuthEvent`.
+    pub object_type: String,
+    /// Id of the object the token grants permission on, i.e. the election id.
+    pub election_id: String,
+    /// Action the token grants permission for, usually `vote`.
+    pub action: String,
+    /// Unix timestamp (in seconds) of when the token was issued.
+    pub timestamp: u64,
+    message: String,
+}
+
+impl KhmacToken {
+    /// The signed message, i.e. the part of the token after the digest.
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    /// Verifies the HMAC-SHA256 digest of the token with the secret shared
+    /// with the backend. The comparison is done in constant time.
+    pub fn verify(&self, shared_secret: &[u8]) -> Result<(), IvrError> {
+        let digest = hex::decode(&self.digest)
+            .map_err(|_| IvrError::InvalidAuthTokenSignature)?;
+        let mut mac = Hmac::<Sha256>::new_from_slice(shared_secret)
+            .map_err(|error| IvrError::Internal(error.to_string()))?;
+        mac.update(self.message.as_bytes());
+        mac.verify_slice(&digest)
+            .map_err(|_| IvrError::InvalidAuthTokenSignature)
+    }
+
+    /// Seconds until the token expires, given that tokens are valid for
+    /// `validity_seconds` after being issued. Negative if already expired.
+    /// The timestamp comes from the token, so the result saturates instead of
+    /// overflowing.
+    pub fn remaining_validity(&self, validity_seconds: u64, now: u64) -> i64 {
+        let expires_at = self.timestamp.saturating_add(validity_seconds);
+        if expires_at >= now {
+            i64::try_from(expires_at - now).unwrap_or(i64::MAX)
+        } else {
+            i64::try_from(now - expires_at).map_or(i64::MIN, |expired| -expired)
+        }
+    }
+
+    /// Checks that the token grants permission to vote in `election_id` and,
+    /// if `validity_seconds` is set, that it has not expired. Returns the
+    /// remaining validity in seconds, if known.
+    pub fn check(
+        &self,
+        election_id: &str,
+        validity_seconds: Option<u64>,
+        now: u64
+    ) -> Result<Option<i64>, IvrError>
+    {
+        if self.object_type != "AuthEvent"
+            || self.action != "vote"
+            || self.election_id != election_id
+        {
+            return Err(IvrError::AuthTokenElectionMismatch);
+        }
+        match validity_seconds {
+            Some(validity_seconds) => {
+                let remaining = self.remaining_validity(validity_seconds, now);
+                if remaining <= 0 {
+                    Err(IvrError::ExpiredAuthToken)
+                } else {
+                    Ok(Some(remaining))
+                }
+            },
+            None => Ok(None),
+        }
+    }
+}
+
+impl FromStr for KhmacToken {
+    type Err = IvrError;
+
+    fn from_str(token: &str) -> Result<Self, Self::Err> {
+        let invalid_token = || IvrError::InvalidAttribute("AuthToken".into());
+        let (algorithm_digest, message) = token
+            .strip_prefix(KHMAC_PREFIX)
+            .and_then(|token| token.split_once('/'))
+            .ok_or_else(invalid_token)?;
+        let (algorithm, digest) = algorithm_digest
+            .split_once(';')
+            .ok_or_else(invalid_token)?;
+        let is_valid_digest = algorithm == "sha-256"
+            && digest.len() == 64
+            && digest.chars().all(|character| character.is_ascii_hexdigit());
+        if !is_valid_digest {
+            return Err(invalid_token());
+        }
+
+        // the voter id could contain colons, so we split from the right
+        let parts: Vec<&str> = message.rsplitn(5, ':').collect();
+        if parts.len() != 5 || parts.iter().any(|part| part.is_empty()) {
+            return Err(invalid_token());
+        }
+        let timestamp: u64 = parts[0].parse().map_err(|_| invalid_token())?;
+
+        Ok(KhmacToken {
+            algorithm: algorithm.into(),
+            digest: digest.to_lowercase(),
+            voter_id: parts[4].into(),
+            object_type: parts[3].into(),
+            election_id: parts[2].into(),
+            action: parts[1].into(),
+            timestamp,
+            message: message.into(),
+        })
+    }
+}
+
+/// Current unix timestamp in seconds.
+pub fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::IvrError;
+    use crate::khmac::KhmacToken;
+
+    const TOKEN: &str = "khmac:///sha-256;c4ba96310ea7474b4ee2e84b00eaf412786816ea7d3713af866dab67c3201668/4cf53604330bab6a6179de2e:AuthEvent:17:vote:1665653516";
+
+    #[test]
+    fn parse_token() {
+        let token: KhmacToken = TOKEN.parse().unwrap();
+        assert_eq!(token.algorithm, "sha-256");
+        assert_eq!(
+            token.digest,
+            "c4ba96310ea7474b4ee2e84b00eaf412786816ea7d3713af866dab67c3201668"
+        );
+        assert_eq!(token.voter_id, "4cf53604330bab6a6179de2e");
+        assert_eq!(token.object_type, "AuthEvent");
+        assert_eq!(token.election_id, "17");
+        assert_eq!(token.action, "vote");
+        assert_eq!(token.timestamp, 1665653516);
+        assert_eq!(
+            token.message(),
+            "4cf53604330bab6a6179de2e:AuthEvent:17:vote:1665653516"
+        );
+    }
+
+    #[test]
+    fn parse_invalid_tokens() {
+        for token in [
+            "",
+            "Bearer 1234",
+            "khmac:///sha-256;c4ba/4cf53604330bab6a6179de2e:AuthEvent:17:vote:1665653516",
+            "khmac:///md5;c4ba96310ea7474b4ee2e84b00eaf412786816ea7d3713af866dab67c3201668/voter:AuthEvent:17:vote:1665653516",
+            "khmac:///sha-256;c4ba96310ea7474b4ee2e84b00eaf412786816ea7d3713af866dab67c3201668/AuthEvent:17:vote:1665653516",
+            "khmac:///sha-256;c4ba96310ea7474b4ee2e84b00eaf412786816ea7d3713af866dab67c3201668/voter:AuthEvent:17:vote:now",
+        ] {
+            assert_eq!(
+                token.parse::<KhmacToken>(),
+                Err(IvrError::InvalidAttribute("AuthToken".into()))
+            );
+        }
+    }
+
+    #[test]
+    fn verify_token() {
+        let token: KhmacToken = "khmac:///sha-256;658d68f5ad0907dea59a04391abe35db1b1210970afa0b71bdfee8514570d5eb/4cf53604330bab6a6179de2e:AuthEvent:17:vote:1665653516"
+            .parse()
+            .unwrap();
+        assert_eq!(token.verify(b"test-shared-secret"), Ok(()));
+        assert_eq!(
+            token.verify(b"another-shared-secret"),
+            Err(IvrError::InvalidAuthTokenSignature)
+        );
+
+        // tampered voter id
+        let token: KhmacToken = "khmac:///sha-256;658d68f5ad0907dea59a04391abe35db1b1210970afa0b71bdfee8514570d5eb/4cf53604330bab6a6179de2f:AuthEvent:17:vote:1665653516"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            token.verify(b"test-shared-secret"),
+            Err(IvrError::InvalidAuthTokenSignature)
+        );
+    }
+
+    #[test]
+    fn check_token() {
+        let token: KhmacToken = TOKEN.parse().unwrap();
+        assert_eq!(token.check("17", None, 1765653516), Ok(None));
+        assert_eq!(token.check("17", Some(300), 1665653616), Ok(Some(200)));
+        assert_eq!(
+            token.check("17", Some(300), 1665653816),
+            Err(IvrError::ExpiredAuthToken)
+        );
+        assert_eq!(
+            token.check("18", Some(300), 1665653616),
+            Err(IvrError::AuthTokenElectionMismatch)
+        );
+
+        // huge timestamps and validities don't overflow
+        let token = KhmacToken { timestamp: u64::MAX, ..token };
+        assert_eq!(token.remaining_validity(300, 100), i64::MAX);
+        assert_eq!(token.remaining_validity(u64::MAX, 0), i64::MAX);
+        let token = KhmacToken { timestamp: 0, ..token };
+        assert_eq!(token.remaining_validity(0, u64::MAX), i64::MIN);
+        assert_eq!(token.remaining_validity(300, 500), -200);
+    }
+}
@@ -0,0 +1,18 @@
+// SPDX-FileCopyrightText: 2022 Eduardo Robles <edu@sequentech.io>
+//
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Code shared by the `authenticate_voter` and `record_vote` lambdas.
+
+pub mod config;
+pub mod connect;
+pub mod error;
+pub mod headers;
+pub mod http;
+pub mod khmac;
+pub mod retry;
+pub mod ssml;
+pub mod timeout;
+pub mod tls;
+
+pub use error::IvrError;
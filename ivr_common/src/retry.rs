@@ -0,0 +1,360 @@
+// SPDX-FileCopyrightText: 2022 Eduardo Robles <edu@sequentech.io>
+//
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Retries of the HTTP calls to the Sequent backend, with bounded exponential
+//! backoff and jitter.
+//!
+//! Connection errors and the `429`, `502`, `503` and `504` status codes are
+//! retried, honouring the `Retry-After` header and without going past the
+//! deadline (see [`crate::timeout`]). Calls that are not idempotent, like
+//! casting a vote, are only retried when the backend can't have processed the
+//! request: when the connection couldn't be established or the backend
+//! answered with `429` or `503`. If they fail after the backend might have
+//! processed them, the `unconfirmed-vote` error is returned instead, as
+//! casting the vote is the only call that is not idempotent.
+
+use std::future::Future;
+use std::time::{Duration, SystemTime};
+use rand::Rng;
+use tracing::{event, Level};
+
+use crate::IvrError;
+use crate::config::parse_env_var;
+
+/// A failed attempt of an HTTP call that might succeed if retried.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Failure {
+    /// The connection to the backend couldn't be established, so the request
+    /// was not sent.
+    Connect(String),
+    /// The request failed after it might have been sent, so the backend might
+    /// have processed it.
+    Transport(String),
+    /// The backend didn't answer in time, so it might have processed the
+    /// request.
+    Timeout(String),
+    /// The backend answered with a retryable status code, see
+    /// [`is_retryable_status`].
+    Status {
+        status: u16,
+        /// Delay requested by the backend in the `Retry-After` header.
+        retry_after: Option<Duration>,
+    },
+    /// An error that must not be retried.
+    Permanent(IvrError),
+}
+
+impl Failure {
+    /// Whether the call can be retried after this failure.
+    pub fn is_retryable(&self, idempotent: bool) -> bool {
+        match self {
+            Failure::Connect(_) => true,
+            Failure::Transport(_) | Failure::Timeout(_) => idempotent,
+            Failure::Status { status, .. } => match status {
+                429 | 503 => true,
+                _ => idempotent,
+            },
+            Failure::Permanent(_) => false,
+        }
+    }
+
+    /// Converts the failure of the last attempt into an error. Calls that are
+    /// not idempotent fail with the `unconfirmed-vote` error if the backend
+    /// might have processed them, i.e. if they could only be retried were
+    /// they idempotent.
+    pub fn into_error(self, idempotent: bool) -> IvrError {
+        if !idempotent && self.is_retryable(true) && !self.is_retryable(false) {
+            return IvrError::UnconfirmedVote(match self {
+                Failure::Transport(details) | Failure::Timeout(details) =>
+                    details,
+                Failure::Status { status, .. } => format!("status {}", status),
+                failure => format!("{:?}", failure),
+            });
+        }
+        self.into()
+    }
+}
+
+impl From<Failure> for IvrError {
+    fn from(failure: Failure) -> IvrError {
+        match failure {
+            Failure::Connect(details) | Failure::Transport(details) =>
+                IvrError::BackendUnavailable(details),
+            Failure::Timeout(_) => IvrError::Timeout,
+            // the backend is rate limiting the calls, so it's a transient
+            // failure and not a problem with the request
+            Failure::Status { status: 429, .. } =>
+                IvrError::BackendUnavailable("too many requests".into()),
+            Failure::Status { status, .. } => IvrError::InvalidStatus(status),
+            Failure::Permanent(error) => error,
+        }
+    }
+}
+
+/// Whether an HTTP status code returned by the backend is worth retrying.
+pub fn is_retryable_status(status: u16) -> bool {
+    matches!(status, 429 | 502 | 503 | 504)
+}
+
+/// Parses the value of a `Retry-After` header, either in seconds or as an
+/// HTTP date.
+pub fn parse_retry_after(value: &str, now: SystemTime) -> Option<Duration> {
+    let value = value.trim();
+    match value.parse::<u64>() {
+        Ok(seconds) => Some(Duration::from_secs(seconds)),
+        Err(_) => httpdate::parse_http_date(value)
+            .ok()
+            .map(|date| date.duration_since(now).unwrap_or(Duration::ZERO)),
+    }
+}
+
+/// Retry policy of the HTTP calls.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    /// Maximum number of attempts, including the first one.
+    pub max_attempts: u32,
+    /// Maximum delay before the first retry. It's doubled on every retry.
+    pub base_delay: Duration,
+    /// Maximum delay between attempts. Calls for which the backend requests a
+    /// longer `Retry-After` are not retried.
+    pub max_delay: Duration,
+}
+
+impl RetryPolicy {
+    /// Reads the retry policy from the env vars:
+    /// - `HTTP_RETRY_MAX_ATTEMPTS`: maximum number of attempts, 3 by default.
+    ///   Set it to 1 to disable retries.
+    /// - `HTTP_RETRY_BASE_DELAY_MS`: base delay in milliseconds, 100 by
+    ///   default.
+    /// - `HTTP_RETRY_MAX_DELAY_MS`: maximum delay in milliseconds, 1000 by
+    ///   default.
+    pub fn from_env() -> Result<RetryPolicy, IvrError> {
+        let policy = RetryPolicy {
+            max_attempts: parse_env_var("HTTP_RETRY_MAX_ATTEMPTS")?
+                .unwrap_or(3),
+            base_delay: Duration::from_millis(
+                parse_env_var("HTTP_RETRY_BASE_DELAY_MS")?.unwrap_or(100)
+            ),
+            max_delay: Duration::from_millis(
+                parse_env_var("HTTP_RETRY_MAX_DELAY_MS")?.unwrap_or(1000)
+            ),
+        };
+        event!(Level::INFO, "retry_policy={:?}", policy);
+        Ok(policy)
+    }
+
+    /// Exponential backoff before the given retry (starting at 1), with full
+    /// jitter: `jitter` is a random number between 0 and 1.
+    pub fn backoff(&self, retry: u32, jitter: f64) -> Duration {
+        let exponential = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(retry.saturating_sub(1)))
+            .min(self.max_delay);
+        exponential.mul_f64(jitter.clamp(0.0, 1.0))
+    }
+
+    /// Delay before the given retry (starting at 1) after a failure, or `None`
+    /// if the backend requested a delay longer than the maximum one.
+    pub fn delay(&self, retry: u32, failure: &Failure, jitter: f64)
+        -> Option<Duration>
+    {
+        match failure {
+            Failure::Status { retry_after: Some(retry_after), .. } => {
+                if *retry_after > self.max_delay {
+                    None
+                } else {
+                    Some(*retry_after)
+                }
+            },
+            _ => Some(self.backoff(retry, jitter)),
+        }
+    }
+}
+
+/// Awaits the futures returned by `attempt` until one succeeds, the failure is
+/// not retryable, the maximum number of attempts is reached or the next retry
+/// wouldn't start before `deadline`. Waiting between attempts doesn't block
+/// the thread.
+pub async fn retry<T, F, A>(
+    policy: &RetryPolicy,
+    idempotent: bool,
+    deadline: SystemTime,
+    mut attempt: F
+) -> Result<T, IvrError>
+where
+    F: FnMut() -> A,
+    A: Future<Output = Result<T, Failure>>
+{
+    let mut attempts: u32 = 0;
+    loop {
+        let failure = match attempt().await {
+            Ok(value) => return Ok(value),
+            Err(failure) => failure,
+        };
+        attempts += 1;
+        if attempts >= policy.max_attempts || !failure.is_retryable(idempotent) {
+            return Err(failure.into_error(idempotent));
+        }
+        let jitter: f64 = rand::thread_rng().gen();
+        let delay = match policy.delay(attempts, &failure, jitter) {
+            Some(delay) => delay,
+            None => return Err(failure.into_error(idempotent)),
+        };
+        if SystemTime::now() + delay >= deadline {
+            event!(Level::WARN, "no time left to retry");
+            return Err(failure.into_error(idempotent));
+        }
+        event!(
+            Level::WARN,
+            attempts,
+            delay_ms = delay.as_millis() as u64,
+            "retrying after {:?}",
+            failure
+        );
+        tokio::time::sleep(delay).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+    use crate::IvrError;
+    use crate::retry::{parse_retry_after, retry, Failure, RetryPolicy};
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(10),
+        }
+    }
+
+    fn far_deadline() -> SystemTime {
+        SystemTime::now() + Duration::from_secs(60)
+    }
+
+    fn status_failure(status: u16) -> Failure {
+        Failure::Status { status, retry_after: None }
+    }
+
+    #[test]
+    fn backoff() {
+        let policy = RetryPolicy {
+            max_attempts: 5,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(1000),
+        };
+        assert_eq!(policy.backoff(1, 1.0), Duration::from_millis(100));
+        assert_eq!(policy.backoff(3, 1.0), Duration::from_millis(400));
+        assert_eq!(policy.backoff(3, 0.5), Duration::from_millis(200));
+        assert_eq!(policy.backoff(10, 1.0), Duration::from_millis(1000));
+        assert_eq!(
+            policy.delay(
+                1,
+                &Failure::Status {
+                    status: 503,
+                    retry_after: Some(Duration::from_secs(2)),
+                },
+                1.0
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn retry_after() {
+        let now = UNIX_EPOCH + Duration::from_secs(784111770);
+        assert_eq!(parse_retry_after("2", now), Some(Duration::from_secs(2)));
+        assert_eq!(
+            parse_retry_after("Sun, 06 Nov 1994 08:49:37 GMT", now),
+            Some(Duration::from_secs(7))
+        );
+        assert_eq!(parse_retry_after("soon", now), None);
+    }
+
+    #[tokio::test]
+    async fn retry_until_success() {
+        let mut attempts = 0;
+        let result = retry(&policy(), true, far_deadline(), || {
+            attempts += 1;
+            let attempt = attempts;
+            async move {
+                if attempt < 3 { Err(status_failure(502)) } else { Ok(attempt) }
+            }
+        }).await;
+        assert_eq!(result, Ok(3));
+
+        let mut attempts = 0;
+        let result: Result<(), IvrError> =
+            retry(&policy(), true, far_deadline(), || {
+                attempts += 1;
+                async { Err(status_failure(504)) }
+            }).await;
+        assert_eq!(result, Err(IvrError::InvalidStatus(504)));
+        assert_eq!(attempts, 3);
+
+        let result: Result<(), IvrError> =
+            retry(&policy(), true, far_deadline(), || async {
+                Err(status_failure(429))
+            }).await;
+        assert_eq!(
+            result,
+            Err(IvrError::BackendUnavailable("too many requests".into()))
+        );
+    }
+
+    #[tokio::test]
+    async fn non_idempotent_retries() {
+        let mut attempts = 0;
+        let result: Result<(), IvrError> =
+            retry(&policy(), false, far_deadline(), || {
+                attempts += 1;
+                async { Err(Failure::Transport("connection reset".into())) }
+            }).await;
+        // the backend might have processed the call
+        assert_eq!(
+            result,
+            Err(IvrError::UnconfirmedVote("connection reset".into()))
+        );
+        assert_eq!(attempts, 1);
+        let result: Result<(), IvrError> =
+            retry(&policy(), false, far_deadline(), || async {
+                Err(status_failure(504))
+            }).await;
+        assert_eq!(result, Err(IvrError::UnconfirmedVote("status 504".into())));
+
+        // the backend didn't process the call
+        let mut attempts = 0;
+        let result = retry(&policy(), false, far_deadline(), || {
+            attempts += 1;
+            async { Err::<(), Failure>(status_failure(503)) }
+        }).await;
+        assert_eq!(attempts, 3);
+        assert_eq!(result, Err(IvrError::InvalidStatus(503)));
+        let result: Result<(), IvrError> =
+            retry(&policy(), false, far_deadline(), || async {
+                Err(Failure::Connect("connection refused".into()))
+            }).await;
+        assert_eq!(
+            result,
+            Err(IvrError::BackendUnavailable("connection refused".into()))
+        );
+    }
+
+    #[tokio::test]
+    async fn retry_within_deadline() {
+        let mut attempts = 0;
+        let deadline = SystemTime::now();
+        let result: Result<(), IvrError> =
+            retry(&policy(), true, deadline, || {
+                attempts += 1;
+                async { Err(Failure::Connect("connection refused".into())) }
+            }).await;
+        assert_eq!(
+            result,
+            Err(IvrError::BackendUnavailable("connection refused".into()))
+        );
+        assert_eq!(attempts, 1);
+    }
+}
@@ -0,0 +1,29 @@
+// SPDX-FileCopyrightText: 2022 Eduardo Robles <edu@sequentech.io>
+//
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Helpers to build the SSML prompts returned to the contact flow.
+
+/// Escapes the characters with a special meaning in SSML, so that texts such
+/// as election titles or answers can be embedded in a prompt.
+pub fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ssml::escape;
+
+    #[test]
+    fn escape_texts() {
+        assert_eq!(escape("Bylaws"), "Bylaws");
+        assert_eq!(
+            escape("Bob & Carol's <list> \"A\""),
+            "Bob &amp; Carol&apos;s &lt;list&gt; &quot;A&quot;"
+        );
+    }
+}
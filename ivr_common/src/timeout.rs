@@ -0,0 +1,139 @@
+// SPDX-FileCopyrightText: 2022 Eduardo Robles <edu@sequentech.io>
+//
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Timeouts of the calls to the Sequent backend, so that the lambdas answer
+//! before Amazon Connect gives up on them.
+//!
+//! Amazon Connect aborts lambda invocations after 8 seconds, which might be
+//! earlier than the deadline of the lambda itself. The lambdas finish their
+//! work before the earliest of both, minus a safety margin to send the
+//! response, and return the `timeout` error code otherwise.
+
+use std::time::{Duration, SystemTime};
+use tracing::{event, Level};
+
+use crate::IvrError;
+use crate::config::parse_env_var;
+
+/// Timeout configuration.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TimeoutConfig {
+    /// Time Amazon Connect waits for the lambda to answer.
+    pub invocation_timeout: Duration,
+    /// Time reserved to answer to Amazon Connect after the last backend call.
+    pub margin: Duration,
+    /// Maximum time to connect to the backend or to wait for data from it.
+    pub request_timeout: Duration,
+}
+
+impl TimeoutConfig {
+    /// Reads the timeout configuration from the env vars:
+    /// - `INVOCATION_TIMEOUT_MS`: time Amazon Connect waits for the lambda, 8000
+    ///   by default.
+    /// - `DEADLINE_MARGIN_MS`: safety margin, 500 by default.
+    /// - `HTTP_TIMEOUT_MS`: maximum time to connect to the backend or to wait
+    ///   for data from it, 3000 by default.
+    pub fn from_env() -> Result<TimeoutConfig, IvrError> {
+        let config = TimeoutConfig {
+            invocation_timeout: Duration::from_millis(
+                parse_env_var("INVOCATION_TIMEOUT_MS")?.unwrap_or(8000)
+            ),
+            margin: Duration::from_millis(
+                parse_env_var("DEADLINE_MARGIN_MS")?.unwrap_or(500)
+            ),
+            request_timeout: Duration::from_millis(
+                parse_env_var("HTTP_TIMEOUT_MS")?.unwrap_or(3000)
+            ),
+        };
+        event!(Level::INFO, "timeout_config={:?}", config);
+        Ok(config)
+    }
+
+    /// Deadline to finish the work of an invocation started at `start`: the
+    /// earliest of the lambda deadline (if known) and the Amazon Connect
+    /// invocation timeout, minus the safety margin.
+    pub fn deadline(&self, lambda_deadline: Option<SystemTime>, start: SystemTime)
+        -> SystemTime
+    {
+        let connect_deadline = start + self.invocation_timeout;
+        let deadline = match lambda_deadline {
+            Some(lambda_deadline) => lambda_deadline.min(connect_deadline),
+            None => connect_deadline,
+        };
+        deadline
+            .checked_sub(self.margin)
+            .unwrap_or(deadline)
+    }
+
+    /// Timeout of the next backend request: the request timeout, but never
+    /// past the deadline.
+    pub fn request_timeout(&self, deadline: SystemTime, now: SystemTime)
+        -> Result<Duration, IvrError>
+    {
+        Ok(remaining_time(deadline, now)?.min(self.request_timeout))
+    }
+}
+
+/// Time left until the deadline, or the `Timeout` error if it has passed.
+pub fn remaining_time(deadline: SystemTime, now: SystemTime)
+    -> Result<Duration, IvrError>
+{
+    match deadline.duration_since(now) {
+        Ok(remaining) if !remaining.is_zero() => Ok(remaining),
+        _ => {
+            event!(Level::WARN, "deadline reached");
+            Err(IvrError::Timeout)
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+    use crate::IvrError;
+    use crate::timeout::TimeoutConfig;
+
+    fn config() -> TimeoutConfig {
+        TimeoutConfig {
+            invocation_timeout: Duration::from_secs(8),
+            margin: Duration::from_millis(500),
+            request_timeout: Duration::from_secs(3),
+        }
+    }
+
+    #[test]
+    fn deadline() {
+        let start = UNIX_EPOCH + Duration::from_secs(1000);
+        assert_eq!(
+            config().deadline(None, start),
+            start + Duration::from_millis(7500)
+        );
+        assert_eq!(
+            config().deadline(Some(start + Duration::from_secs(60)), start),
+            start + Duration::from_millis(7500)
+        );
+        assert_eq!(
+            config().deadline(Some(start + Duration::from_secs(2)), start),
+            start + Duration::from_millis(1500)
+        );
+    }
+
+    #[test]
+    fn request_timeout() {
+        let now = UNIX_EPOCH + Duration::from_secs(1000);
+        assert_eq!(
+            config().request_timeout(now + Duration::from_secs(5), now),
+            Ok(Duration::from_secs(3))
+        );
+        assert_eq!(
+            config().request_timeout(now + Duration::from_secs(1), now),
+            Ok(Duration::from_secs(1))
+        );
+        assert_eq!(config().request_timeout(now, now), Err(IvrError::Timeout));
+        assert_eq!(
+            config().request_timeout(now, now + Duration::from_secs(1)),
+            Err(IvrError::Timeout)
+        );
+    }
+}
@@ -0,0 +1,350 @@
+// SPDX-FileCopyrightText: 2022 Eduardo Robles <edu@sequentech.io>
+//
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! TLS configuration of the connections to the Sequent backend, for
+//! on-premise installs that use a private CA and require client certificates.
+
+use std::env;
+use std::fmt;
+use std::fs;
+use std::sync::Arc;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use rustls::{
+    CertificateError, ClientConfig, DigitallySignedStruct, RootCertStore,
+    SignatureScheme
+};
+use rustls::client::WebPkiServerVerifier;
+use rustls::client::danger::{
+    HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier
+};
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use sha2::{Digest, Sha256};
+use tracing::{event, Level};
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+use crate::IvrError;
+
+/// TLS configuration.
+#[derive(Clone, PartialEq, Default)]
+pub struct TlsConfig {
+    /// PEM bundle of the CAs trusted instead of the web PKI roots.
+    pub ca_bundle: Option<String>,
+    /// PEM client certificate chain, for mutual TLS.
+    pub client_cert: Option<String>,
+    /// PEM private key of the client certificate.
+    pub client_key: Option<String>,
+    /// Base64 SHA-256 digests of the SubjectPublicKeyInfo of the accepted
+    /// backend certificates. If not empty, the backend certificate itself
+    /// must match one of them.
+    pub spki_pins: Vec<String>,
+}
+
+/// Reads a PEM env var, which contains either the PEM data or the path of a
+/// file with it.
+fn pem_env_var(name: &str) -> Result<Option<String>, IvrError> {
+    match env::var(name) {
+        Ok(value) if value.trim_start().starts_with("-----BEGIN") =>
+            Ok(Some(value)),
+        Ok(path) if !path.is_empty() => fs::read_to_string(&path)
+            .map(Some)
+            .map_err(|_| IvrError::Configuration(name.into())),
+        _ => Ok(None),
+    }
+}
+
+fn parse_certificates(pem: &str, env_var_name: &str)
+    -> Result<Vec<CertificateDer<'static>>, IvrError>
+{
+    let certificates = rustls_pemfile::certs(&mut pem.as_bytes())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| IvrError::Configuration(env_var_name.into()))?;
+    if certificates.is_empty() {
+        return Err(IvrError::Configuration(env_var_name.into()));
+    }
+    Ok(certificates)
+}
+
+/// Base64 SHA-256 digest of the SubjectPublicKeyInfo of a DER certificate,
+/// as used for pinning.
+pub fn spki_pin(certificate: &[u8]) -> Option<String> {
+    let (_, certificate) = X509Certificate::from_der(certificate).ok()?;
+    Some(BASE64.encode(Sha256::digest(certificate.public_key().raw)))
+}
+
+/// Verifies the backend certificate with the trusted CAs and then checks that
+/// the certificate is pinned.
+///
+/// Only the end-entity certificate is checked: the intermediates sent by the
+/// server are not authenticated, so anyone could add a pinned certificate to
+/// the chain of a certificate misissued by any trusted CA.
+#[derive(Debug)]
+struct PinnedVerifier {
+    verifier: Arc<WebPkiServerVerifier>,
+    spki_pins: Vec<String>,
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime
+    ) -> Result<ServerCertVerified, rustls::Error>
+    {
+        let verified = self.verifier.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            ocsp_response,
+            now
+        )?;
+        let is_pinned = spki_pin(end_entity)
+            .map(|pin| self.spki_pins.contains(&pin))
+            .unwrap_or(false);
+        if is_pinned {
+            Ok(verified)
+        } else {
+            event!(Level::ERROR, "backend certificate is not pinned");
+            Err(rustls::Error::InvalidCertificate(
+                CertificateError::ApplicationVerificationFailure
+            ))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        certificate: &CertificateDer<'_>,
+        signature: &DigitallySignedStruct
+    ) -> Result<HandshakeSignatureValid, rustls::Error>
+    {
+        self.verifier.verify_tls12_signature(message, certificate, signature)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        certificate: &CertificateDer<'_>,
+        signature: &DigitallySignedStruct
+    ) -> Result<HandshakeSignatureValid, rustls::Error>
+    {
+        self.verifier.verify_tls13_signature(message, certificate, signature)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.verifier.supported_verify_schemes()
+    }
+}
+
+impl TlsConfig {
+    /// Reads the TLS configuration from the env vars. The PEM env vars contain
+    /// either the PEM data or the path of a file with it:
+    /// - `TLS_CA_BUNDLE`: optional PEM bundle of the CAs trusted instead of the
+    ///   web PKI roots.
+    /// - `TLS_CLIENT_CERT` and `TLS_CLIENT_KEY`: optional PEM client
+    ///   certificate chain and private key, for mutual TLS. The key is never
+    ///   logged.
+    /// - `TLS_SPKI_PINS`: optional comma separated list of base64 SHA-256
+    ///   digests of the SubjectPublicKeyInfo of the accepted backend
+    ///   certificates.
+    pub fn from_env() -> Result<TlsConfig, IvrError> {
+        let config = TlsConfig {
+            ca_bundle: pem_env_var("TLS_CA_BUNDLE")?,
+            client_cert: pem_env_var("TLS_CLIENT_CERT")?,
+            client_key: pem_env_var("TLS_CLIENT_KEY")?,
+            spki_pins: env::var("TLS_SPKI_PINS")
+                .unwrap_or_default()
+                .split(',')
+                .map(|pin| pin.trim())
+                .filter(|pin| !pin.is_empty())
+                .map(String::from)
+                .collect(),
+        };
+        event!(Level::INFO, "tls_config={:?}", config);
+        Ok(config)
+    }
+
+    fn server_cert_verifier(&self, provider: Arc<CryptoProvider>)
+        -> Result<Arc<dyn ServerCertVerifier>, IvrError>
+    {
+        let mut roots = RootCertStore::empty();
+        match &self.ca_bundle {
+            Some(ca_bundle) => {
+                for certificate in parse_certificates(ca_bundle, "TLS_CA_BUNDLE")? {
+                    roots.add(certificate).map_err(|_| {
+                        IvrError::Configuration("TLS_CA_BUNDLE".into())
+                    })?;
+                }
+            },
+            None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+        };
+        let verifier = WebPkiServerVerifier::builder_with_provider(
+            Arc::new(roots),
+            provider
+        )
+            .build()
+            .map_err(|error| IvrError::Internal(error.to_string()))?;
+        if self.spki_pins.is_empty() {
+            Ok(verifier)
+        } else {
+            Ok(Arc::new(PinnedVerifier {
+                verifier,
+                spki_pins: self.spki_pins.clone(),
+            }))
+        }
+    }
+
+    /// Builds the rustls client configuration.
+    pub fn client_config(&self) -> Result<ClientConfig, IvrError> {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let builder = ClientConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(|error| IvrError::Internal(error.to_string()))?
+            .dangerous()
+            .with_custom_certificate_verifier(
+                self.server_cert_verifier(provider)?
+            );
+        match (&self.client_cert, &self.client_key) {
+            (Some(client_cert), Some(client_key)) => {
+                let client_cert = parse_certificates(
+                    client_cert,
+                    "TLS_CLIENT_CERT"
+                )?;
+                let client_key: PrivateKeyDer<'static> =
+                    rustls_pemfile::private_key(&mut client_key.as_bytes())
+                        .ok()
+                        .flatten()
+                        .ok_or_else(|| {
+                            IvrError::Configuration("TLS_CLIENT_KEY".into())
+                        })?;
+                builder
+                    .with_client_auth_cert(client_cert, client_key)
+                    .map_err(|_| IvrError::Configuration("TLS_CLIENT_KEY".into()))
+            },
+            (None, None) => Ok(builder.with_no_client_auth()),
+            (Some(_), None) =>
+                Err(IvrError::Configuration("TLS_CLIENT_KEY".into())),
+            (None, Some(_)) =>
+                Err(IvrError::Configuration("TLS_CLIENT_CERT".into())),
+        }
+    }
+}
+
+impl fmt::Debug for TlsConfig {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        formatter
+            .debug_struct("TlsConfig")
+            .field("ca_bundle", &self.ca_bundle.is_some())
+            .field("client_cert", &self.client_cert.is_some())
+            .field("client_key", &self.client_key.is_some())
+            .field("spki_pins", &self.spki_pins)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use rustls::pki_types::{ServerName, UnixTime};
+    use crate::IvrError;
+    use crate::tls::{parse_certificates, spki_pin, TlsConfig};
+
+    const CA: &str = include_str!("../test/tls/ca.pem");
+    const SERVER_CERT: &str = include_str!("../test/tls/server.pem");
+    /// Another CA, and a certificate for the same server name issued by it.
+    const OTHER_CA: &str = include_str!("../test/tls/other-ca.pem");
+    const MISISSUED_CERT: &str = include_str!("../test/tls/misissued.pem");
+    const CLIENT_CERT: &str = include_str!("../test/tls/client.pem");
+    const CLIENT_KEY: &str = include_str!("../test/tls/client-key.pem");
+    const SERVER_PIN: &str = "dCxyjDGUoJD6MqrxHcNWpc9fyu1YHUdnb33oTLqWd0U=";
+
+    /// Verifies the certificate of `localhost` in the `chain` PEM, with the
+    /// rest of the certificates as intermediates.
+    fn verify_chain(config: &TlsConfig, chain: &str) -> bool {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let chain = parse_certificates(chain, "test").unwrap();
+        config
+            .server_cert_verifier(provider)
+            .unwrap()
+            .verify_server_cert(
+                &chain[0],
+                &chain[1..],
+                &ServerName::try_from("localhost").unwrap(),
+                &[],
+                UnixTime::now()
+            )
+            .is_ok()
+    }
+
+    fn verify_server_cert(config: &TlsConfig) -> bool {
+        verify_chain(config, SERVER_CERT)
+    }
+
+    #[test]
+    fn certificate_pin() {
+        let server_cert = parse_certificates(SERVER_CERT, "test").unwrap();
+        assert_eq!(spki_pin(&server_cert[0]), Some(SERVER_PIN.into()));
+        assert_eq!(spki_pin(b"not a certificate"), None);
+    }
+
+    #[test]
+    fn private_ca_and_pinning() {
+        let mut config = TlsConfig {
+            ca_bundle: Some(CA.into()),
+            ..Default::default()
+        };
+        assert!(verify_server_cert(&config));
+
+        config.spki_pins = vec![SERVER_PIN.into()];
+        assert!(verify_server_cert(&config));
+
+        config.spki_pins = vec![
+            "si5ml10Xr+xzkcF9I53uXvHMIUwtHuZu+IVOAyGsGJw=".into()
+        ];
+        assert!(!verify_server_cert(&config));
+
+        // the private CA is not trusted by default
+        assert!(!verify_server_cert(&TlsConfig::default()));
+    }
+
+    #[test]
+    fn pinned_intermediate() {
+        let config = TlsConfig {
+            ca_bundle: Some(format!("{}{}", CA, OTHER_CA)),
+            spki_pins: vec![SERVER_PIN.into()],
+            ..Default::default()
+        };
+        assert!(verify_server_cert(&config));
+        assert!(!verify_chain(&config, MISISSUED_CERT));
+
+        // the pinned certificate is public, so it can be sent as an extra
+        // intermediate of an unrelated chain that is valid
+        let chain = format!("{}{}", MISISSUED_CERT, SERVER_CERT);
+        let unpinned = TlsConfig { spki_pins: vec![], ..config.clone() };
+        assert!(verify_chain(&unpinned, &chain));
+        assert!(!verify_chain(&config, &chain));
+    }
+
+    #[test]
+    fn client_certificate() {
+        let mut config = TlsConfig {
+            ca_bundle: Some(CA.into()),
+            client_cert: Some(CLIENT_CERT.into()),
+            client_key: Some(CLIENT_KEY.into()),
+            spki_pins: vec![],
+        };
+        assert!(config.client_config().unwrap().client_auth_cert_resolver.has_certs());
+        assert!(!format!("{:?}", config).contains("PRIVATE KEY"));
+
+        config.client_key = None;
+        assert_eq!(
+            config.client_config().err(),
+            Some(IvrError::Configuration("TLS_CLIENT_KEY".into()))
+        );
+    }
+}
@@ -0,0 +1,349 @@
+// SPDX-FileCopyrightText: 2022 Eduardo Robles <edu@sequentech.io>
+//
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Benaloh cast-or-audit challenge.
+//!
+//! The ballot is first encrypted, with its proofs, and committed to by
+//! returning its hash to the voter, who then decides whether to cast it or
+//! audit it. The randomness of its encryption is derived from a random nonce,
+//! returned with the ballot, and a secret of the lambda, so that the ballot
+//! can be audited later. The randomness is not sent anywhere unless the ballot
+//! is audited, but it's not secret either: anyone with the secret and the
+//! nonce can derive it again, and so decrypt the ballot, and the nonce is a
+//! contact attribute, so it's kept in the contact trace records and in the
+//! `DEBUG` logs of the connect events.
+//!
+//! An audited ballot is spoiled: the randomness of its encryption is published
+//! to the audit endpoint, so that the voter can check with a verifier tool
+//! that the ballot with that hash encrypts their choices, and then vote again.
+//! The short audit code read to the voter identifies the published randomness.
+//! The use of every committed ballot is recorded in a directory shared by all
+//! the instances of the lambda, so that an audited ballot is never cast and a
+//! cast ballot is never audited.
+
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::Path;
+use hmac::{Hmac, Mac};
+use num_bigint::BigUint;
+use rand::RngCore;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use strand::context::Ctx;
+use ivr_common::IvrError;
+
+use crate::ballot::EncryptedVote;
+use crate::election::PublicKeyStrings;
+use crate::group::Group;
+
+/// Number of hex characters of the audit code.
+const AUDIT_CODE_LENGTH: usize = 6;
+
+/// Number of random bytes of a ballot nonce.
+const NONCE_LENGTH: usize = 16;
+
+/// Extra bits of the digests the randomness is reduced from, so that its bias
+/// is negligible.
+const RANDOMNESS_EXTRA_BITS: u64 = 128;
+
+/// Spoiled ballot published to the `AUDIT_BALLOT_URL` endpoint.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct AuditedBallot {
+    /// The encrypted ballot, with its proofs, as it would have been cast.
+    pub ballot: String,
+    pub ballot_hash: String,
+    /// Randomness of the encryption of every choice, in decimal.
+    pub randomness: Vec<String>,
+    pub audit_code: String,
+}
+
+/// Returns the audit code of a ballot: the start of the hex SHA-256 digest of
+/// the randomness of its choices in decimal, separated by `/`.
+pub fn audit_code(randomness: &[String]) -> String {
+    let digest = Sha256::digest(randomness.join("/").as_bytes());
+    hex::encode(digest)[..AUDIT_CODE_LENGTH].to_string()
+}
+
+/// Returns a new random ballot nonce, in hex.
+pub fn new_nonce() -> String {
+    let mut nonce = [0u8; NONCE_LENGTH];
+    rand::thread_rng().fill_bytes(&mut nonce);
+    hex::encode(nonce)
+}
+
+/// Checks that a ballot nonce, from the `BallotNonce` contact attribute, has
+/// the format of the nonces returned by `new_nonce`.
+pub fn check_nonce(nonce: &str) -> Result<(), IvrError> {
+    let valid = nonce.len() == NONCE_LENGTH * 2
+        && nonce.chars().all(|character| character.is_ascii_hexdigit());
+    if !valid {
+        return Err(IvrError::InvalidAttribute("BallotNonce".into()));
+    }
+    Ok(())
+}
+
+/// Derives the randomness of the encryption of the choice `index` of the
+/// ballot with the given `nonce`, with HMAC-SHA256 keyed with the `secret`.
+/// The digests of `{nonce}/{index}/{block}`, for the blocks `0`, `1`..., are
+/// concatenated until they have 128 bits more than `q`, and reduced to a
+/// number between `1` and `q - 1`.
+pub fn derive_randomness(
+    secret: &[u8],
+    nonce: &str,
+    index: usize,
+    q: &BigUint
+) -> Result<BigUint, IvrError>
+{
+    let mut bytes: Vec<u8> = vec![];
+    let mut block = 0;
+    while (bytes.len() as u64) * 8 < q.bits() + RANDOMNESS_EXTRA_BITS {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret)
+            .map_err(|error| IvrError::Internal(error.to_string()))?;
+        mac.update(format!("{}/{}/{}", nonce, index, block).as_bytes());
+        bytes.extend_from_slice(&mac.finalize().into_bytes());
+        block += 1;
+    }
+    Ok(BigUint::from_bytes_be(&bytes) % (q - 1u32) + 1u32)
+}
+
+/// Checks that every choice of the ballot was encrypted with the given
+/// randomness, i.e. that `alpha = g^r mod p`, returning the
+/// `invalid-attribute` error for the `BallotNonce` otherwise.
+pub fn check_randomness(
+    public_keys: &[PublicKeyStrings],
+    ballot: &EncryptedVote,
+    randomness: &[BigUint]
+) -> Result<(), IvrError>
+{
+    for (index, (choice, public_key)) in
+        ballot.choices.iter().zip(public_keys).enumerate()
+    {
+        let context = Group::find(
+            public_key,
+            &format!("payload.pks[{}]", index)
+        )?.context();
+        let randomness = context
+            .exp_from_string_radix(&randomness[index].to_string(), 10)
+            .map_err(|error| IvrError::Internal(error.to_string()))?;
+        if context.gmod_pow(&randomness).to_string_radix(10) != choice.alpha {
+            return Err(IvrError::InvalidAttribute("BallotNonce".into()));
+        }
+    }
+    Ok(())
+}
+
+/// How a committed ballot is used.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BallotUse {
+    Cast,
+    Audit,
+}
+
+impl BallotUse {
+    fn as_str(&self) -> &'static str {
+        match self {
+            BallotUse::Cast => "cast",
+            BallotUse::Audit => "audit",
+        }
+    }
+}
+
+/// Records that the committed ballot with the hex `ballot_hash` is used, in
+/// the `used_ballots_dir` shared by all the instances of the lambda. The record
+/// is a file named after the hash and created atomically, so a ballot can only
+/// be used in one way: casting an audited ballot, or auditing a cast one,
+/// returns the `invalid-ballot` error. Using it again in the same way is
+/// allowed, so that failed calls can be retried.
+pub fn record_ballot_use(
+    used_ballots_dir: &Path,
+    ballot_hash: &str,
+    ballot_use: BallotUse
+) -> Result<(), IvrError>
+{
+    let valid_hash = !ballot_hash.is_empty()
+        && ballot_hash.chars().all(|character| character.is_ascii_hexdigit());
+    if !valid_hash {
+        return Err(IvrError::Internal(format!(
+            "invalid ballot hash {}",
+            ballot_hash
+        )));
+    }
+    let path = used_ballots_dir.join(ballot_hash);
+    let internal = |error: std::io::Error| {
+        IvrError::Internal(format!("{}: {}", path.display(), error))
+    };
+    match OpenOptions::new().write(true).create_new(true).open(&path) {
+        Ok(mut file) => file
+            .write_all(ballot_use.as_str().as_bytes())
+            .and_then(|_| file.sync_all())
+            .map_err(internal),
+        Err(error) if error.kind() == ErrorKind::AlreadyExists => {
+            // a record still being written by another instance is treated as
+            // a different use, so that the ballot is never used in both ways
+            let used = fs::read_to_string(&path).map_err(internal)?;
+            if used != ballot_use.as_str() {
+                return Err(IvrError::InvalidBallot(format!(
+                    "ballot already used: {}",
+                    used
+                )));
+            }
+            Ok(())
+        },
+        Err(error) => Err(internal(error)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::time::{SystemTime, UNIX_EPOCH};
+    use num_bigint::BigUint;
+    use ivr_common::IvrError;
+    use crate::audit::{
+        audit_code, check_nonce, check_randomness, derive_randomness,
+        new_nonce, record_ballot_use, BallotUse
+    };
+    use crate::ballot::{EncryptedChoice, EncryptedVote};
+    use crate::election::PublicKeyStrings;
+    use crate::group::Group;
+
+    #[test]
+    fn audit_codes() {
+        let randomness = vec!["3".to_string(), "7".to_string()];
+        // sha256("3/7") = d016d3...
+        assert_eq!(audit_code(&randomness), "d016d3");
+        assert_ne!(
+            audit_code(&randomness),
+            audit_code(&["7".to_string(), "3".to_string()])
+        );
+    }
+
+    #[test]
+    fn nonces() {
+        let nonce = new_nonce();
+        assert_eq!(check_nonce(&nonce), Ok(()));
+        assert_ne!(nonce, new_nonce());
+        for invalid in ["", "00", &format!("{}0", nonce), &"g".repeat(32)] {
+            assert_eq!(
+                check_nonce(invalid),
+                Err(IvrError::InvalidAttribute("BallotNonce".into()))
+            );
+        }
+    }
+
+    #[test]
+    fn derived_randomness() {
+        let q = BigUint::from(11u32);
+        let derive = |secret: &[u8], nonce: &str, index: usize| {
+            derive_randomness(secret, nonce, index, &q).unwrap()
+        };
+        let randomness = derive(b"secret", "00ff", 0);
+        assert!(randomness >= BigUint::from(1u32) && randomness < q);
+        assert_eq!(randomness, derive(b"secret", "00ff", 0));
+
+        // 2048-bit values are reduced from 9 digests
+        let q = BigUint::from(1u32) << 2047;
+        let values: Vec<BigUint> = [
+            (b"secret".as_slice(), "00ff", 0),
+            (b"secret", "00ff", 1),
+            (b"secret", "00fe", 0),
+            (b"other", "00ff", 0),
+        ]
+            .iter()
+            .map(|(secret, nonce, index)| {
+                derive_randomness(secret, nonce, *index, &q).unwrap()
+            })
+            .collect();
+        for (index, value) in values.iter().enumerate() {
+            assert!(value.bits() > 2000 && value < &q);
+            assert!(!values[index + 1..].contains(value));
+        }
+    }
+
+    #[test]
+    fn check_ballot_randomness() {
+        let parameters = Group::P2048.parameters();
+        let public_keys = vec![PublicKeyStrings {
+            p: parameters.p.to_string(),
+            q: parameters.q.to_string(),
+            g: parameters.g.to_string(),
+            y: parameters.g.modpow(&BigUint::from(5u32), &parameters.p)
+                .to_string(),
+        }];
+        let alpha = parameters.g.modpow(&BigUint::from(3u32), &parameters.p);
+        let ballot = EncryptedVote {
+            choices: vec![EncryptedChoice {
+                alpha: alpha.to_string(),
+                beta: "4".into(),
+            }],
+            issue_date: "2022/10/13".into(),
+            proofs: vec![],
+        };
+        assert_eq!(
+            check_randomness(&public_keys, &ballot, &[BigUint::from(3u32)]),
+            Ok(())
+        );
+        assert_eq!(
+            check_randomness(&public_keys, &ballot, &[BigUint::from(4u32)]),
+            Err(IvrError::InvalidAttribute("BallotNonce".into()))
+        );
+    }
+
+    #[test]
+    fn ballot_uses() {
+        let used_ballots_dir = std::env::temp_dir().join(format!(
+            "used-ballots-{}-{}",
+            std::process::id(),
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_nanos()
+        ));
+        fs::create_dir(&used_ballots_dir).unwrap();
+        let record = |ballot_hash: &str, ballot_use| {
+            record_ballot_use(&used_ballots_dir, ballot_hash, ballot_use)
+        };
+
+        // a ballot can be used again in the same way, but not in the other
+        assert_eq!(record("00aa", BallotUse::Audit), Ok(()));
+        assert_eq!(record("00aa", BallotUse::Audit), Ok(()));
+        assert_eq!(
+            record("00aa", BallotUse::Cast),
+            Err(IvrError::InvalidBallot("ballot already used: audit".into()))
+        );
+        assert_eq!(record("00bb", BallotUse::Cast), Ok(()));
+        assert_eq!(record("00bb", BallotUse::Cast), Ok(()));
+        assert_eq!(
+            record("00bb", BallotUse::Audit),
+            Err(IvrError::InvalidBallot("ballot already used: cast".into()))
+        );
+        assert_eq!(
+            record("../00aa", BallotUse::Cast),
+            Err(IvrError::Internal("invalid ballot hash ../00aa".into()))
+        );
+
+        // the records are shared by all the instances
+        let instances: Vec<_> = (0..8)
+            .map(|instance| {
+                let used_ballots_dir = used_ballots_dir.clone();
+                std::thread::spawn(move || {
+                    let ballot_use = match instance % 2 {
+                        0 => BallotUse::Cast,
+                        _ => BallotUse::Audit,
+                    };
+                    record_ballot_use(&used_ballots_dir, "00cc", ballot_use)
+                        .map(|_| ballot_use)
+                })
+            })
+            .collect();
+        let uses: Vec<BallotUse> = instances
+            .into_iter()
+            .filter_map(|instance| instance.join().unwrap().ok())
+            .collect();
+        assert!(!uses.is_empty());
+        assert!(uses.iter().all(|ballot_use| *ballot_use == uses[0]));
+
+        fs::remove_dir_all(&used_ballots_dir).unwrap();
+    }
+}
@@ -0,0 +1,332 @@
+// SPDX-FileCopyrightText: 2022 Eduardo Robles <edu@sequentech.io>
+//
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Encrypted ballots, as cast to the ballot box or published for auditing.
+//!
+//! Every ballot is encrypted and its proofs verified by the same pipeline,
+//! whatever is later done with it, so that an audited ballot is exactly the
+//! ballot that would have been cast.
+
+use std::time::SystemTime;
+use num_bigint::BigUint;
+use serde::{Deserialize, Serialize};
+use strand::context::Ctx;
+use strand::elgamal::PublicKey;
+use strand::zkp::Zkp;
+use ivr_common::IvrError;
+use ivr_common::timeout::remaining_time;
+
+use crate::election::PublicKeyStrings;
+use crate::group::Group;
+use crate::proof::{verify_proof, ProofVersion};
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PlaintextProof {
+    pub challenge: String,
+    pub commitment: String,
+    pub response: String
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct EncryptedChoice {
+    pub alpha: String,
+    pub beta: String
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct EncryptedVote {
+    pub choices: Vec<EncryptedChoice>,
+    pub issue_date: String,
+    pub proofs: Vec<PlaintextProof>
+}
+
+/// Encrypts the `plaintexts` of the questions with strand, each one with the
+/// public key of its question, and proves the knowledge of the randomness with
+/// the proof `labels` of the questions. The proofs are verified before
+/// returning the ballot. Every encryption is only started if the `deadline`
+/// hasn't passed.
+///
+/// The randomness of every question is random, unless the ballot might be
+/// audited and its `randomness` is given. Only context-bound proofs can be
+/// proved for a given randomness, so ballots with legacy proofs can't be
+/// audited.
+pub fn encrypt_ballot(
+    public_keys: &[PublicKeyStrings],
+    plaintexts: &[BigUint],
+    randomness: Option<&[BigUint]>,
+    proof_version: ProofVersion,
+    labels: &[Vec<u8>],
+    issue_date: String,
+    deadline: SystemTime
+) -> Result<EncryptedVote, IvrError>
+{
+    let internal = |error: String| IvrError::Internal(error);
+    let mut choices: Vec<EncryptedChoice> = vec![];
+    let mut proofs: Vec<PlaintextProof> = vec![];
+    for (index, public_key_obj) in public_keys.iter().enumerate() {
+        let path = format!("payload.pks[{}]", index);
+        let context = Group::find(public_key_obj, &path)?.context();
+        let public_key_element = context
+            .element_from_string_radix(&public_key_obj.y, 10)
+            .map_err(|_| IvrError::InvalidBackendResponse(format!(
+                "{}.y: not a number",
+                path
+            )))?;
+        let public_key = PublicKey::from_element(&public_key_element, &context);
+        let vote_encoded = context
+            .encode(&plaintexts[index])
+            .map_err(|error| internal(error.to_string()))?;
+
+        // Encrypting takes a while, so don't start if there's no time left to
+        // cast the vote afterwards
+        remaining_time(deadline, SystemTime::now())?;
+
+        let label = &labels[index];
+        let (cyphertext, plaintext_proof) = match (proof_version, randomness) {
+            (ProofVersion::ContextBound, None) => {
+                public_key.encrypt_and_pok(&vote_encoded, label)
+            },
+            (ProofVersion::ContextBound, Some(randomness)) => {
+                let randomness = context
+                    .exp_from_string_radix(&randomness[index].to_string(), 10)
+                    .map_err(|error| internal(error.to_string()))?;
+                let cyphertext = public_key
+                    .encrypt_with_randomness(&vote_encoded, &randomness);
+                let plaintext_proof = Zkp::new(&context).encryption_popk(
+                    &randomness,
+                    cyphertext.mhr(),
+                    cyphertext.gr(),
+                    label
+                );
+                (cyphertext, plaintext_proof)
+            },
+            (ProofVersion::Legacy, None) => {
+                // the debug output is not logged, as it might reveal the vote
+                let (cyphertext, plaintext_proof, _debug_str) = public_key
+                    .encrypt_and_pok_old_version(&vote_encoded, &vec![]);
+                (cyphertext, plaintext_proof)
+            },
+            (ProofVersion::Legacy, Some(_)) => {
+                return Err(internal(
+                    "legacy proofs can't be proved for a given randomness"
+                        .into()
+                ));
+            },
+        };
+        choices.push(EncryptedChoice {
+            alpha: cyphertext.gr().to_string_radix(10),
+            beta: cyphertext.mhr().to_string_radix(10)
+        });
+        proofs.push(PlaintextProof {
+            challenge: plaintext_proof.challenge.to_string_radix(10),
+            commitment: plaintext_proof.commitment.to_string_radix(10),
+            response: plaintext_proof.response.to_string_radix(10)
+        });
+    }
+    let ballot = EncryptedVote { choices, issue_date, proofs };
+    verify_ballot(public_keys, &ballot, proof_version, labels)?;
+    Ok(ballot)
+}
+
+/// Verifies the ballot as the ballot box would: it must have one choice and
+/// one proof per question, with `beta` in the group and a valid proof for the
+/// proof `labels` of the questions. Otherwise returns the `invalid-ballot`
+/// error, so that an invalid ballot is never cast.
+pub fn verify_ballot(
+    public_keys: &[PublicKeyStrings],
+    ballot: &EncryptedVote,
+    proof_version: ProofVersion,
+    labels: &[Vec<u8>]
+) -> Result<(), IvrError>
+{
+    if ballot.choices.len() != public_keys.len()
+        || ballot.proofs.len() != public_keys.len()
+    {
+        return Err(IvrError::InvalidBallot(format!(
+            "choices: {} choices and {} proofs for {} questions",
+            ballot.choices.len(),
+            ballot.proofs.len(),
+            public_keys.len()
+        )));
+    }
+    let choices = ballot.choices.iter().zip(&ballot.proofs);
+    for (index, (choice, proof)) in choices.enumerate() {
+        let path = format!("choices[{}]", index);
+        let group = Group::find(
+            &public_keys[index],
+            &format!("payload.pks[{}]", index)
+        )?;
+        let beta = BigUint::parse_bytes(choice.beta.as_bytes(), 10)
+            .ok_or_else(|| {
+                IvrError::InvalidBallot(format!("{}: not a number", path))
+            })?;
+        if beta == BigUint::default() || beta >= group.parameters().p {
+            return Err(IvrError::InvalidBallot(format!(
+                "{}: beta out of range",
+                path
+            )));
+        }
+        verify_proof(
+            proof_version,
+            &labels[index],
+            group,
+            choice,
+            proof,
+            &path
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+    use num_bigint::BigUint;
+    use ivr_common::IvrError;
+    use crate::ballot::{encrypt_ballot, verify_ballot, EncryptedVote};
+    use crate::election::PublicKeyStrings;
+    use crate::group::Group;
+    use crate::proof::ProofVersion;
+
+    /// Keys of the real group, as strand only supports its contexts.
+    fn public_keys() -> Vec<PublicKeyStrings> {
+        let parameters = Group::P2048.parameters();
+        let y = parameters.g.modpow(&BigUint::from(5u32), &parameters.p);
+        vec![
+            PublicKeyStrings {
+                p: parameters.p.to_string(),
+                q: parameters.q.to_string(),
+                g: parameters.g.to_string(),
+                y: y.to_string(),
+            };
+            2
+        ]
+    }
+
+    fn ballot(proof_version: ProofVersion, randomness: Option<&[BigUint]>)
+        -> Result<EncryptedVote, IvrError>
+    {
+        encrypt_ballot(
+            &public_keys(),
+            &[BigUint::from(9u32), BigUint::from(13u32)],
+            randomness,
+            proof_version,
+            &labels(),
+            "2022/10/13".into(),
+            SystemTime::now() + Duration::from_secs(60)
+        )
+    }
+
+    fn labels() -> Vec<Vec<u8>> {
+        vec![b"17/100/a".to_vec(), b"17/100/b".to_vec()]
+    }
+
+    #[test]
+    fn encrypt_ballots() {
+        let versions = [ProofVersion::Legacy, ProofVersion::ContextBound];
+        for proof_version in versions {
+            let encrypted = ballot(proof_version, None).unwrap();
+            assert_eq!(encrypted.choices.len(), 2);
+            assert_eq!(encrypted.issue_date, "2022/10/13");
+            assert_eq!(
+                verify_ballot(
+                    &public_keys(),
+                    &encrypted,
+                    proof_version,
+                    &labels()
+                ),
+                Ok(())
+            );
+        }
+
+        // ballots that might be audited are encrypted with the given
+        // randomness, so that alpha = g^r mod p
+        let randomness = [BigUint::from(3u32), BigUint::from(7u32)];
+        let audited =
+            ballot(ProofVersion::ContextBound, Some(&randomness)).unwrap();
+        let parameters = Group::P2048.parameters();
+        for (choice, r) in audited.choices.iter().zip(&randomness) {
+            assert_eq!(
+                choice.alpha,
+                parameters.g.modpow(r, &parameters.p).to_string()
+            );
+        }
+        assert_eq!(
+            verify_ballot(
+                &public_keys(),
+                &audited,
+                ProofVersion::ContextBound,
+                &labels()
+            ),
+            Ok(())
+        );
+        assert_eq!(
+            ballot(ProofVersion::Legacy, Some(&randomness)),
+            Err(IvrError::Internal(
+                "legacy proofs can't be proved for a given randomness".into()
+            ))
+        );
+
+        assert_eq!(
+            encrypt_ballot(
+                &public_keys(),
+                &[BigUint::from(9u32), BigUint::from(13u32)],
+                None,
+                ProofVersion::ContextBound,
+                &labels(),
+                "2022/10/13".into(),
+                SystemTime::now() - Duration::from_secs(1)
+            ),
+            Err(IvrError::Timeout)
+        );
+    }
+
+    #[test]
+    fn verify_ballots() {
+        let ballot = ballot(ProofVersion::ContextBound, None).unwrap();
+        let verify = |ballot: &EncryptedVote| {
+            verify_ballot(
+                &public_keys(),
+                ballot,
+                ProofVersion::ContextBound,
+                &labels()
+            )
+        };
+
+        let mut missing_proof = ballot.clone();
+        missing_proof.proofs.pop();
+        assert_eq!(
+            verify(&missing_proof),
+            Err(IvrError::InvalidBallot(
+                "choices: 2 choices and 1 proofs for 2 questions".into()
+            ))
+        );
+        let mut swapped_alphas = ballot.clone();
+        swapped_alphas.choices[1].alpha = ballot.choices[0].alpha.clone();
+        assert_eq!(
+            verify(&swapped_alphas),
+            Err(IvrError::InvalidBallot(
+                "choices[1]: proof verification failed".into()
+            ))
+        );
+        // the proofs are bound to the label of their question
+        let mut swapped_choices = ballot.clone();
+        swapped_choices.choices.swap(0, 1);
+        swapped_choices.proofs.swap(0, 1);
+        assert_eq!(
+            verify(&swapped_choices),
+            Err(IvrError::InvalidBallot(
+                "choices[0]: proof verification failed".into()
+            ))
+        );
+        let mut invalid_beta = ballot;
+        invalid_beta.choices[0].beta = public_keys()[0].p.clone();
+        assert_eq!(
+            verify(&invalid_beta),
+            Err(IvrError::InvalidBallot(
+                "choices[0]: beta out of range".into()
+            ))
+        );
+    }
+}
@@ -0,0 +1,595 @@
+// SPDX-FileCopyrightText: 2022 Eduardo Robles <edu@sequentech.io>
+//
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Models of the election returned by the Sequent backend in the
+//! `GET_ELECTION_URL` call.
+//!
+//! The election `configuration` and its public keys (`pks`) are JSON
+//! documents that the backend might embed as strings in the response. Fields
+//! that can't be parsed or are inconsistent produce an
+//! `invalid-backend-response` error with the path of the field.
+
+use std::collections::HashSet;
+use std::fmt::Display;
+use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use ivr_common::IvrError;
+
+/// ElGamal public key of a question, with the group parameters, as decimal
+/// strings.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PublicKeyStrings {
+    pub q: String,
+    pub p: String,
+    pub y: String,
+    pub g: String
+}
+
+/// State of an election in the backend.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ElectionState {
+    Registered,
+    Created,
+    CreatedError,
+    Started,
+    Suspended,
+    Resumed,
+    Stopped,
+    DoingTally,
+    TallyOk,
+    TallyError,
+    ResultsOk,
+    ResultsPub,
+    /// A state this lambda doesn't know about.
+    #[serde(other)]
+    Unknown,
+}
+
+impl ElectionState {
+    /// Whether the election accepts votes.
+    pub fn is_open(&self) -> bool {
+        matches!(self, ElectionState::Started | ElectionState::Resumed)
+    }
+}
+
+/// Start or end date of an election, either as text or as a timestamp in
+/// milliseconds.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum ElectionDate {
+    Text(String),
+    Timestamp(i64),
+}
+
+/// Formats of the text dates without a time zone, which are in UTC.
+const NAIVE_DATE_FORMATS: [&str; 2] = [
+    "%Y-%m-%dT%H:%M:%S%.f",
+    "%Y-%m-%d %H:%M:%S%.f",
+];
+
+impl ElectionDate {
+    /// Returns the date in UTC. Text dates are either in RFC 3339 or, as
+    /// stored by the backend, without a time zone. `path` is the path of the
+    /// date in the response, used in the error details.
+    pub fn to_datetime(&self, path: &str) -> Result<DateTime<Utc>, IvrError> {
+        match self {
+            ElectionDate::Text(text) => {
+                if let Ok(date) = DateTime::parse_from_rfc3339(text) {
+                    return Ok(date.with_timezone(&Utc));
+                }
+                NAIVE_DATE_FORMATS
+                    .iter()
+                    .find_map(|format| {
+                        NaiveDateTime::parse_from_str(text, format).ok()
+                    })
+                    .map(|date| Utc.from_utc_datetime(&date))
+                    .ok_or_else(|| {
+                        invalid(path, format!("invalid date {}", text))
+                    })
+            },
+            ElectionDate::Timestamp(timestamp) => Utc
+                .timestamp_millis_opt(*timestamp)
+                .single()
+                .ok_or_else(|| {
+                    invalid(path, format!("invalid timestamp {}", timestamp))
+                }),
+        }
+    }
+}
+
+/// A link of an answer. The voting booth also uses them to flag special
+/// answers.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct AnswerUrl {
+    pub title: String,
+    pub url: String,
+}
+
+/// Title of the link that flags the answer the voting booth chooses for
+/// explicit null votes.
+const INVALID_VOTE_FLAG: &str = "invalidVoteFlag";
+
+/// A possible answer of a question.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct Answer {
+    pub id: u32,
+    pub text: String,
+    #[serde(default)]
+    pub category: String,
+    #[serde(default)]
+    pub details: String,
+    #[serde(default)]
+    pub sort_order: i64,
+    #[serde(default)]
+    pub urls: Vec<AnswerUrl>,
+}
+
+impl Answer {
+    /// Whether the answer is not a real option, but the one chosen by the
+    /// voting booth for explicit null votes.
+    pub fn is_invalid_vote_flag(&self) -> bool {
+        self.urls.iter().any(|url| url.title == INVALID_VOTE_FLAG)
+    }
+}
+
+/// A question of the ballot.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct Question {
+    pub title: String,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub layout: String,
+    /// Minimum number of answers the voter has to choose.
+    pub min: u32,
+    /// Maximum number of answers the voter can choose.
+    pub max: u32,
+    #[serde(default)]
+    pub num_winners: u32,
+    /// Tally method, for example `plurality-at-large`.
+    pub tally_type: String,
+    pub answers: Vec<Answer>,
+    #[serde(default)]
+    pub extra_options: Option<QuestionExtraOptions>,
+}
+
+/// Extra options of a question. Only the ones used by the lambda are parsed.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct QuestionExtraOptions {
+    /// Whether null votes are allowed: `allowed` (default), `warn`,
+    /// `warn-invalid-implicit-and-explicit` or `not-allowed`.
+    #[serde(default)]
+    pub invalid_vote_policy: Option<String>,
+}
+
+/// Tally methods where the voter chooses answers without ranking them.
+const PLURALITY_TALLY_TYPES: [&str; 1] = ["plurality-at-large"];
+
+/// Tally methods where the voter ranks the chosen answers. Other methods, such
+/// as `cumulative`, are not supported by the lambda.
+const PREFERENTIAL_TALLY_TYPES: [&str; 7] = [
+    "borda",
+    "borda-nauru",
+    "borda-mas-madrid",
+    "desborda",
+    "desborda2",
+    "desborda3",
+    "pairwise-beta",
+];
+
+impl Question {
+    /// Whether the order of the chosen answers matters, i.e. the voter ranks
+    /// them.
+    pub fn is_preferential(&self) -> bool {
+        PREFERENTIAL_TALLY_TYPES.contains(&self.tally_type.as_str())
+    }
+
+    /// Whether the lambda can encode votes for the tally method of the
+    /// question.
+    pub fn is_supported(&self) -> bool {
+        self.is_preferential()
+            || PLURALITY_TALLY_TYPES.contains(&self.tally_type.as_str())
+    }
+
+    /// Returns the answer flagged for explicit null votes, if any.
+    pub fn invalid_vote_answer(&self) -> Option<&Answer> {
+        self.answers.iter().find(|answer| answer.is_invalid_vote_flag())
+    }
+
+    /// Whether the voter can cast a null (spoiled) vote.
+    pub fn allows_null_vote(&self) -> bool {
+        let policy = self
+            .extra_options
+            .as_ref()
+            .and_then(|options| options.invalid_vote_policy.as_deref());
+        policy != Some("not-allowed")
+    }
+}
+
+/// Configuration of an election, as created by the election administrator.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct ElectionConfig {
+    pub id: u64,
+    pub title: String,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub layout: String,
+    pub questions: Vec<Question>,
+}
+
+/// An election.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Election {
+    pub id: u64,
+    pub state: ElectionState,
+    pub start_date: Option<ElectionDate>,
+    pub end_date: Option<ElectionDate>,
+    pub configuration: ElectionConfig,
+    /// Public keys of the questions, in the same order. Empty until the
+    /// election keys are created.
+    pub pks: Vec<PublicKeyStrings>,
+}
+
+#[derive(Deserialize)]
+struct ElectionResponse {
+    payload: ElectionPayload,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ElectionPayload {
+    id: u64,
+    state: ElectionState,
+    #[serde(default)]
+    start_date: Option<ElectionDate>,
+    #[serde(default)]
+    end_date: Option<ElectionDate>,
+    configuration: Value,
+    #[serde(default)]
+    pks: Value,
+}
+
+fn invalid(path: &str, details: impl Display) -> IvrError {
+    IvrError::InvalidBackendResponse(format!("{}: {}", path, details))
+}
+
+/// Parses a field with a JSON document, either embedded as a string or not.
+fn parse_embedded<T: DeserializeOwned>(value: Value, path: &str)
+    -> Result<T, IvrError>
+{
+    let result = match value {
+        Value::String(json) => serde_json::from_str(&json),
+        value => serde_json::from_value(value),
+    };
+    result.map_err(|error| invalid(path, error))
+}
+
+impl Election {
+    /// Parses the body of the `GET_ELECTION_URL` response and checks that the
+    /// election is consistent.
+    pub fn parse(body: &str) -> Result<Election, IvrError> {
+        let response: ElectionResponse = serde_json::from_str(body)
+            .map_err(|error| invalid("election", error))?;
+        let payload = response.payload;
+        let pks = match payload.pks {
+            Value::Null => vec![],
+            pks => parse_embedded(pks, "payload.pks")?,
+        };
+        let election = Election {
+            id: payload.id,
+            state: payload.state,
+            start_date: payload.start_date,
+            end_date: payload.end_date,
+            configuration: parse_embedded(
+                payload.configuration,
+                "payload.configuration"
+            )?,
+            pks,
+        };
+        election.check()?;
+        Ok(election)
+    }
+
+    /// Returns the start date of the election, if set.
+    pub fn start_datetime(&self) -> Result<Option<DateTime<Utc>>, IvrError> {
+        self.start_date
+            .as_ref()
+            .map(|date| date.to_datetime("payload.startDate"))
+            .transpose()
+    }
+
+    /// Returns the end date of the election, if set.
+    pub fn end_datetime(&self) -> Result<Option<DateTime<Utc>>, IvrError> {
+        self.end_date
+            .as_ref()
+            .map(|date| date.to_datetime("payload.endDate"))
+            .transpose()
+    }
+
+    /// Checks that the election accepts votes at `now`: it's started or
+    /// resumed, and `now` is between its start and end dates, if set.
+    /// Otherwise returns the `election-not-open` error.
+    pub fn check_open(&self, now: DateTime<Utc>) -> Result<(), IvrError> {
+        let not_started = matches!(
+            self.start_datetime()?,
+            Some(start_date) if start_date > now
+        );
+        let ended = matches!(
+            self.end_datetime()?,
+            Some(end_date) if end_date <= now
+        );
+        if !self.state.is_open() || not_started || ended {
+            return Err(IvrError::ElectionNotOpen);
+        }
+        Ok(())
+    }
+
+    fn check(&self) -> Result<(), IvrError> {
+        let dates = (self.start_datetime()?, self.end_datetime()?);
+        if let (Some(start_date), Some(end_date)) = dates {
+            if start_date > end_date {
+                return Err(invalid(
+                    "payload.endDate",
+                    format!("{} is before the start date", end_date)
+                ));
+            }
+        }
+        if self.configuration.id != self.id {
+            return Err(invalid(
+                "payload.configuration.id",
+                format!("{} is not the election id", self.configuration.id)
+            ));
+        }
+        if self.configuration.questions.is_empty() {
+            return Err(invalid("payload.configuration.questions", "empty"));
+        }
+        for (index, question) in self.configuration.questions.iter().enumerate() {
+            let path = format!("payload.configuration.questions[{}]", index);
+            if !question.is_supported() {
+                return Err(invalid(
+                    &format!("{}.tally_type", path),
+                    format!("unsupported tally type {}", question.tally_type)
+                ));
+            }
+            if question.answers.is_empty() {
+                return Err(invalid(&format!("{}.answers", path), "empty"));
+            }
+            let mut answer_ids: HashSet<u32> = HashSet::new();
+            for (answer_index, answer) in question.answers.iter().enumerate() {
+                // the voting booth and the tally expect ids from 0 to the
+                // number of answers
+                if answer.id as usize >= question.answers.len() {
+                    return Err(invalid(
+                        &format!("{}.answers[{}].id", path, answer_index),
+                        format!("answer id {} out of range", answer.id)
+                    ));
+                }
+                if !answer_ids.insert(answer.id) {
+                    return Err(invalid(
+                        &format!("{}.answers[{}].id", path, answer_index),
+                        format!("duplicated answer id {}", answer.id)
+                    ));
+                }
+            }
+            if question.min > question.max {
+                return Err(invalid(
+                    &format!("{}.min", path),
+                    format!("{} is greater than max {}", question.min, question.max)
+                ));
+            }
+            if question.max as usize > question.answers.len() {
+                return Err(invalid(
+                    &format!("{}.max", path),
+                    format!(
+                        "{} is greater than the number of answers {}",
+                        question.max,
+                        question.answers.len()
+                    )
+                ));
+            }
+        }
+        if self.state.is_open()
+            && self.pks.len() != self.configuration.questions.len()
+        {
+            return Err(invalid(
+                "payload.pks",
+                format!(
+                    "{} public keys for {} questions",
+                    self.pks.len(),
+                    self.configuration.questions.len()
+                )
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{DateTime, Utc};
+    use serde_json::Value;
+    use ivr_common::IvrError;
+    use crate::election::{
+        Election, ElectionDate, ElectionState, PublicKeyStrings
+    };
+
+    const ELECTION: &str = include_str!("../test/mock_backend/election.json");
+
+    fn utc(date: &str) -> DateTime<Utc> {
+        date.parse().unwrap()
+    }
+
+    fn parse_modified<F: Fn(&mut Value)>(modify: F) -> Result<Election, IvrError> {
+        let mut body: Value = serde_json::from_str(ELECTION).unwrap();
+        modify(&mut body);
+        Election::parse(&body.to_string())
+    }
+
+    #[test]
+    fn parse_election() {
+        let election = Election::parse(ELECTION).unwrap();
+        assert_eq!(election.id, 17);
+        assert_eq!(election.state, ElectionState::Started);
+        assert_eq!(election.configuration.title, "Referendum");
+        let question = &election.configuration.questions[0];
+        assert_eq!((question.min, question.max), (0, 1));
+        assert_eq!(question.answers[1].text, "No");
+        assert_eq!(
+            election.pks,
+            vec![PublicKeyStrings {
+                q: "11".into(),
+                p: "23".into(),
+                y: "12".into(),
+                g: "4".into(),
+            }]
+        );
+
+        // the configuration might also be embedded as a string
+        let embedded = parse_modified(|body| {
+            let configuration = body["payload"]["configuration"].to_string();
+            body["payload"]["configuration"] = configuration.into();
+        });
+        assert_eq!(embedded, Ok(election));
+    }
+
+    #[test]
+    fn invalid_election() {
+        assert_eq!(
+            parse_modified(|body| {
+                body["payload"]["configuration"]["questions"][0]["answers"][1]["id"] = 0.into();
+            }),
+            Err(IvrError::InvalidBackendResponse(
+                "payload.configuration.questions[0].answers[1].id: duplicated answer id 0".into()
+            ))
+        );
+        assert_eq!(
+            parse_modified(|body| {
+                body["payload"]["configuration"]["questions"][0]["max"] = 3.into();
+            }),
+            Err(IvrError::InvalidBackendResponse(
+                "payload.configuration.questions[0].max: 3 is greater than the number of answers 2".into()
+            ))
+        );
+        assert_eq!(
+            parse_modified(|body| body["payload"]["pks"] = "[]".into()),
+            Err(IvrError::InvalidBackendResponse(
+                "payload.pks: 0 public keys for 1 questions".into()
+            ))
+        );
+        let missing_title = parse_modified(|body| {
+            body["payload"]["configuration"]
+                .as_object_mut()
+                .unwrap()
+                .remove("title");
+        });
+        assert!(matches!(
+            missing_title,
+            Err(IvrError::InvalidBackendResponse(details))
+                if details.starts_with("payload.configuration: missing field `title`")
+        ));
+
+        assert_eq!(
+            parse_modified(|body| {
+                body["payload"]["configuration"]["questions"][0]["tally_type"] = "cumulative".into();
+            }),
+            Err(IvrError::InvalidBackendResponse(
+                "payload.configuration.questions[0].tally_type: unsupported tally type cumulative".into()
+            ))
+        );
+
+        // the keys are not needed until the election starts
+        let created = parse_modified(|body| {
+            body["payload"]["state"] = "created".into();
+            body["payload"]["pks"] = Value::Null;
+        }).unwrap();
+        assert!(!created.state.is_open());
+        assert!(created.pks.is_empty());
+    }
+
+    #[test]
+    fn tally_types() {
+        let election = Election::parse(ELECTION).unwrap();
+        let mut question = election.configuration.questions[0].clone();
+        assert!(question.is_supported() && !question.is_preferential());
+        question.tally_type = "desborda3".into();
+        assert!(question.is_supported() && question.is_preferential());
+        for tally_type in ["cumulative", "borda-custom", ""] {
+            question.tally_type = tally_type.into();
+            assert!(!question.is_supported() && !question.is_preferential());
+        }
+    }
+
+    #[test]
+    fn election_dates() {
+        let date = |date: ElectionDate| date.to_datetime("payload.startDate");
+        let expected = utc("2022-10-13T09:00:00Z");
+        assert_eq!(
+            date(ElectionDate::Text("2022-10-13T09:00:00.000".into())),
+            Ok(expected)
+        );
+        assert_eq!(
+            date(ElectionDate::Text("2022-10-13 09:00:00".into())),
+            Ok(expected)
+        );
+        assert_eq!(
+            date(ElectionDate::Text("2022-10-13T11:00:00+02:00".into())),
+            Ok(expected)
+        );
+        assert_eq!(
+            date(ElectionDate::Timestamp(expected.timestamp_millis())),
+            Ok(expected)
+        );
+        assert_eq!(
+            date(ElectionDate::Text("next monday".into())),
+            Err(IvrError::InvalidBackendResponse(
+                "payload.startDate: invalid date next monday".into()
+            ))
+        );
+        assert_eq!(
+            parse_modified(|body| {
+                body["payload"]["endDate"] = "2022-10-12T09:00:00.000".into();
+            }),
+            Err(IvrError::InvalidBackendResponse(
+                "payload.endDate: 2022-10-12 09:00:00 UTC is before the start \
+                date".into()
+            ))
+        );
+    }
+
+    #[test]
+    fn open_election() {
+        let election = Election::parse(ELECTION).unwrap();
+        let open = utc("2022-10-14T00:00:00Z");
+        assert_eq!(election.check_open(open), Ok(()));
+        let before_start = utc("2022-10-13T08:00:00Z");
+        assert_eq!(
+            election.check_open(before_start),
+            Err(IvrError::ElectionNotOpen)
+        );
+        let after_end = utc("2099-10-20T09:00:00Z");
+        assert_eq!(
+            election.check_open(after_end),
+            Err(IvrError::ElectionNotOpen)
+        );
+
+        let stopped = Election {
+            state: ElectionState::Stopped,
+            ..election.clone()
+        };
+        assert_eq!(stopped.check_open(open), Err(IvrError::ElectionNotOpen));
+
+        // elections without dates are open while started or resumed
+        let undated = Election {
+            start_date: None,
+            end_date: None,
+            state: ElectionState::Resumed,
+            ..election
+        };
+        assert_eq!(undated.check_open(after_end), Ok(()));
+    }
+}
@@ -0,0 +1,594 @@
+// SPDX-FileCopyrightText: 2022 Eduardo Robles <edu@sequentech.io>
+//
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Encoding of the voter choices into ballot plaintexts, with the same scheme
+//! as the Sequent voting booth so that the tally can decode them.
+//!
+//! The choices of a question are encoded as the decimal number formed by
+//! concatenating `id + 1` of every chosen answer, zero-padded to the number of
+//! digits of the number of answers plus 2. For example, with 10 answers every
+//! choice takes 2 digits, and choosing the answers with ids 0 and 9 is encoded
+//! as `0110`.
+//!
+//! Voters choose several answers of a question by separating them with `,` or
+//! with the DTMF `*` key, for example `1*3`. If the question has less than 10
+//! answers, the positions can also be typed without separators, for example
+//! `3142`. Plurality questions don't depend on the order of the choices, so
+//! the chosen ids are encoded in ascending order, while preferential questions
+//! are encoded in the order of the voter ranking.
+//!
+//! The tally (`parse_vote` of agora-tally) decodes every group of digits as
+//! the answer id plus 1, counting a blank vote for the number of answers plus 2
+//! and an invalid vote for any other number that isn't an answer. So blank
+//! votes, which don't choose any answer, are encoded as the number of answers
+//! plus 2, and null (spoiled) votes as the number of answers plus 1.
+//!
+//! Questions can also have an answer flagged with an `invalidVoteFlag` link,
+//! which the voting booth chooses for explicit null votes instead. The flagged
+//! answer is then the encoding of the null votes, and voters can't choose it as
+//! a regular answer.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use num_bigint::BigUint;
+use serde::Deserialize;
+use ivr_common::IvrError;
+
+use crate::election::{Answer, Question};
+
+/// A vote that doesn't choose any answer.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SpecialVote {
+    Blank,
+    Null,
+}
+
+/// What an alias of the voter choice stands for: the id of an answer, or
+/// `blank` or `null`.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(untagged)]
+pub enum VoteAlias {
+    Answer(u32),
+    Special(SpecialVote),
+}
+
+/// Aliases of the answers by election id, or `default` for all the
+/// elections: map from a word spoken by the voter or DTMF digits to what it
+/// stands for.
+pub type VoteAliases = HashMap<String, HashMap<String, VoteAlias>>;
+
+/// The choice of the voter in a question.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Selection {
+    /// Ids of the chosen answers, in the order they are encoded.
+    Answers(Vec<u32>),
+    Blank,
+    Null,
+}
+
+fn normalize(text: &str) -> String {
+    text.trim().to_lowercase()
+}
+
+/// Returns the aliases of an election, which override the default ones, with
+/// normalized keys.
+pub fn election_aliases(aliases: &VoteAliases, election_id: &str)
+    -> BTreeMap<String, VoteAlias>
+{
+    ["default", election_id]
+        .iter()
+        .filter_map(|key| aliases.get(*key))
+        .flat_map(|election_aliases| election_aliases.iter())
+        .map(|(alias, answer_id)| (normalize(alias), *answer_id))
+        .collect()
+}
+
+/// Finds the answer chosen by the voter in the `vote` text, which is either:
+/// - one of the `aliases`,
+/// - the text of the answer, ignoring case, or
+/// - the 1-based position of the answer in the question, sorted as in the
+///   voting booth.
+pub fn find_answer<'a>(
+    question: &'a Question,
+    aliases: &BTreeMap<String, VoteAlias>,
+    vote: &str
+) -> Result<&'a Answer, IvrError>
+{
+    let vote = normalize(vote);
+    // the answer flagged for null votes is not shown by the voting booth
+    let mut answers = question
+        .answers
+        .iter()
+        .filter(|answer| !answer.is_invalid_vote_flag());
+    let answer = match aliases.get(&vote) {
+        Some(VoteAlias::Special(_)) => None,
+        Some(VoteAlias::Answer(answer_id)) => {
+            answers.find(|answer| answer.id == *answer_id)
+        },
+        None => answers
+            .clone()
+            .find(|answer| normalize(&answer.text) == vote)
+            .or_else(|| {
+                let mut sorted_answers: Vec<&Answer> = answers.collect();
+                sorted_answers.sort_by_key(|answer| answer.sort_order);
+                vote.parse::<usize>()
+                    .ok()
+                    .and_then(|position| position.checked_sub(1))
+                    .and_then(|index| sorted_answers.get(index).copied())
+            }),
+    };
+    answer.ok_or(IvrError::InvalidVote(vote))
+}
+
+/// Characters separating the answers chosen in a question.
+const SEPARATORS: [char; 2] = [',', '*'];
+
+/// Splits the `vote` text into the choices of the voter.
+fn split_choices(
+    question: &Question,
+    aliases: &BTreeMap<String, VoteAlias>,
+    vote: &str
+) -> Vec<String>
+{
+    let vote = vote.trim();
+    let digits_only = !vote.is_empty()
+        && vote.chars().all(|character| character.is_ascii_digit());
+    // with less than 10 answers every position is a single digit
+    if digits_only
+        && question.answers.len() < 10
+        && !aliases.contains_key(vote)
+    {
+        vote.chars().map(String::from).collect()
+    } else {
+        vote.split(SEPARATORS)
+            .map(str::trim)
+            .filter(|choice| !choice.is_empty())
+            .map(String::from)
+            .collect()
+    }
+}
+
+/// Finds the answers chosen by the voter in the `vote` text of the question
+/// number `question_number` (starting at 1), checking that their number is
+/// within the question limits and that no answer is chosen twice.
+///
+/// An empty vote, or one without answers, is a blank vote, which is only
+/// allowed if the question doesn't require choosing any answer. Blank and
+/// null votes can also be chosen with the `aliases`, and null votes are only
+/// allowed if the question allows them.
+pub fn find_selection(
+    question_number: usize,
+    question: &Question,
+    aliases: &BTreeMap<String, VoteAlias>,
+    vote: &str
+) -> Result<Selection, IvrError>
+{
+    let special = match aliases.get(&normalize(vote)) {
+        Some(VoteAlias::Special(special)) => Some(*special),
+        _ => None,
+    };
+    if special == Some(SpecialVote::Null) {
+        if !question.allows_null_vote() {
+            return Err(IvrError::InvalidVote("null vote not allowed".into()));
+        }
+        return Ok(Selection::Null);
+    }
+    let mut answer_ids: Vec<u32> = vec![];
+    let choices = match special {
+        Some(_) => vec![],
+        None => split_choices(question, aliases, vote),
+    };
+    for choice in choices {
+        let answer = find_answer(question, aliases, &choice)?;
+        if answer_ids.contains(&answer.id) {
+            return Err(IvrError::InvalidVote(format!(
+                "answer {} chosen twice",
+                choice
+            )));
+        }
+        answer_ids.push(answer.id);
+    }
+    let selected = answer_ids.len();
+    if selected < question.min as usize || selected > question.max as usize {
+        return Err(IvrError::InvalidSelectionCount {
+            question: question_number,
+            selected,
+            min: question.min,
+            max: question.max,
+        });
+    }
+    if answer_ids.is_empty() {
+        return Ok(Selection::Blank);
+    }
+    if !question.is_preferential() {
+        answer_ids.sort_unstable();
+    }
+    Ok(Selection::Answers(answer_ids))
+}
+
+/// Returns the votes of the questions of the ballot, in the same order, from
+/// the `Vote_1`..`Vote_n` contact attributes. Ballots with a single question
+/// can also use the `Vote` attribute.
+pub fn question_votes<'a>(
+    attributes: &'a HashMap<String, String>,
+    questions: &[Question]
+) -> Result<Vec<&'a String>, IvrError>
+{
+    (1..=questions.len())
+        .map(|number| {
+            let name = format!("Vote_{}", number);
+            match attributes.get(&name) {
+                Some(vote) => Ok(vote),
+                None => match attributes.get("Vote") {
+                    Some(vote) if questions.len() == 1 => Ok(vote),
+                    _ => Err(IvrError::MissingAttribute(name)),
+                },
+            }
+        })
+        .collect()
+}
+
+/// Encodes the ids of the answers chosen by the voter in a question, in order.
+pub fn encode_answers(question: &Question, answer_ids: &[u32])
+    -> Result<BigUint, IvrError>
+{
+    let digits = (question.answers.len() + 2).to_string().len();
+    let mut chosen: HashSet<u32> = HashSet::new();
+    let mut encoded = String::new();
+    for answer_id in answer_ids.iter() {
+        let is_answer = question.answers.iter().any(|answer| {
+            answer.id == *answer_id && !answer.is_invalid_vote_flag()
+        });
+        if !is_answer || !chosen.insert(*answer_id) {
+            return Err(IvrError::InvalidVote(answer_id.to_string()));
+        }
+        encoded.push_str(&format!("{:0digits$}", answer_id + 1));
+    }
+    BigUint::parse_bytes(encoded.as_bytes(), 10)
+        .ok_or_else(|| IvrError::InvalidVote("no answers".into()))
+}
+
+/// Encodes the choice of the voter in a question.
+pub fn encode_selection(question: &Question, selection: &Selection)
+    -> Result<BigUint, IvrError>
+{
+    match selection {
+        Selection::Answers(answer_ids) => encode_answers(question, answer_ids),
+        Selection::Blank => Ok(BigUint::from(question.answers.len() + 2)),
+        Selection::Null => match question.invalid_vote_answer() {
+            Some(answer) => Ok(BigUint::from(answer.id + 1)),
+            None => Ok(BigUint::from(question.answers.len() + 1)),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use num_bigint::BigUint;
+    use ivr_common::IvrError;
+    use crate::election::{
+        Answer, AnswerUrl, Question, QuestionExtraOptions
+    };
+    use crate::encoding::{
+        election_aliases, encode_answers, encode_selection, find_answer,
+        find_selection, question_votes, Selection, SpecialVote, VoteAlias
+    };
+
+    fn question(answers: &[&str]) -> Question {
+        Question {
+            title: "Question".into(),
+            description: "".into(),
+            layout: "simple".into(),
+            min: 0,
+            max: 1,
+            num_winners: 1,
+            tally_type: "plurality-at-large".into(),
+            answers: answers
+                .iter()
+                .enumerate()
+                .map(|(id, text)| Answer {
+                    id: id as u32,
+                    text: text.to_string(),
+                    category: "".into(),
+                    details: "".into(),
+                    // sorted in reverse order
+                    sort_order: -(id as i64),
+                    urls: vec![],
+                })
+                .collect(),
+            extra_options: None,
+        }
+    }
+
+    #[test]
+    fn encode() {
+        let yes_no = question(&["Yes", "No", "Abstention"]);
+        assert_eq!(encode_answers(&yes_no, &[0]), Ok(BigUint::from(1u32)));
+        assert_eq!(encode_answers(&yes_no, &[2]), Ok(BigUint::from(3u32)));
+        assert_eq!(
+            encode_answers(&yes_no, &[3]),
+            Err(IvrError::InvalidVote("3".into()))
+        );
+
+        let ten_answers = question(&["A"; 10]);
+        assert_eq!(encode_answers(&ten_answers, &[9]), Ok(BigUint::from(10u32)));
+        assert_eq!(
+            encode_answers(&ten_answers, &[0, 9]),
+            Ok(BigUint::from(110u32))
+        );
+        assert_eq!(
+            encode_answers(&ten_answers, &[9, 9]),
+            Err(IvrError::InvalidVote("9".into()))
+        );
+    }
+
+    #[test]
+    fn find_answers() {
+        let yes_no = question(&["Yes", "No", "Abstention"]);
+        let aliases = election_aliases(
+            &HashMap::from([
+                (
+                    "default".into(),
+                    HashMap::from([("yeah".into(), VoteAlias::Answer(0))])
+                ),
+                (
+                    "17".into(),
+                    HashMap::from([("Nope".into(), VoteAlias::Answer(1))])
+                ),
+                (
+                    "18".into(),
+                    HashMap::from([("yeah".into(), VoteAlias::Answer(1))])
+                ),
+            ]),
+            "17"
+        );
+        let find = |vote| {
+            find_answer(&yes_no, &aliases, vote).map(|answer| answer.id)
+        };
+        assert_eq!(find("yeah"), Ok(0));
+        assert_eq!(find(" nope "), Ok(1));
+        assert_eq!(find("ABSTENTION"), Ok(2));
+        // positions follow the sort order of the answers
+        assert_eq!(find("1"), Ok(2));
+        assert_eq!(find("3"), Ok(0));
+        assert_eq!(find("4"), Err(IvrError::InvalidVote("4".into())));
+        assert_eq!(find("0"), Err(IvrError::InvalidVote("0".into())));
+        assert_eq!(find("maybe"), Err(IvrError::InvalidVote("maybe".into())));
+    }
+
+    #[test]
+    fn multiple_answers() {
+        let mut board = question(&["Alice", "Bob", "Carol", "Dave"]);
+        board.min = 1;
+        board.max = 2;
+        let aliases = election_aliases(&HashMap::new(), "17");
+        let find = |vote| find_selection(2, &board, &aliases, vote);
+        assert_eq!(find("carol, alice"), Ok(Selection::Answers(vec![0, 2])));
+        // positions follow the sort order of the answers
+        assert_eq!(find("1*3"), Ok(Selection::Answers(vec![1, 3])));
+        assert_eq!(find("bob"), Ok(Selection::Answers(vec![1])));
+        assert_eq!(
+            find("1*2*3"),
+            Err(IvrError::InvalidSelectionCount {
+                question: 2,
+                selected: 3,
+                min: 1,
+                max: 2,
+            })
+        );
+        assert_eq!(
+            find(" * "),
+            Err(IvrError::InvalidSelectionCount {
+                question: 2,
+                selected: 0,
+                min: 1,
+                max: 2,
+            })
+        );
+        assert_eq!(find("5"), Err(IvrError::InvalidVote("5".into())));
+
+        assert_eq!(find("31"), Ok(Selection::Answers(vec![1, 3])));
+        assert_eq!(
+            find("1*1"),
+            Err(IvrError::InvalidVote("answer 1 chosen twice".into()))
+        );
+    }
+
+    #[test]
+    fn ranked_answers() {
+        let mut ranking = question(&["Alice", "Bob", "Carol", "Dave"]);
+        ranking.tally_type = "borda".into();
+        ranking.max = 4;
+        let aliases = election_aliases(&HashMap::new(), "17");
+        let find = |vote| find_selection(1, &ranking, &aliases, vote);
+        // positions follow the sort order of the answers: Dave, Carol, Bob,
+        // Alice
+        assert_eq!(find("3142"), Ok(Selection::Answers(vec![1, 3, 0, 2])));
+        assert_eq!(find("alice, bob"), Ok(Selection::Answers(vec![0, 1])));
+        assert_eq!(
+            encode_selection(&ranking, &find("3142").unwrap()),
+            Ok(BigUint::from(2413u32))
+        );
+        assert_eq!(
+            find("3143"),
+            Err(IvrError::InvalidVote("answer 3 chosen twice".into()))
+        );
+        assert_eq!(find("3152"), Err(IvrError::InvalidVote("5".into())));
+    }
+
+    #[test]
+    fn blank_and_null_votes() {
+        let mut yes_no = question(&["Yes", "No", "Abstention"]);
+        let aliases = election_aliases(
+            &HashMap::from([(
+                "default".into(),
+                HashMap::from([
+                    ("none".into(), VoteAlias::Special(SpecialVote::Blank)),
+                    ("9".into(), VoteAlias::Special(SpecialVote::Null)),
+                ])
+            )]),
+            "17"
+        );
+        assert_eq!(
+            find_selection(1, &yes_no, &aliases, ""),
+            Ok(Selection::Blank)
+        );
+        assert_eq!(
+            find_selection(1, &yes_no, &aliases, "None"),
+            Ok(Selection::Blank)
+        );
+        assert_eq!(
+            find_selection(1, &yes_no, &aliases, "9"),
+            Ok(Selection::Null)
+        );
+        assert_eq!(
+            encode_selection(&yes_no, &Selection::Blank),
+            Ok(BigUint::from(5u32))
+        );
+        assert_eq!(
+            encode_selection(&yes_no, &Selection::Null),
+            Ok(BigUint::from(4u32))
+        );
+        // a special alias is not an answer
+        assert_eq!(
+            find_selection(1, &yes_no, &aliases, "1*none"),
+            Err(IvrError::InvalidVote("none".into()))
+        );
+
+        // blank votes are not allowed when an answer must be chosen
+        yes_no.min = 1;
+        assert_eq!(
+            find_selection(1, &yes_no, &aliases, "none"),
+            Err(IvrError::InvalidSelectionCount {
+                question: 1,
+                selected: 0,
+                min: 1,
+                max: 1,
+            })
+        );
+        yes_no.extra_options = Some(QuestionExtraOptions {
+            invalid_vote_policy: Some("not-allowed".into()),
+        });
+        assert_eq!(
+            find_selection(1, &yes_no, &aliases, "9"),
+            Err(IvrError::InvalidVote("null vote not allowed".into()))
+        );
+    }
+
+    /// Decodes a plaintext as `parse_vote` of agora-tally: every group of
+    /// digits is an answer id plus 1, the number of answers plus 2 is a blank
+    /// vote and any other id out of range is an invalid vote.
+    fn parse_vote(question: &Question, plaintext: &BigUint)
+        -> Result<Vec<u32>, &'static str>
+    {
+        let answers = question.answers.len();
+        let tab_size = (answers + 2).to_string().len();
+        let vote = plaintext.to_string();
+        let zeros = (tab_size - vote.len() % tab_size) % tab_size;
+        let vote = format!("{}{}", "0".repeat(zeros), vote);
+        let mut options = vec![];
+        for index in (0..vote.len()).step_by(tab_size) {
+            let digits = &vote[index..index + tab_size];
+            let option = digits.parse::<i64>().unwrap() - 1;
+            if option == answers as i64 + 1 {
+                return Err("blank");
+            }
+            if option < 0 || option >= answers as i64 {
+                return Err("invalid");
+            }
+            options.push(option as u32);
+        }
+        Ok(options)
+    }
+
+    #[test]
+    fn tally_reference_vectors() {
+        let yes_no = question(&["Yes", "No", "Abstention"]);
+        let ten_answers = question(&["A"; 10]);
+        let cases = [
+            (&yes_no, Selection::Answers(vec![2]), "3", Ok(vec![2])),
+            (&yes_no, Selection::Blank, "5", Err("blank")),
+            (&yes_no, Selection::Null, "4", Err("invalid")),
+            (
+                &ten_answers,
+                Selection::Answers(vec![0, 9]),
+                "110",
+                Ok(vec![0, 9])
+            ),
+            (&ten_answers, Selection::Blank, "12", Err("blank")),
+            (&ten_answers, Selection::Null, "11", Err("invalid")),
+        ];
+        for (question, selection, encoded, parsed) in cases {
+            let plaintext = encode_selection(question, &selection).unwrap();
+            assert_eq!(plaintext.to_string(), encoded, "{:?}", selection);
+            assert_eq!(parse_vote(question, &plaintext), parsed);
+        }
+    }
+
+    #[test]
+    fn invalid_vote_flag() {
+        let mut yes_no = question(&["Yes", "No", "Null"]);
+        yes_no.answers[2].urls = vec![AnswerUrl {
+            title: "invalidVoteFlag".into(),
+            url: "true".into(),
+        }];
+        let aliases = election_aliases(
+            &HashMap::from([(
+                "default".into(),
+                HashMap::from([
+                    ("9".into(), VoteAlias::Special(SpecialVote::Null)),
+                    ("nul".into(), VoteAlias::Answer(2)),
+                ])
+            )]),
+            "17"
+        );
+        let find = |vote| find_selection(1, &yes_no, &aliases, vote);
+        assert_eq!(find("9"), Ok(Selection::Null));
+        assert_eq!(
+            encode_selection(&yes_no, &Selection::Null),
+            Ok(BigUint::from(3u32))
+        );
+
+        // the flagged answer can't be chosen as a regular one, and positions
+        // skip it: No, Yes
+        assert_eq!(find("null"), Err(IvrError::InvalidVote("null".into())));
+        assert_eq!(find("nul"), Err(IvrError::InvalidVote("nul".into())));
+        assert_eq!(find("1"), Ok(Selection::Answers(vec![1])));
+        assert_eq!(find("3"), Err(IvrError::InvalidVote("3".into())));
+        assert_eq!(
+            encode_answers(&yes_no, &[2]),
+            Err(IvrError::InvalidVote("2".into()))
+        );
+    }
+
+    #[test]
+    fn votes_by_question() {
+        let questions = vec![question(&["Yes", "No"]), question(&["A", "B"])];
+        let attributes = HashMap::from([
+            ("Vote".to_string(), "yes".to_string()),
+            ("Vote_1".to_string(), "no".to_string()),
+            ("Vote_2".to_string(), "b".to_string()),
+        ]);
+        assert_eq!(question_votes(&attributes, &questions), Ok(vec![
+            &"no".to_string(),
+            &"b".to_string(),
+        ]));
+        assert_eq!(
+            question_votes(&attributes, &questions[..1]),
+            Ok(vec![&"no".to_string()])
+        );
+
+        // a single question can use the Vote attribute
+        let single = HashMap::from([("Vote".to_string(), "yes".to_string())]);
+        assert_eq!(
+            question_votes(&single, &questions[..1]),
+            Ok(vec![&"yes".to_string()])
+        );
+        assert_eq!(
+            question_votes(&single, &questions),
+            Err(IvrError::MissingAttribute("Vote_1".into()))
+        );
+    }
+}
@@ -0,0 +1,293 @@
+// SPDX-FileCopyrightText: 2022 Eduardo Robles <edu@sequentech.io>
+//
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Groups of the election public keys supported by the lambda.
+//!
+//! The votes are encoded with the strand context of the group of the public
+//! key, so the `p`, `q` and `g` parameters published with the key must be
+//! exactly those of a supported context. Otherwise the ballots would be
+//! encoded in a different group and couldn't be tallied.
+//!
+//! Only the 2048-bit group of Sequent elections is supported. It's the only
+//! group of strand, at the pinned revision, where the keys have the `p`, `q`,
+//! `g` and `y` values published by the Sequent backend: the other backends of
+//! strand either implement the same group with a native library, unavailable
+//! in the lambda runtime, or use elliptic curves, whose keys can't be tallied
+//! by Sequent elections.
+//!
+//! The public key itself must also be an element of the prime-order subgroup.
+//! Otherwise, a key substituted by a compromised or misconfigured backend
+//! could leak information about the encrypted votes.
+
+use num_bigint::BigUint;
+use strand::backend::num_bigint_sha2::{BigintCtx, P2048};
+use strand::context::Ctx;
+use ivr_common::IvrError;
+
+use crate::election::PublicKeyStrings;
+
+/// Parameters of a group: the modulus `p`, the order `q` of the subgroup and
+/// its generator `g`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GroupParameters {
+    pub p: BigUint,
+    pub q: BigUint,
+    pub g: BigUint,
+}
+
+/// A group supported by the lambda, i.e. with a strand context to encrypt the
+/// votes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Group {
+    /// The 2048-bit group used by Sequent elections.
+    P2048,
+}
+
+/// Parses a decimal number published by the backend.
+pub fn parse_decimal(value: &str, path: &str) -> Result<BigUint, IvrError> {
+    BigUint::parse_bytes(value.trim().as_bytes(), 10).ok_or_else(|| {
+        IvrError::InvalidBackendResponse(format!("{}: not a number", path))
+    })
+}
+
+impl Group {
+    /// All the supported groups. See the module documentation for why there's
+    /// only one.
+    pub const ALL: [Group; 1] = [Group::P2048];
+
+    /// Returns the strand context of the group, used to encrypt the votes and
+    /// to prove and verify their proofs.
+    pub fn context(&self) -> BigintCtx<P2048> {
+        match self {
+            Group::P2048 => BigintCtx::<P2048>::new(),
+        }
+    }
+
+    /// Returns the parameters of the strand context of the group.
+    pub fn parameters(&self) -> GroupParameters {
+        let context = self.context();
+        // the parameters are constants of strand, so failing to parse them is
+        // a bug and not an error of the election
+        let parse = |value: String| {
+            BigUint::parse_bytes(value.as_bytes(), 10)
+                .expect("strand group parameters are decimal numbers")
+        };
+        GroupParameters {
+            p: parse(context.modulus().to_string_radix(10)),
+            q: parse(context.exp_modulus().to_string_radix(10)),
+            g: parse(context.generator().to_string_radix(10)),
+        }
+    }
+
+    /// Finds the supported group of a public key, returning the
+    /// `unsupported-group` error if there's none or if its parameters don't
+    /// match the group with the same modulus. `path` is the path of the key in
+    /// the election, used in the error details.
+    pub fn find(public_key: &PublicKeyStrings, path: &str)
+        -> Result<Group, IvrError>
+    {
+        let groups: Vec<(Group, GroupParameters)> = Group::ALL
+            .iter()
+            .map(|group| (*group, group.parameters()))
+            .collect();
+        find_group(public_key, path, &groups)
+    }
+}
+
+/// Checks that the `y` of a public key lies in the prime-order subgroup of its
+/// group, i.e. that `1 < y < p` and `y^q = 1 mod p`, returning the
+/// `invalid-public-key` error otherwise.
+pub fn check_public_key(public_key: &PublicKeyStrings, path: &str)
+    -> Result<(), IvrError>
+{
+    let p = parse_decimal(&public_key.p, &format!("{}.p", path))?;
+    let q = parse_decimal(&public_key.q, &format!("{}.q", path))?;
+    let y = parse_decimal(&public_key.y, &format!("{}.y", path))?;
+    let one = BigUint::from(1u32);
+    if y <= one || y >= p {
+        return Err(IvrError::InvalidPublicKey(format!(
+            "{}.y: out of range",
+            path
+        )));
+    }
+    if y.modpow(&q, &p) != one {
+        return Err(IvrError::InvalidPublicKey(format!(
+            "{}.y: not in the prime-order subgroup",
+            path
+        )));
+    }
+    Ok(())
+}
+
+/// Finds the group of a public key among `groups`.
+fn find_group(
+    public_key: &PublicKeyStrings,
+    path: &str,
+    groups: &[(Group, GroupParameters)]
+) -> Result<Group, IvrError>
+{
+    let p = parse_decimal(&public_key.p, &format!("{}.p", path))?;
+    let q = parse_decimal(&public_key.q, &format!("{}.q", path))?;
+    let g = parse_decimal(&public_key.g, &format!("{}.g", path))?;
+    let (group, parameters) = groups
+        .iter()
+        .find(|(_, parameters)| parameters.p == p)
+        .ok_or_else(|| IvrError::UnsupportedGroup(format!(
+            "{}.p: unsupported modulus of {} bits",
+            path,
+            p.bits()
+        )))?;
+    if parameters.q != q {
+        return Err(IvrError::UnsupportedGroup(format!(
+            "{}.q: doesn't match the {:?} group",
+            path,
+            group
+        )));
+    }
+    if parameters.g != g {
+        return Err(IvrError::UnsupportedGroup(format!(
+            "{}.g: doesn't match the {:?} group",
+            path,
+            group
+        )));
+    }
+    Ok(*group)
+}
+
+#[cfg(test)]
+mod tests {
+    use num_bigint::BigUint;
+    use ivr_common::IvrError;
+    use crate::election::PublicKeyStrings;
+    use crate::group::{check_public_key, find_group, Group, GroupParameters};
+
+    fn public_key(p: &str, q: &str, g: &str) -> PublicKeyStrings {
+        PublicKeyStrings {
+            p: p.into(),
+            q: q.into(),
+            g: g.into(),
+            y: "12".into(),
+        }
+    }
+
+    #[test]
+    fn check_public_keys() {
+        let check = |y: &str| {
+            let public_key = PublicKeyStrings {
+                y: y.into(),
+                ..public_key("23", "11", "4")
+            };
+            check_public_key(&public_key, "payload.pks[0]")
+        };
+        // 12 = 4^5 mod 23
+        assert_eq!(check("12"), Ok(()));
+        for y in ["0", "1", "23", "24"] {
+            assert_eq!(
+                check(y),
+                Err(IvrError::InvalidPublicKey(
+                    "payload.pks[0].y: out of range".into()
+                ))
+            );
+        }
+        // 5 generates the whole group, of order 22
+        assert_eq!(
+            check("5"),
+            Err(IvrError::InvalidPublicKey(
+                "payload.pks[0].y: not in the prime-order subgroup".into()
+            ))
+        );
+        assert_eq!(
+            check("y"),
+            Err(IvrError::InvalidBackendResponse(
+                "payload.pks[0].y: not a number".into()
+            ))
+        );
+    }
+
+    #[test]
+    fn find_p2048() {
+        let parameters = Group::P2048.parameters();
+        let one = BigUint::from(1u32);
+        assert_eq!(parameters.p.bits(), 2048);
+        assert_eq!((&parameters.p - &one) % &parameters.q, BigUint::default());
+        assert_ne!(parameters.g, one);
+        assert_eq!(parameters.g.modpow(&parameters.q, &parameters.p), one);
+
+        // the generator is a valid public key of the group
+        let p2048_key = |q: &BigUint, g: &BigUint| PublicKeyStrings {
+            p: parameters.p.to_string(),
+            q: q.to_string(),
+            g: g.to_string(),
+            y: parameters.g.to_string(),
+        };
+        let generator_key = p2048_key(&parameters.q, &parameters.g);
+        assert_eq!(
+            Group::find(&generator_key, "payload.pks[0]"),
+            Ok(Group::P2048)
+        );
+        assert_eq!(check_public_key(&generator_key, "payload.pks[0]"), Ok(()));
+
+        // other generators of the subgroup don't match
+        let g2 = parameters.g.modpow(&BigUint::from(2u32), &parameters.p);
+        assert_eq!(
+            Group::find(&p2048_key(&parameters.q, &g2), "payload.pks[0]"),
+            Err(IvrError::UnsupportedGroup(
+                "payload.pks[0].g: doesn't match the P2048 group".into()
+            ))
+        );
+        let order = &parameters.p - &one;
+        assert_eq!(
+            Group::find(&p2048_key(&order, &parameters.g), "payload.pks[0]"),
+            Err(IvrError::UnsupportedGroup(
+                "payload.pks[0].q: doesn't match the P2048 group".into()
+            ))
+        );
+        assert_eq!(
+            Group::find(&public_key("23", "11", "4"), "payload.pks[0]"),
+            Err(IvrError::UnsupportedGroup(
+                "payload.pks[0].p: unsupported modulus of 5 bits".into()
+            ))
+        );
+    }
+
+    #[test]
+    fn find_groups() {
+        let groups = [(
+            Group::P2048,
+            GroupParameters {
+                p: BigUint::from(23u32),
+                q: BigUint::from(11u32),
+                g: BigUint::from(4u32),
+            }
+        )];
+        let find = |public_key| {
+            find_group(&public_key, "payload.pks[1]", &groups)
+        };
+        assert_eq!(find(public_key("23", "11", "4")), Ok(Group::P2048));
+        assert_eq!(
+            find(public_key("47", "23", "4")),
+            Err(IvrError::UnsupportedGroup(
+                "payload.pks[1].p: unsupported modulus of 6 bits".into()
+            ))
+        );
+        assert_eq!(
+            find(public_key("23", "11", "2")),
+            Err(IvrError::UnsupportedGroup(
+                "payload.pks[1].g: doesn't match the P2048 group".into()
+            ))
+        );
+        assert_eq!(
+            find(public_key("23", "22", "4")),
+            Err(IvrError::UnsupportedGroup(
+                "payload.pks[1].q: doesn't match the P2048 group".into()
+            ))
+        );
+        assert_eq!(
+            find(public_key("23", "0x0b", "4")),
+            Err(IvrError::InvalidBackendResponse(
+                "payload.pks[1].q: not a number".into()
+            ))
+        );
+    }
+}
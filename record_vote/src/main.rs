@@ -8,45 +8,71 @@ use lambda_runtime::{run, service_fn, Error, LambdaEvent};
 use sha2::{Sha256, Digest};
 use std::env;
 use std::str::FromStr;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime};
 use serde_json::{json, Value};
 use serde::{Deserialize, Serialize};
 use num_bigint::BigUint;
-use strand::context::Ctx;
-use strand::backend::num_bigint_sha2::{BigintCtx, P2048};
-use strand::elgamal::PublicKey;
 use chrono::prelude::*;
 use tracing::{event, Level};
 
-use oxhttp::Client;
-use oxhttp::model::{Request, Method, Status, HeaderName};
+use ivr_common::IvrError;
+use ivr_common::config::{env_var, json_env_var, parse_env_var};
+use ivr_common::khmac::{unix_timestamp, KhmacToken};
+use ivr_common::connect::{context_deadline, get_attribute, to_response};
+use ivr_common::http::{BackendRequest, HttpClient};
 
-#[derive(Serialize, Deserialize)]
-pub struct PublicKeyStrings {
-    pub q: String,
-    pub p: String,
-    pub y: String,
-    pub g: String
-}
+mod audit;
+mod ballot;
+mod election;
+mod encoding;
+mod group;
+mod proof;
+mod public_key_cache;
+mod readback;
+use audit::{
+    audit_code, check_nonce, check_randomness, derive_randomness, new_nonce,
+    record_ballot_use, AuditedBallot, BallotUse
+};
+use ballot::{encrypt_ballot, verify_ballot, EncryptedVote};
+use election::{Election, PublicKeyStrings};
+use encoding::{
+    election_aliases, encode_selection, find_selection, question_votes,
+    Selection, VoteAlias, VoteAliases
+};
+use group::{check_public_key, parse_decimal, Group};
+use proof::{proof_label, ProofVersion};
+use public_key_cache::{CachePolicy, PublicKeyCache};
+use readback::{verbatim_ssml, vote_readback_ssml, ReadbackTexts};
 
-#[derive(Serialize, Deserialize)]
-pub struct PlaintextProof {
-    challenge: String,
-    commitment: String,
-    response: String
+/// What `record_vote` does with the vote, from the `VoteAction` contact
+/// attribute.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum VoteAction {
+    /// Cast the vote: the ballot committed to by a previous `Encrypt` action
+    /// if any, or the vote encrypted now.
+    Cast,
+    /// Only check the vote and read it back.
+    Preview,
+    /// Encrypt the vote and commit to the ballot, without casting it.
+    Encrypt,
+    /// Publish the committed ballot for auditing instead of casting it.
+    Audit,
 }
 
-#[derive(Serialize, Deserialize)]
-pub struct EncryptedChoice {
-    alpha: String,
-    beta: String
-}
+impl FromStr for VoteAction {
+    type Err = IvrError;
 
-#[derive(Serialize, Deserialize)]
-pub struct EncryptedVote {
-    choices: Vec<EncryptedChoice>,
-    issue_date: String,
-    proofs: Vec<PlaintextProof>
+    fn from_str(action: &str) -> Result<Self, Self::Err> {
+        match action {
+            "cast" => Ok(VoteAction::Cast),
+            "preview" => Ok(VoteAction::Preview),
+            "encrypt" => Ok(VoteAction::Encrypt),
+            "audit" => Ok(VoteAction::Audit),
+            _ => Err(IvrError::InvalidAttribute("VoteAction".into())),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -55,67 +81,103 @@ pub struct VoteRequest {
     vote_hash: String
 }
 
-fn get_public_key(client: &Client, get_election_url: &String) 
--> Result<PublicKey<BigintCtx::<P2048>>, Error>
+/// Fingerprint of a public key: the hex SHA-256 digest of its `p`, `q`, `g`
+/// and `y` values in decimal, separated by `/`.
+fn public_key_fingerprint(public_key: &PublicKeyStrings) -> String {
+    get_hash(&format!(
+        "{}/{}/{}/{}",
+        public_key.p,
+        public_key.q,
+        public_key.g,
+        public_key.y
+    ))
+}
+
+/// Fetches the election from the backend, returning it with the fingerprints
+/// of its public keys, separated by `,`. Elections that don't accept votes
+/// or with invalid keys are returned as an error, so that they are not
+/// cached. If `pinned_fingerprints` is set, the fingerprints of the keys must
+/// match them, in the same order.
+async fn get_election(
+    http_client: &HttpClient,
+    deadline: SystemTime,
+    headers: &[(String, String)],
+    election_id: &str,
+    get_election_url: &String,
+    pinned_fingerprints: Option<&Vec<String>>
+) -> Result<(Election, String), IvrError>
 {
     event!(
         Level::DEBUG,
         get_election_url = get_election_url,
     );
-    let response = client.request(
-        Request::builder(Method::GET, get_election_url.parse()?).build()
-    )?;
-
-    let status = response.status();
-    event!(Level::INFO, request_response_status = status.to_string());
-    
-    let body = response.into_body().to_string()?;
+    let request = BackendRequest::get(get_election_url, "GET_ELECTION_URL")?
+        .with_extra_headers(headers)?;
+    let (status, body) = http_client.send(deadline, &request).await?;
     event!(Level::INFO, request_response_body = body);
 
-    if ! status.is_successful() {
-        return Err("invalid-status".into());
+    if !(200..300).contains(&status) {
+        return Err(IvrError::InvalidStatus(status));
     }
 
-    let body_value: Value = serde_json::from_str(&body)?;
-    if !body_value.is_object()
-        || !body_value.as_object().unwrap()["payload"].is_object()
-        || !body_value.as_object().unwrap()["payload"].as_object().unwrap()["pks"].is_string()
-
-    {
-        return Err("invalid-election-body".into());
-    }
-
-    let public_key_string = body_value
-        .as_object()
-        .unwrap()["payload"]
-        .as_object()
-        .unwrap()["pks"]
-        .to_string()
-        .replace("\\", "");
-    // strip the initial and last chars.. (they are unneeded doble quotes)
-    let public_key_string = &public_key_string[1..public_key_string.len()-1];
-    event!(Level::DEBUG, "public_key_string='{}'", public_key_string);
-
-    let public_key_list: Vec<PublicKeyStrings> = 
-        serde_json::from_str(&public_key_string)?;
-    if public_key_list.len() != 1 {
-        return Err("more-than-one-public-key".into());
-    }
-    let public_key_obj = &public_key_list[0];
-    
-    let context = BigintCtx::<P2048>::new();
-    return Ok(
-        PublicKey::from_element(
-            &context.element_from_string_radix(&public_key_obj.y, 10)?,
-            &context
-        )
-    );
+    let election = Election::parse(&body)?;
+    if election.id.to_string() != election_id {
+        return Err(IvrError::InvalidBackendResponse(format!(
+            "payload.id: {} is not the requested election",
+            election.id
+        )));
+    }
+    check_election_open(&election)?;
+    // Keys in unsupported groups, outside the prime-order subgroup or
+    // different from the pinned ones are rejected before encrypting any vote
+    // with them
+    let mut fingerprints: Vec<String> = vec![];
+    for (index, public_key) in election.pks.iter().enumerate() {
+        let path = format!("payload.pks[{}]", index);
+        let group = Group::find(public_key, &path)?;
+        event!(Level::INFO, "public_key_group={:?}", group);
+        check_public_key(public_key, &path)?;
+        fingerprints.push(public_key_fingerprint(public_key));
+    }
+    let fingerprint = fingerprints.join(",");
+    event!(Level::INFO, public_key_fingerprint = fingerprint);
+    if let Some(pinned_fingerprints) = pinned_fingerprints {
+        let matches = pinned_fingerprints.len() == fingerprints.len()
+            && pinned_fingerprints
+                .iter()
+                .zip(&fingerprints)
+                .all(|(pinned, fingerprint)| {
+                    pinned.trim().eq_ignore_ascii_case(fingerprint)
+                });
+        if !matches {
+            event!(
+                Level::ERROR,
+                "pinned_public_key_fingerprints={:?}",
+                pinned_fingerprints
+            );
+            return Err(IvrError::InvalidPublicKey(
+                "payload.pks: fingerprint doesn't match the pinned one".into()
+            ));
+        }
+        event!(Level::INFO, "public key fingerprint pinned");
+    }
+    Ok((election, fingerprint))
 }
 
-fn get_voter_id(auth_token: &String) -> Result<String, Error> {
-    let (_, signed_data) = auth_token.rsplit_once('/').ok_or("")?;
-    let (voter_id, _) = signed_data.split_once(':').ok_or("")?;
-    return Ok(voter_id.into());
+/// Checks that the election accepts votes right now, logging its state and
+/// dates otherwise.
+fn check_election_open(election: &Election) -> Result<(), IvrError> {
+    let result = election.check_open(Utc::now());
+    if result.is_err() {
+        event!(
+            Level::WARN,
+            "election_state={:?} start_date={:?} end_date={:?}",
+            election.state,
+            election.start_date,
+            election.end_date
+        );
+    }
+    result
 }
 
 pub fn get_hash(data: &String) -> String {
@@ -125,118 +187,435 @@ pub fn get_hash(data: &String) -> String {
     return hex::encode(&hashed)
 }
 
-/// This is the main body for the function.
-/// Write your code inside it.
-/// There are some code example in the following URLs:
-/// - https://github.com/awslabs/aws-lambda-rust-runtime/tree/main/examples
-/// - https://github.com/aws-samples/serverless-rust-demo/
-async fn function_handler(event: LambdaEvent<ConnectEvent>) -> Result<Value, Error> {
+/// Encodes the vote of every question of the election from the contact
+/// attributes, returning the plaintexts of the questions with the SSML
+/// readback of the vote.
+fn encode_vote(
+    attributes: &HashMap<String, String>,
+    election: &Election,
+    aliases: &BTreeMap<String, VoteAlias>,
+    readback_texts: &ReadbackTexts
+) -> Result<(Vec<BigUint>, String), IvrError>
+{
+    let questions = &election.configuration.questions;
+    let votes = question_votes(attributes, questions)?;
+    event!(Level::DEBUG, "votes={:?}", votes);
+    let mut selections: Vec<Selection> = vec![];
+    let mut plaintexts: Vec<BigUint> = vec![];
+    for (index, (question, vote)) in questions.iter().zip(votes).enumerate() {
+        let selection = find_selection(index + 1, question, aliases, vote)?;
+        event!(
+            Level::DEBUG,
+            question = question.title,
+            "selection={:?}",
+            selection
+        );
+        plaintexts.push(encode_selection(question, &selection)?);
+        selections.push(selection);
+    }
+    let vote_readback_ssml =
+        vote_readback_ssml(questions, &selections, readback_texts);
+    event!(Level::DEBUG, vote_readback_ssml);
+    Ok((plaintexts, vote_readback_ssml))
+}
 
-    let (connect_event, connect_context) = event.into_parts();
-    event!(
-        Level::DEBUG,
-        connect_event = serde_json::to_string(&connect_event)?,
-        connect_context = serde_json::to_string(&connect_context)?
-    );
+/// Encrypts the vote provided in the contact attributes and casts it to the
+/// ballot box, returning the start of the ballot hash as SSML. The work is
+/// finished before the lambda deadline, if known, or the `timeout` error is
+/// returned.
+async fn record_vote(
+    http_client: &HttpClient,
+    public_key_cache: &PublicKeyCache<Election>,
+    connect_event: &ConnectEvent,
+    lambda_deadline: Option<SystemTime>
+) -> Result<Value, IvrError>
+{
+    let started_at = SystemTime::now();
+    let deadline = http_client
+        .config()
+        .timeouts
+        .deadline(lambda_deadline, started_at);
 
     // Example RECORD_VOTE_URL, where votes will be posted: 
     // https://clientname.example.com/elections/api/election/{{election_id}}/voter/{{voter_id}}
     // Note that:
     // - {{election_id}} will be substituted with the election id
     // - {{voter_id}} will be substituted with the voter id
-    let record_vote_url_template = env::var("RECORD_VOTE_URL")?;
+    let record_vote_url_template = env_var("RECORD_VOTE_URL")?;
     event!(Level::INFO, record_vote_url_template);
 
     // Example GET_ELECTION_URL, used to fetch election config:
     // https://clientname.example.com/elections/api/election/{{election_id}}
     // Note that {{election_id}} will be substituted with the election id
-    let get_election_url_template = env::var("GET_ELECTION_URL")?;
+    let get_election_url_template = env_var("GET_ELECTION_URL")?;
     event!(Level::INFO, get_election_url_template);
 
-    let vote_encoding_array_str = env::var("VOTE_ENCODING_ARRAY")?;
-    event!(Level::INFO, vote_encoding_array_str);
-
-    let vote_text: &String = connect_event
-        .details
-        .contact_data
-        .attributes
-        .get("Vote")
-        .ok_or(String::from("Vote contact data attribute missing"))?;
-    event!(Level::DEBUG, vote_text);
-
-    let auth_token: &String = connect_event
-        .details
-        .contact_data
-        .attributes
-        .get("AuthToken")
-        .ok_or(String::from("AuthToken contact data attribute missing"))?;
+    // Optional aliases of the answers by election id (or "default"), for
+    // example {"default": {"yes": 0, "no": 1, "none": "blank"}}. The vote is
+    // encoded from the answers of the election configuration
+    let vote_aliases: VoteAliases =
+        json_env_var("VOTE_ALIASES")?.unwrap_or_default();
+    event!(Level::INFO, "vote_aliases={:?}", vote_aliases);
+    if env::var("VOTE_ENCODING_ARRAY").is_ok() {
+        event!(
+            Level::WARN,
+            "VOTE_ENCODING_ARRAY is ignored, the vote encoding is derived \
+            from the election configuration"
+        );
+    }
+
+    // Time the elections and their public keys are cached before refreshing
+    // them. Set it to 0 to fetch them for every vote. While the backend is
+    // unavailable, expired keys are still used up to the maximum staleness
+    let public_key_cache_policy = CachePolicy {
+        ttl: Duration::from_secs(
+            parse_env_var("PUBLIC_KEY_CACHE_TTL_SECONDS")?.unwrap_or(300)
+        ),
+        max_staleness: Duration::from_secs(
+            parse_env_var("PUBLIC_KEY_CACHE_MAX_STALENESS_SECONDS")?
+                .unwrap_or(900)
+        ),
+    };
+    event!(
+        Level::INFO,
+        "public_key_cache_policy={:?}",
+        public_key_cache_policy
+    );
+
+    // Optional fingerprints of the public keys by election id, one per
+    // question, for example {"17": ["9f86d0..."]}. They are not secret, but
+    // if set the backend can't substitute the keys
+    let pinned_fingerprints: HashMap<String, Vec<String>> =
+        json_env_var("PINNED_PUBLIC_KEY_FINGERPRINTS")?.unwrap_or_default();
+    event!(Level::INFO, "pinned_fingerprints={:?}", pinned_fingerprints);
+
+    // Texts read back for blank and null votes, in the language of the
+    // contact flow
+    let default_texts = ReadbackTexts::default();
+    let readback_texts = ReadbackTexts {
+        blank: env::var("BLANK_VOTE_TEXT").unwrap_or(default_texts.blank),
+        null: env::var("NULL_VOTE_TEXT").unwrap_or(default_texts.null),
+    };
+    event!(Level::INFO, "readback_texts={:?}", readback_texts);
+
+    let attributes = &connect_event.details.contact_data.attributes;
+    let auth_token: &String = get_attribute(attributes, "AuthToken")?;
     event!(Level::DEBUG, auth_token);
 
-    let election_id: &String = connect_event
-        .details
-        .contact_data
-        .attributes
-        .get("ElectionId")
-        .ok_or(String::from("ElectionId contact data attribute missing"))?;
+    let election_id: &String = get_attribute(attributes, "ElectionId")?;
     event!(Level::DEBUG, election_id);
 
+    // Reject tokens for other elections or, if the token validity is
+    // configured, expired tokens before doing any expensive encryption
+    let auth_token_data: KhmacToken = auth_token.parse()?;
+
+    // Optional secret shared with the backend, used to verify the token digest
+    // locally so that tampered or corrupted tokens fail fast. It's not logged
+    if let Ok(shared_secret) = env::var("AUTH_TOKEN_SHARED_SECRET") {
+        auth_token_data.verify(shared_secret.as_bytes())?;
+        event!(Level::INFO, "auth token digest verified");
+    }
+    let auth_token_validity = auth_token_data.check(
+        election_id,
+        parse_env_var("AUTH_TOKEN_VALIDITY_SECONDS")?,
+        unix_timestamp()
+    )?;
+    event!(Level::INFO, "auth_token_validity={:?}", auth_token_validity);
+
+    let voter_id = &auth_token_data.voter_id;
+    event!(Level::INFO, voter_id);
+
+    // Elections that need the legacy proof of knowledge of the plaintext of
+    // the Sequent voting booth, for example ["17", "18"]. Other elections use
+    // proofs bound to the election, the voter and the public key
+    let legacy_proof_elections: Vec<String> =
+        json_env_var("LEGACY_PROOF_ELECTIONS")?.unwrap_or_default();
+    let proof_version =
+        ProofVersion::for_election(&legacy_proof_elections, election_id);
+    event!(Level::INFO, "proof_version={:?}", proof_version);
+
     let get_election_url = get_election_url_template
         .replace("{{election_id}}", election_id);
 
-    let client = Client::new();
-    let public_key = get_public_key(&client, &get_election_url)?;
-    let vote_encoding_array: HashMap<String, u32> = 
-        serde_json::from_str(&vote_encoding_array_str)?;
+    let contact_data = &connect_event.details.contact_data;
+    let headers = http_client.config().headers.render(&HashMap::from([
+        ("contact_id", contact_data.contact_id.clone().unwrap_or_default()),
+        (
+            "initial_contact_id",
+            contact_data.initial_contact_id.clone().unwrap_or_default()
+        ),
+        ("election_id", election_id.clone()),
+    ]));
+    let fetch_election = get_election(
+        http_client,
+        deadline,
+        &headers,
+        election_id,
+        &get_election_url,
+        pinned_fingerprints.get(election_id)
+    );
+    let election = public_key_cache
+        .get(
+            election_id,
+            &public_key_cache_policy,
+            Instant::now(),
+            fetch_election
+        )
+        .await?;
+    // The cached election might have ended since it was fetched
+    check_election_open(&election)?;
 
-    let context = BigintCtx::<P2048>::new();
-    
-    let vote_int: &u32 = vote_encoding_array.get(vote_text).ok_or("")?;
-    let vote_encoded = context.encode(&BigUint::from(*vote_int))?;
+    let vote_action: VoteAction = attributes
+        .get("VoteAction")
+        .map(|action| action.parse())
+        .transpose()?
+        .unwrap_or(VoteAction::Cast);
+    event!(Level::INFO, "vote_action={:?}", vote_action);
 
-    let (cyphertext, plaintext_proof, debug_str) = public_key
-        .encrypt_and_pok_old_version(
-            &vote_encoded,
-            &vec![]
+    // Legacy proofs can only be proved with a random randomness, so their
+    // ballots can't be audited
+    let auditable =
+        matches!(vote_action, VoteAction::Encrypt | VoteAction::Audit);
+    if auditable && proof_version == ProofVersion::Legacy {
+        event!(
+            Level::WARN,
+            "the ballots of elections with legacy proofs can't be audited"
         );
-    event!(Level::DEBUG, old_version_debug = debug_str);
+        return Err(IvrError::InvalidAttribute("VoteAction".into()));
+    }
+
+    // Ballot encrypted by a previous `encrypt` action, which is cast or
+    // audited as is. The vote attributes are not read again, as the voter
+    // committed to the ballot and not to them
+    let committed_ballot = match vote_action {
+        VoteAction::Cast | VoteAction::Audit => attributes
+            .get("EncryptedBallot")
+            .filter(|ballot| !ballot.is_empty()),
+        VoteAction::Preview | VoteAction::Encrypt => None,
+    };
+    if vote_action == VoteAction::Audit && committed_ballot.is_none() {
+        return Err(IvrError::MissingAttribute("EncryptedBallot".into()));
+    }
 
-    let plaintext_proof_struct = PlaintextProof {
-        challenge: plaintext_proof.challenge.to_string_radix(10),
-        commitment: plaintext_proof.commitment.to_string_radix(10),
-        response: plaintext_proof.response.to_string_radix(10)
+    // Otherwise the vote of the contact attributes is encoded, with all its
+    // answers found before encrypting anything so that invalid votes fail
+    // fast
+    let vote = match committed_ballot {
+        Some(_) => None,
+        None => Some(encode_vote(
+            attributes,
+            &election,
+            &election_aliases(&vote_aliases, election_id),
+            &readback_texts
+        )?),
     };
-    let encrypted_ballot = EncryptedChoice {
-        alpha: cyphertext.gr().to_string_radix(10),
-        beta: cyphertext.mhr().to_string_radix(10)
+    let vote_readback_ssml =
+        vote.as_ref().map(|(_, vote_readback_ssml)| vote_readback_ssml);
+
+    // With the `preview` action the vote is only checked and read back, so
+    // that the voter can confirm it before casting it
+    if vote_action == VoteAction::Preview {
+        return Ok(json!({
+            "VoteReadbackSSML": &vote_readback_ssml,
+            "ElectionTitle": &election.configuration.title
+        }));
+    }
+
+    // The proofs of every question are bound to its own public key
+    let proof_labels: Vec<Vec<u8>> = election
+        .pks
+        .iter()
+        .map(|public_key| proof_label(
+            election_id,
+            voter_id,
+            &public_key_fingerprint(public_key)
+        ))
+        .collect();
+    let derive_ballot_randomness = |secret: &str, nonce: &str| {
+        election
+            .pks
+            .iter()
+            .enumerate()
+            .map(|(index, public_key)| derive_randomness(
+                secret.as_bytes(),
+                nonce,
+                index,
+                &parse_decimal(
+                    &public_key.q,
+                    &format!("payload.pks[{}].q", index)
+                )?
+            ))
+            .collect::<Result<Vec<BigUint>, IvrError>>()
     };
-    let encrypted_ballot = EncryptedVote {
-        choices: vec![
-            encrypted_ballot
-        ],
-        issue_date: Utc::now().format("%Y/%m/%d").to_string(),
-        proofs: vec![
-            plaintext_proof_struct
-        ]
+
+    let (encrypted_ballot_str, encrypted_ballot, ballot_nonce) = match &vote {
+        Some((plaintexts, _)) => {
+            // Only the randomness of ballots encrypted with the `encrypt`
+            // action is derived, as they might be audited. Otherwise strand
+            // chooses it
+            let (randomness, nonce) = match vote_action {
+                VoteAction::Encrypt => {
+                    let secret = env_var("BALLOT_AUDIT_SECRET")?;
+                    let nonce = new_nonce();
+                    (
+                        Some(derive_ballot_randomness(&secret, &nonce)?),
+                        Some(nonce)
+                    )
+                },
+                _ => (None, None),
+            };
+            let ballot = encrypt_ballot(
+                &election.pks,
+                plaintexts,
+                randomness.as_deref(),
+                proof_version,
+                &proof_labels,
+                Utc::now().format("%Y/%m/%d").to_string(),
+                deadline
+            )?;
+            let ballot_str = serde_json::to_string(&ballot)
+                .map_err(|error| IvrError::Internal(error.to_string()))?;
+            (ballot_str, ballot, nonce)
+        },
+        None => {
+            let ballot_str = get_attribute(attributes, "EncryptedBallot")?;
+            let ballot: EncryptedVote = serde_json::from_str(ballot_str)
+                .map_err(|error| {
+                    event!(Level::ERROR, "EncryptedBallot: {}", error);
+                    IvrError::InvalidAttribute("EncryptedBallot".into())
+                })?;
+            (ballot_str.clone(), ballot, None)
+        },
     };
-    let encrypted_ballot_str: String = serde_json::to_string(&encrypted_ballot)?;
+
+    // Verify the ballot as the ballot box would, so that an invalid ballot is
+    // never cast or audited
+    verify_ballot(
+        &election.pks,
+        &encrypted_ballot,
+        proof_version,
+        &proof_labels
+    )?;
+    event!(Level::INFO, "ballot proofs verified");
     event!(Level::INFO, encrypted_ballot_str);
 
     let vote_hash = get_hash(&encrypted_ballot_str);
     event!(Level::INFO, vote_hash = vote_hash);
+    let vote_hash_ssml = verbatim_ssml(&vote_hash[..8]);
+    let invalid_header = || IvrError::InvalidAttribute("AuthToken".into());
+
+    // With the `encrypt` action the ballot is only committed to: the voter
+    // hears the start of its hash and then decides whether to cast it or audit
+    // it, returning the `EncryptedBallot` and `BallotNonce` attributes
+    if let Some(ballot_nonce) = ballot_nonce {
+        return Ok(json!({
+            "EncryptedBallot": &encrypted_ballot_str,
+            "BallotNonce": &ballot_nonce,
+            "VoteHashStartSSML": &vote_hash_ssml,
+            "VoteReadbackSSML": &vote_readback_ssml,
+            "ElectionTitle": &election.configuration.title
+        }));
+    }
+
+    // Directory where the use of every committed ballot is recorded, shared
+    // by all the instances of the lambda, so that an audited ballot is never
+    // cast and a cast ballot is never audited, revealing its randomness. It's
+    // only required to cast or audit a committed ballot
+    let used_ballots_dir = match committed_ballot {
+        Some(_) => Some(env_var("USED_BALLOTS_DIR")?),
+        None => None,
+    };
+    event!(Level::INFO, "used_ballots_dir={:?}", used_ballots_dir);
+
+    if vote_action == VoteAction::Audit {
+        // Example AUDIT_BALLOT_URL, where the audited ballots are published:
+        // https://clientname.example.com/elections/api/election/{{election_id}}/voter/{{voter_id}}/audit
+        let audit_ballot_url_template = env_var("AUDIT_BALLOT_URL")?;
+        event!(Level::INFO, audit_ballot_url_template);
+
+        // The randomness is derived again from the nonce, and it must be the
+        // one the committed ballot was encrypted with
+        let ballot_audit_secret = env_var("BALLOT_AUDIT_SECRET")?;
+        let ballot_nonce = get_attribute(attributes, "BallotNonce")?;
+        check_nonce(ballot_nonce)?;
+        let randomness =
+            derive_ballot_randomness(&ballot_audit_secret, ballot_nonce)?;
+        check_randomness(&election.pks, &encrypted_ballot, &randomness)?;
+        let randomness: Vec<String> = randomness
+            .iter()
+            .map(|randomness| randomness.to_string())
+            .collect();
+
+        if let Some(used_ballots_dir) = &used_ballots_dir {
+            record_ballot_use(
+                Path::new(used_ballots_dir),
+                &vote_hash,
+                BallotUse::Audit
+            )?;
+        }
+
+        let audit_ballot_url = audit_ballot_url_template
+            .replace("{{election_id}}", election_id)
+            .replace("{{voter_id}}", voter_id);
+        event!(Level::DEBUG, audit_ballot_url);
+
+        let audit_code = audit_code(&randomness);
+        let audited_ballot = AuditedBallot {
+            ballot: encrypted_ballot_str,
+            ballot_hash: vote_hash.clone(),
+            randomness,
+            audit_code: audit_code.clone(),
+        };
+        let audited_ballot_str = serde_json::to_string(&audited_ballot)
+            .map_err(|error| IvrError::Internal(error.to_string()))?;
+        event!(Level::DEBUG, audited_ballot_str);
+
+        // Publishing the same spoiled ballot twice is harmless, so it can be
+        // retried
+        let request =
+            BackendRequest::post(&audit_ballot_url, "AUDIT_BALLOT_URL")?
+            .with_header("Authorization", auth_token, invalid_header)?
+            .with_header("Content-Type", "application/json", invalid_header)?
+            .with_extra_headers(&headers)?
+            .with_body(audited_ballot_str)
+            .idempotent();
+        let (status, body) = http_client.send(deadline, &request).await?;
+        event!(Level::INFO, request_response_body = body);
+        if !(200..300).contains(&status) {
+            return Err(IvrError::InvalidStatus(status));
+        }
+        // The audited ballot is spoiled, so it's discarded to never cast it
+        return Ok(json!({
+            "EncryptedBallot": "",
+            "BallotNonce": "",
+            "VoteHashStartSSML": &vote_hash_ssml,
+            "AuditCode": &audit_code,
+            "AuditCodeSSML": verbatim_ssml(&audit_code),
+            "ElectionTitle": &election.configuration.title
+        }));
+    }
+
+    // A committed ballot is recorded as cast before posting it, so that it's
+    // never audited afterwards
+    if let Some(used_ballots_dir) = &used_ballots_dir {
+        record_ballot_use(
+            Path::new(used_ballots_dir),
+            &vote_hash,
+            BallotUse::Cast
+        )?;
+    }
 
     let vote_request = VoteRequest {
         vote: encrypted_ballot_str,
         vote_hash: vote_hash.clone()
     };
-    let vote_request_str: String = serde_json::to_string(&vote_request)?;
+    let vote_request_str: String = serde_json::to_string(&vote_request)
+        .map_err(|error| IvrError::Internal(error.to_string()))?;
     event!(Level::INFO, vote_request_str);
 
-    let voter_id = get_voter_id(auth_token)?;
-    event!(Level::INFO, voter_id);
-
     let record_vote_url = record_vote_url_template
         .replace("{{election_id}}", election_id)
-        .replace("{{voter_id}}", &voter_id);
+        .replace("{{voter_id}}", voter_id);
     event!(Level::DEBUG, record_vote_url);
 
     event!(
@@ -245,42 +624,80 @@ async fn function_handler(event: LambdaEvent<ConnectEvent>) -> Result<Value, Err
         request_authorization_header = auth_token,
         request_body = vote_request_str
     );
-    let response = client.request(
-        Request::builder(Method::POST, record_vote_url.parse()?)
-            .with_header(HeaderName::AUTHORIZATION, auth_token.as_str())?
-            .with_header(HeaderName::CONTENT_TYPE, "application/json")?
-            .with_body(vote_request_str)
-    )?;
-    
-    let status = response.status();
-    event!(Level::INFO, request_response_status = status.to_string());
-    
-    let body = response.into_body().to_string()?;
+    // Casting a vote is not idempotent, so it's only retried if the backend
+    // can't have processed it
+    let request = BackendRequest::post(&record_vote_url, "RECORD_VOTE_URL")?
+        .with_header("Authorization", auth_token, invalid_header)?
+        .with_header("Content-Type", "application/json", invalid_header)?
+        .with_extra_headers(&headers)?
+        .with_body(vote_request_str);
+    let (status, body) = http_client.send(deadline, &request).await?;
     event!(Level::INFO, request_response_body = body);
     
     match status {
-        Status::OK => {
-            let vote_hash_ssml = String::from(&vote_hash[..8]).chars().fold(
-                String::from(""),
-                |hash_ssml, character| {
-                    format!(
-                        "{}<s><say-as interpret-as=\"verbatim\">{}</say-as></s>",
-                        hash_ssml,
-                        character
-                    )
-                }
-            );
-            let ret_value = json!({
-                "VoteHashStartSSML": &vote_hash_ssml
+        200 => {
+            // The ballot and its nonce are cleared, so that the contact flow
+            // doesn't use them again. A committed ballot was already read
+            // back by the `encrypt` action
+            let mut ret_value = json!({
+                "EncryptedBallot": "",
+                "BallotNonce": "",
+                "VoteHashStartSSML": &vote_hash_ssml,
+                "ElectionTitle": &election.configuration.title
             });
+            if let Some(vote_readback_ssml) = vote_readback_ssml {
+                ret_value["VoteReadbackSSML"] = json!(vote_readback_ssml);
+            }
             event!(Level::DEBUG, ret_value = ret_value.to_string());
 
             Ok(ret_value)
         },
-        _ => Err("invalid-status".into())
+        // the ballot box might have recorded the vote before failing, so it
+        // must not be cast again
+        status if status >= 500 => Err(IvrError::UnconfirmedVote(
+            format!("status {}", status)
+        )),
+        status => Err(IvrError::InvalidStatus(status))
     }
 }
 
+/// This is the main body for the function.
+/// Write your code inside it.
+/// There are some code example in the following URLs:
+/// - https://github.com/awslabs/aws-lambda-rust-runtime/tree/main/examples
+/// - https://github.com/aws-samples/serverless-rust-demo/
+///
+/// Errors are not returned as a failed invocation but as a successful
+/// response with the `Status`, `ErrorCode` and `Retryable` attributes, so that
+/// the contact flow can branch on them.
+///
+/// `http_client` is created at cold start, or is the configuration error found
+/// when creating it. `public_key_cache` is shared by the warm invocations.
+async fn function_handler(
+    http_client: &Result<HttpClient, IvrError>,
+    public_key_cache: &PublicKeyCache<Election>,
+    event: LambdaEvent<ConnectEvent>
+) -> Result<Value, Error>
+{
+    let (connect_event, connect_context) = event.into_parts();
+    event!(
+        Level::DEBUG,
+        connect_event = serde_json::to_string(&connect_event)?,
+        connect_context = serde_json::to_string(&connect_context)?
+    );
+
+    let result = match http_client {
+        Ok(http_client) => record_vote(
+            http_client,
+            public_key_cache,
+            &connect_event,
+            context_deadline(connect_context.deadline)
+        ).await,
+        Err(error) => Err(error.clone()),
+    };
+    Ok(to_response(result))
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Error> {
     let tracing_level_str = env::var("TRACING_LEVEL")
@@ -299,5 +716,709 @@ async fn main() -> Result<(), Error> {
     event!(Level::INFO, tracing_level_str);
     event!(Level::INFO, "starting up `record_vote` lambda");
 
-    run(service_fn(function_handler)).await
+    // The HTTP client is created once and reused by the warm invocations, so
+    // that the connections to the backend are kept alive. A configuration
+    // error is returned by every invocation
+    let http_client = HttpClient::from_env();
+    if let Err(error) = &http_client {
+        event!(Level::ERROR, "invalid HTTP configuration: {}", error);
+    }
+    let http_client = &http_client;
+    let public_key_cache = &PublicKeyCache::default();
+    run(service_fn(move |event| async move {
+        function_handler(http_client, public_key_cache, event).await
+    })).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::env;
+    use std::fs;
+    use std::include_str;
+    use std::path::PathBuf;
+    use std::time::{SystemTime, UNIX_EPOCH};
+    use serde_json::Value;
+    use serde_json::json;
+    use lambda_runtime::Error;
+    use serial_test::serial;
+    use aws_lambda_events::event::connect::ConnectEvent;
+    use httpmock::prelude::*;
+    use httpmock::Mock;
+    use num_bigint::BigUint;
+    use ivr_common::http::HttpClient;
+
+    use crate::ballot::EncryptedVote;
+    use crate::election::Election;
+    use crate::group::Group;
+    use crate::public_key_cache::PublicKeyCache;
+    use crate::{function_handler, get_hash, VoteRequest};
+
+    const AUTH_TOKEN: &str = "khmac:///sha-256;c4ba96310ea7474b4ee2e84b00eaf412786816ea7d3713af866dab67c3201668/4cf53604330bab6a6179de2e:AuthEvent:17:vote:1665653516";
+    const RECORD_VOTE_PATH: &str =
+        "/election/17/voter/4cf53604330bab6a6179de2e";
+    const AUDIT_BALLOT_PATH: &str =
+        "/election/17/voter/4cf53604330bab6a6179de2e/audit";
+    const ELECTION: &str = include_str!("../test/mock_backend/election.json");
+
+    // Set environment variables. If any of the values is an empty string,
+    // unsets the variable.
+    //
+    // IMPORTANT: env vars are set for the whole executable, so changing this
+    // might create run conditions on any function that depends on env
+    // variables.
+    fn set_env_vars(env_vars: &HashMap<&str, &str>) {
+        for (env_var_name, env_var_value) in env_vars.iter() {
+            if !env_var_value.is_empty() {
+                env::set_var(env_var_name, env_var_value);
+            } else {
+                env::remove_var(env_var_name);
+            }
+        }
+    }
+
+    // returns the election of the mock backend with its toy public keys
+    // replaced by keys of the 2048-bit group, one per question
+    fn with_public_keys(election_str: &str) -> String {
+        let mut election: Value = serde_json::from_str(election_str).unwrap();
+        let questions = election["payload"]["configuration"]["questions"]
+            .as_array()
+            .unwrap()
+            .len();
+        let parameters = Group::P2048.parameters();
+        let public_keys: Vec<Value> = (0..questions)
+            .map(|index| {
+                let secret = BigUint::from(1000u32 + index as u32);
+                let y = parameters.g.modpow(&secret, &parameters.p);
+                json!({
+                    "p": parameters.p.to_string(),
+                    "q": parameters.q.to_string(),
+                    "g": parameters.g.to_string(),
+                    "y": y.to_string()
+                })
+            })
+            .collect();
+        // the keys are embedded in the election as a JSON string
+        let public_keys = Value::from(public_keys).to_string();
+        election["payload"]["pks"] = public_keys.into();
+        election.to_string()
+    }
+
+    // returns a new directory to record the used ballots
+    fn used_ballots_dir() -> PathBuf {
+        let used_ballots_dir = env::temp_dir().join(format!(
+            "used-ballots-{}-{}",
+            std::process::id(),
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_nanos()
+        ));
+        fs::create_dir(&used_ballots_dir).unwrap();
+        used_ballots_dir
+    }
+
+    // default init function for unit tests, returning the mock of the
+    // election
+    fn init<'a>(
+        server: &'a MockServer,
+        override_env_vars: Option<HashMap<&str, &str>>,
+        election: &str
+    ) -> Mock<'a>
+    {
+        let election_mock = server.mock(|when, then| {
+            when.method(GET)
+                .path("/election/17");
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(election);
+        });
+
+        let get_election_url = server.url("/election/{{election_id}}");
+        let record_vote_url =
+            server.url("/election/{{election_id}}/voter/{{voter_id}}");
+        let audit_ballot_url =
+            server.url("/election/{{election_id}}/voter/{{voter_id}}/audit");
+        let default_env_vars: HashMap<&str, &str> = HashMap::from([
+            ("TRACING_LEVEL", "debug"),
+            ("GET_ELECTION_URL", get_election_url.as_str()),
+            ("RECORD_VOTE_URL", record_vote_url.as_str()),
+            ("AUDIT_BALLOT_URL", audit_ballot_url.as_str()),
+            ("BALLOT_AUDIT_SECRET", "audit-secret"),
+            ("USED_BALLOTS_DIR", ""),
+            ("VOTE_ALIASES", ""),
+            ("VOTE_ENCODING_ARRAY", ""),
+            ("BLANK_VOTE_TEXT", ""),
+            ("NULL_VOTE_TEXT", ""),
+            ("LEGACY_PROOF_ELECTIONS", ""),
+            ("PINNED_PUBLIC_KEY_FINGERPRINTS", ""),
+            ("PUBLIC_KEY_CACHE_TTL_SECONDS", ""),
+            ("PUBLIC_KEY_CACHE_MAX_STALENESS_SECONDS", ""),
+            ("AUTH_TOKEN_SHARED_SECRET", ""),
+            ("AUTH_TOKEN_VALIDITY_SECONDS", ""),
+            ("HTTP_RETRY_MAX_ATTEMPTS", ""),
+            ("HTTP_RETRY_BASE_DELAY_MS", ""),
+            ("HTTP_RETRY_MAX_DELAY_MS", ""),
+            ("HTTP_TIMEOUT_MS", ""),
+            ("HTTP_HEADERS", ""),
+            ("HTTP_SECRET_HEADERS", "")
+        ]);
+        let override_env_vars_val = override_env_vars
+            .unwrap_or_default();
+        let env_vars: HashMap<&str, &str> = default_env_vars
+            .into_iter()
+            .chain(override_env_vars_val)
+            .collect();
+        set_env_vars(&env_vars);
+
+        election_mock
+    }
+
+    // mock of the ballot box, returning the given status and response to any
+    // vote of the voter
+    fn record_vote_mock<'a>(
+        server: &'a MockServer,
+        status: u16,
+        response: &str
+    ) -> Mock<'a>
+    {
+        server.mock(|when, then| {
+            when.method(POST)
+                .path(RECORD_VOTE_PATH)
+                .header("authorization", AUTH_TOKEN)
+                .header("content-type", "application/json");
+            then.status(status)
+                .header("content-type", "application/json")
+                .body(response);
+        })
+    }
+
+    // calls the crate's lambda, with the HTTP client created from the env vars
+    // as in a cold start and the given contact attributes added to the event
+    async fn call_lambda(
+        public_key_cache: &PublicKeyCache<Election>,
+        connect_event_str: &str,
+        attributes: &[(&str, &str)]
+    ) -> Result<Value, Error>
+    {
+        let mut input: ConnectEvent = serde_json::from_str(connect_event_str)?;
+        for (name, value) in attributes {
+            input
+                .details
+                .contact_data
+                .attributes
+                .insert(name.to_string(), value.to_string());
+        }
+        let context = lambda_runtime::Context::default();
+        let event = lambda_runtime::LambdaEvent::new(input, context);
+        let http_client = HttpClient::from_env();
+        function_handler(&http_client, public_key_cache, event).await
+    }
+
+    // returns the ballot cast in a request to the ballot box, if any
+    fn cast_ballot(request_body: &[u8]) -> Option<EncryptedVote> {
+        let vote_request: VoteRequest =
+            serde_json::from_slice(request_body).ok()?;
+        serde_json::from_str(&vote_request.vote).ok()
+    }
+
+    // Simulates how casting a vote should happen
+    #[tokio::test]
+    #[serial]
+    async fn cast_vote() {
+        let server = MockServer::start();
+        let election_mock = init(
+            &server,
+            Default::default(),
+            &with_public_keys(ELECTION)
+        );
+        let record_vote_mock = record_vote_mock(
+            &server,
+            200,
+            include_str!("../test/mock_backend/success.json")
+        );
+
+        let event_result = call_lambda(
+            &PublicKeyCache::default(),
+            include_str!("../test/test_data_1.json"),
+            &[]
+        )
+            .await
+            .expect("failed to handle event");
+
+        println!("event_result = {:?}", &event_result);
+        election_mock.assert();
+        record_vote_mock.assert();
+        assert_eq!(event_result["Status"], "success");
+        assert_eq!(event_result["EncryptedBallot"], "");
+        assert_eq!(event_result["ElectionTitle"], "Referendum");
+        assert_eq!(
+            event_result["VoteReadbackSSML"],
+            "<speak><p><s>Do you approve the new bylaws?</s><s>Yes</s></p>\
+            </speak>"
+        );
+    }
+
+    // Casts a vote in an election with two questions, with a choice encrypted
+    // for each one
+    #[tokio::test]
+    #[serial]
+    async fn cast_multiple_questions() {
+        let server = MockServer::start();
+        let election_mock = init(
+            &server,
+            Default::default(),
+            &with_public_keys(include_str!(
+                "../test/mock_backend/election_multiple_questions.json"
+            ))
+        );
+        let record_vote_mock = server.mock(|when, then| {
+            when.method(POST)
+                .path(RECORD_VOTE_PATH)
+                .matches(|request| {
+                    let ballot = request
+                        .body
+                        .as_deref()
+                        .and_then(cast_ballot);
+                    matches!(
+                        ballot,
+                        Some(ballot)
+                            if ballot.choices.len() == 2
+                                && ballot.proofs.len() == 2
+                    )
+                });
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(include_str!("../test/mock_backend/success.json"));
+        });
+
+        let event_result = call_lambda(
+            &PublicKeyCache::default(),
+            include_str!("../test/test_data_2.json"),
+            &[]
+        )
+            .await
+            .expect("failed to handle event");
+
+        election_mock.assert();
+        record_vote_mock.assert();
+        assert_eq!(event_result["Status"], "success");
+        assert_eq!(
+            event_result["VoteReadbackSSML"],
+            "<speak><p><s>Do you approve the new bylaws?</s><s>Yes</s></p>\
+            <p><s>Elect the members of the board</s><s>Alice</s>\
+            <s>Carol</s></p></speak>"
+        );
+
+        // every question needs its vote
+        let event_result = call_lambda(
+            &PublicKeyCache::default(),
+            include_str!("../test/test_data_1.json"),
+            &[]
+        )
+            .await
+            .expect("failed to handle event");
+
+        record_vote_mock.assert();
+        assert_eq!(event_result["Status"], "error");
+        assert_eq!(event_result["ErrorCode"], "missing-attribute");
+    }
+
+    // The preview action only reads back the vote, without casting it
+    #[tokio::test]
+    #[serial]
+    async fn preview_vote() {
+        let server = MockServer::start();
+        let election_mock = init(
+            &server,
+            Default::default(),
+            &with_public_keys(ELECTION)
+        );
+        let record_vote_mock = record_vote_mock(
+            &server,
+            200,
+            include_str!("../test/mock_backend/success.json")
+        );
+
+        let event_result = call_lambda(
+            &PublicKeyCache::default(),
+            include_str!("../test/test_data_1.json"),
+            &[("VoteAction", "preview"), ("Vote", "no")]
+        )
+            .await
+            .expect("failed to handle event");
+
+        election_mock.assert();
+        record_vote_mock.assert_hits(0);
+        assert_eq!(event_result["Status"], "success");
+        assert_eq!(
+            event_result["VoteReadbackSSML"],
+            "<speak><p><s>Do you approve the new bylaws?</s><s>No</s></p>\
+            </speak>"
+        );
+        assert!(event_result.get("EncryptedBallot").is_none());
+        assert!(event_result.get("VoteHashStartSSML").is_none());
+    }
+
+    // Casts the ballot committed to by the encrypt action, which can't be
+    // audited afterwards
+    #[tokio::test]
+    #[serial]
+    async fn encrypt_and_cast() {
+        let server = MockServer::start();
+        let used_ballots_dir = used_ballots_dir();
+        let election_mock = init(
+            &server,
+            Some(HashMap::from([
+                ("USED_BALLOTS_DIR", used_ballots_dir.to_str().unwrap())
+            ])),
+            &with_public_keys(ELECTION)
+        );
+        let public_key_cache = PublicKeyCache::default();
+
+        let encrypted = call_lambda(
+            &public_key_cache,
+            include_str!("../test/test_data_1.json"),
+            &[("VoteAction", "encrypt")]
+        )
+            .await
+            .expect("failed to handle event");
+
+        assert_eq!(encrypted["Status"], "success");
+        assert_eq!(
+            encrypted["VoteReadbackSSML"],
+            "<speak><p><s>Do you approve the new bylaws?</s><s>Yes</s></p>\
+            </speak>"
+        );
+        let ballot = encrypted["EncryptedBallot"].as_str().unwrap();
+        let ballot_nonce = encrypted["BallotNonce"].as_str().unwrap();
+        let ballot_hash = get_hash(&ballot.to_string());
+
+        // the committed ballot is cast as is, ignoring the vote attributes
+        let record_vote_mock = server.mock(|when, then| {
+            when.method(POST)
+                .path(RECORD_VOTE_PATH)
+                .json_body(json!({ "vote": ballot, "vote_hash": ballot_hash }));
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(include_str!("../test/mock_backend/success.json"));
+        });
+        let cast = call_lambda(
+            &public_key_cache,
+            include_str!("../test/test_data_1.json"),
+            &[
+                ("VoteAction", "cast"),
+                ("Vote", "no"),
+                ("EncryptedBallot", ballot),
+                ("BallotNonce", ballot_nonce)
+            ]
+        )
+            .await
+            .expect("failed to handle event");
+
+        record_vote_mock.assert();
+        assert_eq!(cast["Status"], "success");
+        assert_eq!(cast["EncryptedBallot"], "");
+        assert_eq!(cast["BallotNonce"], "");
+        assert_eq!(cast["VoteHashStartSSML"], encrypted["VoteHashStartSSML"]);
+        assert!(cast.get("VoteReadbackSSML").is_none());
+
+        // a cast ballot is never audited
+        let audit_ballot_mock = server.mock(|when, then| {
+            when.method(POST)
+                .path(AUDIT_BALLOT_PATH);
+            then.status(200);
+        });
+        let audited = call_lambda(
+            &public_key_cache,
+            include_str!("../test/test_data_1.json"),
+            &[
+                ("VoteAction", "audit"),
+                ("EncryptedBallot", ballot),
+                ("BallotNonce", ballot_nonce)
+            ]
+        )
+            .await
+            .expect("failed to handle event");
+
+        audit_ballot_mock.assert_hits(0);
+        assert_eq!(audited["Status"], "error");
+        assert_eq!(audited["ErrorCode"], "invalid-ballot");
+        election_mock.assert();
+
+        fs::remove_dir_all(&used_ballots_dir).unwrap();
+    }
+
+    // Audits the ballot committed to by the encrypt action, which can't be
+    // cast afterwards
+    #[tokio::test]
+    #[serial]
+    async fn encrypt_and_audit() {
+        let server = MockServer::start();
+        let used_ballots_dir = used_ballots_dir();
+        let election_mock = init(
+            &server,
+            Some(HashMap::from([
+                ("USED_BALLOTS_DIR", used_ballots_dir.to_str().unwrap())
+            ])),
+            &with_public_keys(ELECTION)
+        );
+        let record_vote_mock = record_vote_mock(
+            &server,
+            200,
+            include_str!("../test/mock_backend/success.json")
+        );
+        let public_key_cache = PublicKeyCache::default();
+
+        let encrypted = call_lambda(
+            &public_key_cache,
+            include_str!("../test/test_data_1.json"),
+            &[("VoteAction", "encrypt")]
+        )
+            .await
+            .expect("failed to handle event");
+
+        assert_eq!(encrypted["Status"], "success");
+        let ballot = encrypted["EncryptedBallot"].as_str().unwrap();
+        let ballot_nonce = encrypted["BallotNonce"].as_str().unwrap();
+        let ballot_hash = get_hash(&ballot.to_string());
+
+        let audit_ballot_mock = server.mock(|when, then| {
+            when.method(POST)
+                .path(AUDIT_BALLOT_PATH)
+                .header("authorization", AUTH_TOKEN)
+                .json_body_partial(
+                    json!({ "ballot": ballot, "ballot_hash": ballot_hash })
+                        .to_string()
+                );
+            then.status(200);
+        });
+        let audited = call_lambda(
+            &public_key_cache,
+            include_str!("../test/test_data_1.json"),
+            &[
+                ("VoteAction", "audit"),
+                ("EncryptedBallot", ballot),
+                ("BallotNonce", ballot_nonce)
+            ]
+        )
+            .await
+            .expect("failed to handle event");
+
+        audit_ballot_mock.assert();
+        assert_eq!(audited["Status"], "success");
+        assert_eq!(audited["EncryptedBallot"], "");
+        assert_eq!(audited["BallotNonce"], "");
+        assert_eq!(audited["AuditCode"].as_str().unwrap().len(), 6);
+        assert!(audited.get("VoteReadbackSSML").is_none());
+
+        // an audited ballot is spoiled and never cast
+        let cast = call_lambda(
+            &public_key_cache,
+            include_str!("../test/test_data_1.json"),
+            &[
+                ("VoteAction", "cast"),
+                ("EncryptedBallot", ballot),
+                ("BallotNonce", ballot_nonce)
+            ]
+        )
+            .await
+            .expect("failed to handle event");
+
+        record_vote_mock.assert_hits(0);
+        assert_eq!(cast["Status"], "error");
+        assert_eq!(cast["ErrorCode"], "invalid-ballot");
+
+        // nor audited with a nonce other than its own
+        let audited = call_lambda(
+            &public_key_cache,
+            include_str!("../test/test_data_1.json"),
+            &[
+                ("VoteAction", "audit"),
+                ("EncryptedBallot", ballot),
+                ("BallotNonce", "00000000000000000000000000000000")
+            ]
+        )
+            .await
+            .expect("failed to handle event");
+
+        audit_ballot_mock.assert();
+        assert_eq!(audited["Status"], "error");
+        assert_eq!(audited["ErrorCode"], "invalid-attribute");
+        election_mock.assert();
+
+        fs::remove_dir_all(&used_ballots_dir).unwrap();
+    }
+
+    // A committed ballot with an invalid proof is neither cast nor audited
+    #[tokio::test]
+    #[serial]
+    async fn invalid_proof() {
+        let server = MockServer::start();
+        let used_ballots_dir = used_ballots_dir();
+        init(
+            &server,
+            Some(HashMap::from([
+                ("USED_BALLOTS_DIR", used_ballots_dir.to_str().unwrap())
+            ])),
+            &with_public_keys(ELECTION)
+        );
+        let record_vote_mock = record_vote_mock(
+            &server,
+            200,
+            include_str!("../test/mock_backend/success.json")
+        );
+        let audit_ballot_mock = server.mock(|when, then| {
+            when.method(POST)
+                .path(AUDIT_BALLOT_PATH);
+            then.status(200);
+        });
+        let public_key_cache = PublicKeyCache::default();
+
+        let encrypted = call_lambda(
+            &public_key_cache,
+            include_str!("../test/test_data_1.json"),
+            &[("VoteAction", "encrypt")]
+        )
+            .await
+            .expect("failed to handle event");
+
+        let ballot_nonce = encrypted["BallotNonce"].as_str().unwrap();
+        let mut ballot: EncryptedVote = serde_json::from_str(
+            encrypted["EncryptedBallot"].as_str().unwrap()
+        ).unwrap();
+        let response: BigUint = ballot.proofs[0].response.parse().unwrap();
+        ballot.proofs[0].response = (response + 1u32).to_string();
+        let tampered_ballot = serde_json::to_string(&ballot).unwrap();
+
+        for vote_action in ["cast", "audit"] {
+            let event_result = call_lambda(
+                &public_key_cache,
+                include_str!("../test/test_data_1.json"),
+                &[
+                    ("VoteAction", vote_action),
+                    ("EncryptedBallot", &tampered_ballot),
+                    ("BallotNonce", ballot_nonce)
+                ]
+            )
+                .await
+                .expect("failed to handle event");
+
+            assert_eq!(event_result["Status"], "error");
+            assert_eq!(event_result["ErrorCode"], "invalid-ballot");
+            assert_eq!(event_result["Retryable"], "false");
+        }
+        record_vote_mock.assert_hits(0);
+        audit_ballot_mock.assert_hits(0);
+
+        fs::remove_dir_all(&used_ballots_dir).unwrap();
+    }
+
+    // The election and its public keys are fetched once and reused by the
+    // following votes, until they expire
+    #[tokio::test]
+    #[serial]
+    async fn public_key_cache_reuse() {
+        let server = MockServer::start();
+        let election_mock = init(
+            &server,
+            Default::default(),
+            &with_public_keys(ELECTION)
+        );
+        let record_vote_mock = record_vote_mock(
+            &server,
+            200,
+            include_str!("../test/mock_backend/success.json")
+        );
+        let public_key_cache = PublicKeyCache::default();
+
+        for vote_action in ["preview", "cast"] {
+            let event_result = call_lambda(
+                &public_key_cache,
+                include_str!("../test/test_data_1.json"),
+                &[("VoteAction", vote_action)]
+            )
+                .await
+                .expect("failed to handle event");
+
+            assert_eq!(event_result["Status"], "success");
+        }
+        election_mock.assert_hits(1);
+        record_vote_mock.assert();
+
+        // without caching, every vote fetches the election
+        set_env_vars(&HashMap::from([("PUBLIC_KEY_CACHE_TTL_SECONDS", "0")]));
+        let event_result = call_lambda(
+            &public_key_cache,
+            include_str!("../test/test_data_1.json"),
+            &[("VoteAction", "preview")]
+        )
+            .await
+            .expect("failed to handle event");
+
+        assert_eq!(event_result["Status"], "success");
+        election_mock.assert_hits(2);
+    }
+
+    // Public keys outside the supported groups are rejected before encrypting
+    // any vote with them
+    #[tokio::test]
+    #[serial]
+    async fn unsupported_group() {
+        let server = MockServer::start();
+        let election_mock = init(
+            &server,
+            Default::default(),
+            ELECTION
+        );
+        let record_vote_mock = record_vote_mock(
+            &server,
+            200,
+            include_str!("../test/mock_backend/success.json")
+        );
+
+        let event_result = call_lambda(
+            &PublicKeyCache::default(),
+            include_str!("../test/test_data_1.json"),
+            &[]
+        )
+            .await
+            .expect("failed to handle event");
+
+        election_mock.assert();
+        record_vote_mock.assert_hits(0);
+        assert_eq!(event_result["Status"], "error");
+        assert_eq!(event_result["ErrorCode"], "unsupported-group");
+    }
+
+    // A vote that might have been recorded by a failing ballot box is not
+    // retried
+    #[tokio::test]
+    #[serial]
+    async fn unconfirmed_vote() {
+        let server = MockServer::start();
+        init(
+            &server,
+            Default::default(),
+            &with_public_keys(ELECTION)
+        );
+        let record_vote_mock = record_vote_mock(
+            &server,
+            500,
+            include_str!("../test/mock_backend/failure.json")
+        );
+
+        let event_result = call_lambda(
+            &PublicKeyCache::default(),
+            include_str!("../test/test_data_1.json"),
+            &[]
+        )
+            .await
+            .expect("failed to handle event");
+
+        record_vote_mock.assert();
+        assert_eq!(event_result["Status"], "error");
+        assert_eq!(event_result["ErrorCode"], "unconfirmed-vote");
+        assert_eq!(event_result["Retryable"], "false");
+    }
 }
@@ -0,0 +1,346 @@
+// SPDX-FileCopyrightText: 2022 Eduardo Robles <edu@sequentech.io>
+//
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Proofs of knowledge of the plaintext of the encrypted choices.
+//!
+//! By default the choices are encrypted and proved with the strand API, with
+//! the Fiat-Shamir challenge of the proof bound to a label with the election
+//! id, the voter id and the fingerprint of the public key, so that the proof
+//! can't be replayed in another election or by another voter. Elections that
+//! need the legacy proof of the Sequent voting booth, which isn't bound to any
+//! context, can still use it with the `LEGACY_PROOF_ELECTIONS` switch.
+//!
+//! Before posting a ballot, every proof is verified against its ciphertext and
+//! public key, so that a bug, a dependency regression or a hardware fault
+//! can't record an invalid ballot.
+
+use num_bigint::BigUint;
+use sha2::{Digest, Sha256};
+use strand::context::{Ctx, Element};
+use strand::zkp::{Schnorr, Zkp};
+use ivr_common::IvrError;
+
+use crate::ballot::{EncryptedChoice, PlaintextProof};
+use crate::group::Group;
+
+/// Version of the proof of knowledge of the plaintext.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProofVersion {
+    /// Proof of the strand API, with the challenge bound to the election, the
+    /// voter and the public key.
+    ContextBound,
+    /// Legacy proof of the Sequent voting booth.
+    Legacy,
+}
+
+impl ProofVersion {
+    /// Returns the version of the proof used in an election, given the ids of
+    /// the elections that need the legacy proof.
+    pub fn for_election(legacy_elections: &[String], election_id: &str)
+        -> ProofVersion
+    {
+        if legacy_elections.iter().any(|id| id.trim() == election_id) {
+            ProofVersion::Legacy
+        } else {
+            ProofVersion::ContextBound
+        }
+    }
+}
+
+/// Label the challenge of a context-bound proof is bound to:
+/// `{election_id}/{voter_id}/{public_key_fingerprint}`.
+pub fn proof_label(
+    election_id: &str,
+    voter_id: &str,
+    public_key_fingerprint: &str
+) -> Vec<u8>
+{
+    format!("{}/{}/{}", election_id, voter_id, public_key_fingerprint)
+        .into_bytes()
+}
+
+/// Returns the challenge of a legacy proof, as derived by the Sequent voting
+/// booth: the SHA-256 digest of `{alpha}/{commitment}`, with the values in
+/// decimal and the digest read as a big-endian number.
+fn legacy_challenge(alpha: &str, commitment: &str) -> BigUint {
+    let input = format!("{}/{}", alpha, commitment);
+    BigUint::from_bytes_be(&Sha256::digest(input.as_bytes()))
+}
+
+/// Verifies the proof of an encrypted choice with the strand context of the
+/// `group` of its public key:
+/// - Context-bound proofs are verified by strand, for the given `label`.
+/// - Legacy proofs must satisfy `g^response = commitment * alpha^challenge`,
+///   with the challenge derived from the ciphertext and the commitment, modulo
+///   `q` as only its value as an exponent matters. Without the second check,
+///   anyone could simulate a valid proof without knowing the randomness.
+///
+/// Any mismatch returns the `invalid-ballot` error. `path` is the path of the
+/// choice in the ballot, used in the error details.
+pub fn verify_proof(
+    version: ProofVersion,
+    label: &[u8],
+    group: Group,
+    choice: &EncryptedChoice,
+    proof: &PlaintextProof,
+    path: &str
+) -> Result<(), IvrError>
+{
+    let invalid = |details: &str| {
+        IvrError::InvalidBallot(format!("{}: {}", path, details))
+    };
+    let parse = |value: &str| {
+        BigUint::parse_bytes(value.as_bytes(), 10)
+            .ok_or_else(|| invalid("not a number"))
+    };
+    let parameters = group.parameters();
+    let one = BigUint::from(1u32);
+    let alpha = parse(&choice.alpha)?;
+    if alpha <= one || alpha >= parameters.p {
+        return Err(invalid("alpha out of range"));
+    }
+    let commitment = parse(&proof.commitment)?;
+    if commitment <= one || commitment >= parameters.p {
+        return Err(invalid("commitment out of range"));
+    }
+    let challenge = parse(&proof.challenge)?;
+
+    let context = group.context();
+    let element = |value: &str| {
+        context
+            .element_from_string_radix(value, 10)
+            .map_err(|_| invalid("not a number"))
+    };
+    let exponent = |value: &str| {
+        context
+            .exp_from_string_radix(value, 10)
+            .map_err(|_| invalid("not a number"))
+    };
+    let gr = element(&choice.alpha)?;
+    let schnorr = Schnorr {
+        commitment: element(&proof.commitment)?,
+        challenge: exponent(&proof.challenge)?,
+        response: exponent(&proof.response)?,
+    };
+    match version {
+        ProofVersion::ContextBound => {
+            let mhr = element(&choice.beta)?;
+            let zkp = Zkp::new(&context);
+            if !zkp.encryption_popk_verify(&mhr, &gr, &schnorr, label) {
+                return Err(invalid("proof verification failed"));
+            }
+        },
+        ProofVersion::Legacy => {
+            let expected_challenge =
+                legacy_challenge(&choice.alpha, &proof.commitment);
+            let q = &parameters.q;
+            if challenge % q != expected_challenge % q {
+                return Err(invalid("challenge mismatch"));
+            }
+            let expected = context.modulo(
+                &schnorr
+                    .commitment
+                    .mul(&context.emod_pow(&gr, &schnorr.challenge))
+            );
+            if context.gmod_pow(&schnorr.response) != expected {
+                return Err(invalid("proof verification failed"));
+            }
+        },
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use num_bigint::BigUint;
+    use strand::context::Ctx;
+    use strand::elgamal::PublicKey;
+    use ivr_common::IvrError;
+    use crate::ballot::{EncryptedChoice, PlaintextProof};
+    use crate::group::Group;
+    use crate::proof::{proof_label, verify_proof, ProofVersion};
+
+    #[test]
+    fn proof_version() {
+        let legacy_elections = vec!["17".to_string(), " 18 ".to_string()];
+        assert_eq!(
+            ProofVersion::for_election(&legacy_elections, "18"),
+            ProofVersion::Legacy
+        );
+        assert_eq!(
+            ProofVersion::for_election(&legacy_elections, "1"),
+            ProofVersion::ContextBound
+        );
+        assert_eq!(
+            ProofVersion::for_election(&[], "17"),
+            ProofVersion::ContextBound
+        );
+        assert_eq!(
+            proof_label("17", "100", "9f86d0"),
+            b"17/100/9f86d0".to_vec()
+        );
+    }
+
+    /// Encrypts and proves the plaintext `9` with the strand API, as in the
+    /// ballots.
+    fn encrypt(version: ProofVersion, label: &[u8])
+        -> (EncryptedChoice, PlaintextProof)
+    {
+        let context = Group::P2048.context();
+        let y = context.gmod_pow(
+            &context.exp_from_string_radix("5", 10).unwrap()
+        );
+        let public_key = PublicKey::from_element(&y, &context);
+        let encoded = context.encode(&BigUint::from(9u32)).unwrap();
+        let (cyphertext, proof) = match version {
+            ProofVersion::ContextBound => {
+                public_key.encrypt_and_pok(&encoded, label)
+            },
+            ProofVersion::Legacy => {
+                let (cyphertext, proof, _debug_str) = public_key
+                    .encrypt_and_pok_old_version(&encoded, &vec![]);
+                (cyphertext, proof)
+            },
+        };
+        (
+            EncryptedChoice {
+                alpha: cyphertext.gr().to_string_radix(10),
+                beta: cyphertext.mhr().to_string_radix(10),
+            },
+            PlaintextProof {
+                challenge: proof.challenge.to_string_radix(10),
+                commitment: proof.commitment.to_string_radix(10),
+                response: proof.response.to_string_radix(10),
+            },
+        )
+    }
+
+    #[test]
+    fn verify_proofs() {
+        let label = proof_label("17", "100", "9f86d0");
+        let verify = |
+            version,
+            label: &[u8],
+            choice: &EncryptedChoice,
+            proof: &PlaintextProof
+        | {
+            verify_proof(
+                version,
+                label,
+                Group::P2048,
+                choice,
+                proof,
+                "choices[0]"
+            )
+        };
+        let invalid = |details: &str| {
+            Err(IvrError::InvalidBallot(format!("choices[0]: {}", details)))
+        };
+        for version in [ProofVersion::Legacy, ProofVersion::ContextBound] {
+            let (choice, proof) = encrypt(version, &label);
+            assert_eq!(verify(version, &label, &choice, &proof), Ok(()));
+
+            let response = BigUint::parse_bytes(proof.response.as_bytes(), 10)
+                .unwrap();
+            let wrong_response = PlaintextProof {
+                response: (response + 1u32).to_string(),
+                ..proof.clone()
+            };
+            assert_eq!(
+                verify(version, &label, &choice, &wrong_response),
+                invalid("proof verification failed")
+            );
+            // a different ciphertext doesn't match the proof
+            let other_alpha = EncryptedChoice {
+                alpha: "2".into(),
+                ..choice.clone()
+            };
+            let mismatch = match version {
+                ProofVersion::ContextBound => "proof verification failed",
+                ProofVersion::Legacy => "challenge mismatch",
+            };
+            assert_eq!(
+                verify(version, &label, &other_alpha, &proof),
+                invalid(mismatch)
+            );
+            let out_of_range = EncryptedChoice {
+                alpha: Group::P2048.parameters().p.to_string(),
+                ..choice.clone()
+            };
+            assert_eq!(
+                verify(version, &label, &out_of_range, &proof),
+                invalid("alpha out of range")
+            );
+            assert_eq!(
+                verify(
+                    version,
+                    &label,
+                    &choice,
+                    &PlaintextProof {
+                        commitment: "1".into(),
+                        ..proof.clone()
+                    }
+                ),
+                invalid("commitment out of range")
+            );
+        }
+
+        // context-bound proofs only verify with the same label
+        let (choice, proof) = encrypt(ProofVersion::ContextBound, &label);
+        for other_label in [
+            proof_label("18", "100", "9f86d0"),
+            proof_label("17", "101", "9f86d0"),
+            proof_label("17", "100", "60303a"),
+        ] {
+            assert_eq!(
+                verify(
+                    ProofVersion::ContextBound,
+                    &other_label,
+                    &choice,
+                    &proof
+                ),
+                invalid("proof verification failed")
+            );
+        }
+        assert_eq!(
+            verify(ProofVersion::Legacy, &label, &choice, &proof),
+            invalid("challenge mismatch")
+        );
+    }
+
+    #[test]
+    fn simulated_legacy_proofs() {
+        // a simulated proof, with the commitment g^response / alpha^challenge
+        // for any challenge, satisfies the verification equation without
+        // knowing the randomness, but not the challenge derivation
+        let parameters = Group::P2048.parameters();
+        let (choice, _) = encrypt(ProofVersion::Legacy, b"");
+        let alpha = BigUint::parse_bytes(choice.alpha.as_bytes(), 10).unwrap();
+        let challenge = BigUint::from(5u32);
+        let response = BigUint::from(7u32);
+        let alpha_inverse =
+            alpha.modpow(&(&parameters.q - &challenge), &parameters.p);
+        let commitment = (parameters.g.modpow(&response, &parameters.p)
+            * alpha_inverse)
+            % &parameters.p;
+        let simulated = PlaintextProof {
+            challenge: challenge.to_string(),
+            commitment: commitment.to_string(),
+            response: response.to_string(),
+        };
+        assert_eq!(
+            verify_proof(
+                ProofVersion::Legacy,
+                b"",
+                Group::P2048,
+                &choice,
+                &simulated,
+                "choices[0]"
+            ),
+            Err(IvrError::InvalidBallot(
+                "choices[0]: challenge mismatch".into()
+            ))
+        );
+    }
+}
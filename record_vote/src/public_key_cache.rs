@@ -0,0 +1,364 @@
+// SPDX-FileCopyrightText: 2022 Eduardo Robles <edu@sequentech.io>
+//
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! In-process cache of the election public keys, shared by the warm
+//! invocations of the lambda. The rest of the election configuration is cached
+//! with them.
+//!
+//! The public key of an election doesn't change once the election starts, so
+//! it's only fetched from the backend for the first vote of each election.
+//! Once a cached key is older than the TTL, it's refreshed by the next vote,
+//! within the deadline of that invocation. The lambda environment is frozen
+//! between invocations, so the refresh is not left running in the background.
+//!
+//! If the backend is unavailable, the expired key is still used until it's
+//! older than the maximum staleness. Any other failed refresh evicts the key,
+//! for example when the election is no longer open.
+//!
+//! A refreshed key with a different fingerprint is never adopted. The
+//! fingerprint of the cached key is kept as a tombstone, and from then on
+//! every vote of the election refetches the key, ignoring the TTL, and fails
+//! with the `invalid-public-key` error unless the backend returns the old key
+//! again. The new key is only used once it's pinned explicitly, which
+//! redeploys the lambda with an empty cache.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use tracing::{event, Level};
+use ivr_common::IvrError;
+
+/// How long the cached keys are used.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CachePolicy {
+    /// Age after which a key is refreshed. Zero disables the cache.
+    pub ttl: Duration,
+    /// Age after which a key is not used anymore, even if it can't be
+    /// refreshed because the backend is unavailable.
+    pub max_staleness: Duration,
+}
+
+/// A cached public key.
+struct Entry<T> {
+    key: T,
+    /// Fingerprint of the key, to detect when it changes.
+    fingerprint: String,
+    fetched_at: Instant,
+    /// Whether the backend returned a key with a different fingerprint. The
+    /// entry is then a tombstone of the trusted fingerprint, and the key isn't
+    /// used until it's fetched again with the same fingerprint.
+    changed: bool,
+}
+
+/// Cache of public keys by election id. Clones share the same entries.
+pub struct PublicKeyCache<T> {
+    entries: Arc<Mutex<HashMap<String, Entry<T>>>>,
+}
+
+impl<T> Clone for PublicKeyCache<T> {
+    fn clone(&self) -> Self {
+        PublicKeyCache { entries: self.entries.clone() }
+    }
+}
+
+impl<T> Default for PublicKeyCache<T> {
+    fn default() -> Self {
+        PublicKeyCache { entries: Arc::new(Mutex::new(HashMap::new())) }
+    }
+}
+
+impl<T: Clone> PublicKeyCache<T> {
+    fn lock(&self)
+        -> Result<MutexGuard<'_, HashMap<String, Entry<T>>>, IvrError>
+    {
+        self.entries
+            .lock()
+            .map_err(|error| IvrError::Internal(error.to_string()))
+    }
+
+    /// Returns the public key of the election at `now`, from the cache or
+    /// awaiting `fetch`, which returns the key and its fingerprint. Keys older
+    /// than the TTL of the `policy` are refreshed with `fetch`, and so are the
+    /// keys whose fingerprint changed, for every call.
+    pub async fn get<F>(
+        &self,
+        election_id: &str,
+        policy: &CachePolicy,
+        now: Instant,
+        fetch: F
+    ) -> Result<T, IvrError>
+    where
+        F: Future<Output = Result<(T, String), IvrError>>
+    {
+        if policy.ttl.is_zero() {
+            return fetch.await.map(|(key, _)| key);
+        }
+        let cached = self.lock()?
+            .get(election_id)
+            .map(|entry| (
+                entry.key.clone(),
+                entry.fingerprint.clone(),
+                now.saturating_duration_since(entry.fetched_at),
+                entry.changed
+            ));
+        let (cached_key, cached_fingerprint, age, changed) = match cached {
+            Some((key, _, age, false)) if age < policy.ttl => {
+                event!(Level::INFO, election_id, "public key cached");
+                return Ok(key);
+            },
+            Some(cached) => cached,
+            None => {
+                event!(Level::INFO, election_id, "public key not cached");
+                let (key, fingerprint) = fetch.await?;
+                self.insert(election_id, &key, fingerprint, now, false)?;
+                return Ok(key);
+            },
+        };
+        if changed {
+            event!(Level::INFO, election_id, "refetching changed public key");
+        } else {
+            event!(Level::INFO, election_id, "refreshing expired public key");
+        }
+        match fetch.await {
+            Ok((_, fingerprint)) if fingerprint != cached_fingerprint => {
+                event!(
+                    Level::ERROR,
+                    election_id,
+                    old_fingerprint = cached_fingerprint,
+                    new_fingerprint = fingerprint,
+                    "public key changed"
+                );
+                self.insert(
+                    election_id,
+                    &cached_key,
+                    cached_fingerprint,
+                    now,
+                    true
+                )?;
+                Err(IvrError::InvalidPublicKey(
+                    "payload.pks: changed since it was cached".into()
+                ))
+            },
+            Ok((key, fingerprint)) => {
+                if changed {
+                    event!(
+                        Level::WARN,
+                        election_id,
+                        "public key changed back to the cached one"
+                    );
+                }
+                self.insert(election_id, &key, fingerprint, now, false)?;
+                Ok(key)
+            },
+            // the tombstone is kept whatever the error, so that the changed
+            // key can't be adopted after an eviction
+            Err(error) if changed => Err(error),
+            Err(error)
+                if error.is_retryable() && age < policy.max_staleness =>
+            {
+                event!(
+                    Level::WARN,
+                    election_id,
+                    "public key refresh failed, using the expired one: {}",
+                    error
+                );
+                Ok(cached_key)
+            },
+            Err(error) => {
+                event!(Level::WARN, election_id, "evicting public key");
+                self.lock()?.remove(election_id);
+                Err(error)
+            },
+        }
+    }
+
+    fn insert(
+        &self,
+        election_id: &str,
+        key: &T,
+        fingerprint: String,
+        now: Instant,
+        changed: bool
+    ) -> Result<(), IvrError>
+    {
+        self.lock()?.insert(election_id.into(), Entry {
+            key: key.clone(),
+            fingerprint,
+            fetched_at: now,
+            changed,
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::time::{Duration, Instant};
+    use ivr_common::IvrError;
+    use crate::public_key_cache::{CachePolicy, PublicKeyCache};
+
+    const POLICY: CachePolicy = CachePolicy {
+        ttl: Duration::from_secs(300),
+        max_staleness: Duration::from_secs(900),
+    };
+
+    /// Cache of string keys, counting the fetches from the backend.
+    #[derive(Default)]
+    struct TestCache {
+        cache: PublicKeyCache<String>,
+        fetches: AtomicU32,
+    }
+
+    impl TestCache {
+        /// Gets the key of the election `seconds` after `start`, with a fetch
+        /// returning `fetched`. The fingerprint of a key is the key itself.
+        async fn get(
+            &self,
+            election_id: &str,
+            start: Instant,
+            seconds: u64,
+            fetched: Result<&str, IvrError>
+        ) -> Result<String, IvrError>
+        {
+            let now = start + Duration::from_secs(seconds);
+            let fetch = async {
+                self.fetches.fetch_add(1, Ordering::SeqCst);
+                fetched.map(|key| (key.to_string(), key.to_string()))
+            };
+            self.cache.get(election_id, &POLICY, now, fetch).await
+        }
+
+        fn fetches(&self) -> u32 {
+            self.fetches.load(Ordering::SeqCst)
+        }
+    }
+
+    #[tokio::test]
+    async fn cached_keys() {
+        let cache = TestCache::default();
+        let start = Instant::now();
+        for _ in 0..3 {
+            assert_eq!(
+                cache.get("17", start, 0, Ok("y1")).await,
+                Ok("y1".into())
+            );
+        }
+        assert_eq!(cache.fetches(), 1);
+
+        // keys are cached per election
+        assert_eq!(cache.get("18", start, 0, Ok("y2")).await, Ok("y2".into()));
+        assert_eq!(cache.fetches(), 2);
+
+        // failed fetches are not cached
+        assert_eq!(
+            cache.get("19", start, 0, Err(IvrError::Timeout)).await,
+            Err(IvrError::Timeout)
+        );
+        assert_eq!(cache.get("19", start, 0, Ok("y3")).await, Ok("y3".into()));
+        assert_eq!(cache.fetches(), 4);
+
+        // a zero ttl disables the cache
+        let no_cache = CachePolicy { ttl: Duration::ZERO, ..POLICY };
+        let fetch = async { Ok(("y4".to_string(), "y4".to_string())) };
+        assert_eq!(
+            cache.cache.get("17", &no_cache, start, fetch).await,
+            Ok("y4".into())
+        );
+        assert_eq!(cache.get("17", start, 0, Ok("y1")).await, Ok("y1".into()));
+    }
+
+    #[tokio::test]
+    async fn expired_keys() {
+        let cache = TestCache::default();
+        let start = Instant::now();
+        assert_eq!(cache.get("17", start, 0, Ok("y1")).await, Ok("y1".into()));
+
+        // the expired key is refreshed by the next get
+        assert_eq!(
+            cache.get("17", start, 300, Ok("y1")).await,
+            Ok("y1".into())
+        );
+        assert_eq!(cache.fetches(), 2);
+        assert_eq!(
+            cache.get("17", start, 599, Ok("y1")).await,
+            Ok("y1".into())
+        );
+        assert_eq!(cache.fetches(), 2);
+
+        // while the backend is unavailable, the expired key is used until the
+        // maximum staleness
+        let unavailable = || IvrError::BackendUnavailable("".into());
+        assert_eq!(
+            cache.get("17", start, 1000, Err(unavailable())).await,
+            Ok("y1".into())
+        );
+        assert_eq!(
+            cache.get("17", start, 1200, Err(unavailable())).await,
+            Err(unavailable())
+        );
+        assert_eq!(
+            cache.get("17", start, 1200, Ok("y1")).await,
+            Ok("y1".into())
+        );
+
+        // any other failure evicts the key
+        assert_eq!(
+            cache.get("17", start, 1500, Err(IvrError::ElectionNotOpen)).await,
+            Err(IvrError::ElectionNotOpen)
+        );
+        assert_eq!(
+            cache.get("17", start, 1500, Err(unavailable())).await,
+            Err(unavailable())
+        );
+    }
+
+    #[tokio::test]
+    async fn changed_keys() {
+        let cache = TestCache::default();
+        let start = Instant::now();
+        assert_eq!(cache.get("17", start, 0, Ok("y1")).await, Ok("y1".into()));
+
+        // a changed key is not adopted
+        let changed = || Err(IvrError::InvalidPublicKey(
+            "payload.pks: changed since it was cached".into()
+        ));
+        assert_eq!(cache.get("17", start, 300, Ok("y2")).await, changed());
+        assert_eq!(cache.fetches(), 2);
+
+        // nor used later: the next calls refetch it, even before the TTL
+        assert_eq!(cache.get("17", start, 301, Ok("y2")).await, changed());
+        assert_eq!(cache.get("17", start, 2000, Ok("y2")).await, changed());
+        assert_eq!(cache.fetches(), 4);
+
+        // failed refetches keep the tombstone and don't use the old key
+        let unavailable = || IvrError::BackendUnavailable("".into());
+        assert_eq!(
+            cache.get("17", start, 2001, Err(unavailable())).await,
+            Err(unavailable())
+        );
+        assert_eq!(
+            cache.get("17", start, 2002, Err(IvrError::ElectionNotOpen)).await,
+            Err(IvrError::ElectionNotOpen)
+        );
+        assert_eq!(cache.get("17", start, 2003, Ok("y2")).await, changed());
+        assert_eq!(cache.fetches(), 7);
+
+        // the old key is used again once the backend returns it
+        assert_eq!(
+            cache.get("17", start, 2004, Ok("y1")).await,
+            Ok("y1".into())
+        );
+        assert_eq!(
+            cache.get("17", start, 2005, Ok("y2")).await,
+            Ok("y1".into())
+        );
+        assert_eq!(cache.fetches(), 8);
+
+        // other elections are not affected
+        assert_eq!(cache.get("18", start, 0, Ok("y2")).await, Ok("y2".into()));
+        assert_eq!(cache.fetches(), 9);
+    }
+}
@@ -0,0 +1,155 @@
+// SPDX-FileCopyrightText: 2022 Eduardo Robles <edu@sequentech.io>
+//
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! SSML readback of the answers chosen by the voter, so that the contact flow
+//! can ask for confirmation before casting the vote.
+
+use ivr_common::ssml::escape;
+
+use crate::election::Question;
+use crate::encoding::Selection;
+
+/// Texts read back for blank and null votes, which don't have answers.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReadbackTexts {
+    pub blank: String,
+    pub null: String,
+}
+
+impl Default for ReadbackTexts {
+    fn default() -> Self {
+        ReadbackTexts {
+            blank: "Blank vote".into(),
+            null: "Null vote".into(),
+        }
+    }
+}
+
+/// Returns the SSML to read a code, such as the start of the ballot hash,
+/// character by character.
+pub fn verbatim_ssml(code: &str) -> String {
+    code.chars()
+        .map(|character| format!(
+            "<s><say-as interpret-as=\"verbatim\">{}</say-as></s>",
+            escape(&character.to_string())
+        ))
+        .collect()
+}
+
+/// Returns the SSML readback of the answers chosen in every question, with
+/// the title of the question followed by the text of the answers. The answers
+/// of preferential questions are preceded by their position in the ranking.
+pub fn vote_readback_ssml(
+    questions: &[Question],
+    selections: &[Selection],
+    texts: &ReadbackTexts
+) -> String
+{
+    let mut ssml = String::from("<speak>");
+    for (question, selection) in questions.iter().zip(selections) {
+        ssml.push_str(&format!("<p><s>{}</s>", escape(&question.title)));
+        let answer_ids = match selection {
+            Selection::Answers(answer_ids) => answer_ids,
+            Selection::Blank => {
+                ssml.push_str(&format!("<s>{}</s></p>", escape(&texts.blank)));
+                continue;
+            },
+            Selection::Null => {
+                ssml.push_str(&format!("<s>{}</s></p>", escape(&texts.null)));
+                continue;
+            },
+        };
+        for (position, answer_id) in answer_ids.iter().enumerate() {
+            let Some(answer) = question
+                .answers
+                .iter()
+                .find(|answer| answer.id == *answer_id)
+            else {
+                continue;
+            };
+            if question.is_preferential() {
+                ssml.push_str(&format!(
+                    "<s><say-as interpret-as=\"ordinal\">{}</say-as>: {}</s>",
+                    position + 1,
+                    escape(&answer.text)
+                ));
+            } else {
+                ssml.push_str(&format!("<s>{}</s>", escape(&answer.text)));
+            }
+        }
+        ssml.push_str("</p>");
+    }
+    ssml.push_str("</speak>");
+    ssml
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::election::{Answer, Question};
+    use crate::encoding::Selection;
+    use crate::readback::{verbatim_ssml, vote_readback_ssml, ReadbackTexts};
+
+    fn question(title: &str, tally_type: &str, answers: &[&str]) -> Question {
+        Question {
+            title: title.into(),
+            description: "".into(),
+            layout: "simple".into(),
+            min: 0,
+            max: answers.len() as u32,
+            num_winners: 1,
+            tally_type: tally_type.into(),
+            answers: answers
+                .iter()
+                .enumerate()
+                .map(|(id, text)| Answer {
+                    id: id as u32,
+                    text: text.to_string(),
+                    category: "".into(),
+                    details: "".into(),
+                    sort_order: id as i64,
+                    urls: vec![],
+                })
+                .collect(),
+            extra_options: None,
+        }
+    }
+
+    #[test]
+    fn verbatim() {
+        assert_eq!(
+            verbatim_ssml("a1"),
+            "<s><say-as interpret-as=\"verbatim\">a</say-as></s>\
+            <s><say-as interpret-as=\"verbatim\">1</say-as></s>"
+        );
+    }
+
+    #[test]
+    fn readback() {
+        let questions = vec![
+            question("Bylaws", "plurality-at-large", &["Yes", "No"]),
+            question("Board", "borda", &["Alice", "Bob & Carol", "Dave"]),
+            question("Budget", "plurality-at-large", &["Yes", "No"]),
+        ];
+        let selections = [
+            Selection::Answers(vec![0]),
+            Selection::Answers(vec![2, 1]),
+            Selection::Blank,
+        ];
+        assert_eq!(
+            vote_readback_ssml(
+                &questions,
+                &selections,
+                &ReadbackTexts::default()
+            ),
+            "<speak>\
+            <p><s>Bylaws</s><s>Yes</s></p>\
+            <p><s>Board</s>\
+            <s><say-as interpret-as=\"ordinal\">1</say-as>: Dave</s>\
+            <s><say-as interpret-as=\"ordinal\">2</say-as>: Bob &amp; Carol</s>\
+            </p>\
+            <p><s>Budget</s><s>Blank vote</s></p>\
+            </speak>"
+        );
+    }
+}